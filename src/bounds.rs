@@ -0,0 +1,454 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix, Matrix4, MetricSpace, Point3, Vector3, Vector4};
+
+/// An axis-aligned bounding box.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+  pub min: Point3<f32>,
+  pub max: Point3<f32>,
+}
+
+impl Aabb {
+  pub fn new(min: impl Into<Point3<f32>>, max: impl Into<Point3<f32>>) -> Self {
+    Self {
+      min: min.into(),
+      max: max.into(),
+    }
+  }
+
+  /// A box containing nothing, which any point grows to fit.
+  pub fn empty() -> Self {
+    Self::new([f32::MAX; 3], [f32::MIN; 3])
+  }
+
+  pub fn from_points(points: impl IntoIterator<Item = Point3<f32>>) -> Self {
+    points.into_iter().fold(Self::empty(), |aabb, point| {
+      Self::new(
+        [
+          aabb.min.x.min(point.x),
+          aabb.min.y.min(point.y),
+          aabb.min.z.min(point.z),
+        ],
+        [
+          aabb.max.x.max(point.x),
+          aabb.max.y.max(point.y),
+          aabb.max.z.max(point.z),
+        ],
+      )
+    })
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+  }
+
+  /// The smallest box containing both boxes.
+  pub fn union(&self, other: &Aabb) -> Self {
+    Self::from_points([self.min, self.max, other.min, other.max])
+  }
+
+  pub fn center(&self) -> Point3<f32> {
+    self.min.midpoint(self.max)
+  }
+
+  pub fn extent(&self) -> Vector3<f32> {
+    self.max - self.min
+  }
+
+  /// The smallest box containing this box after an affine transform.
+  pub fn transform(&self, matrix: &Matrix4<f32>) -> Self {
+    if self.is_empty() {
+      return *self;
+    }
+    // Each axis of the box adds its smaller and larger end to the new box separately
+    let translation = Point3::from_vec(matrix.w.truncate());
+    let (mut min, mut max) = (translation, translation);
+    for axis in 0..3 {
+      let column = matrix[axis].truncate();
+      let (a, b) = (column * self.min[axis], column * self.max[axis]);
+      for i in 0..3 {
+        min[i] += a[i].min(b[i]);
+        max[i] += a[i].max(b[i]);
+      }
+    }
+    Self::new(min, max)
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingSphere {
+  pub center: Point3<f32>,
+  pub radius: f32,
+}
+
+impl BoundingSphere {
+  pub fn new(center: impl Into<Point3<f32>>, radius: f32) -> Self {
+    Self {
+      center: center.into(),
+      radius,
+    }
+  }
+
+  /// A sphere around the center of the points' bounding box, which is close to the smallest
+  /// enclosing sphere for most meshes and much cheaper to find.
+  pub fn from_points(points: &[Point3<f32>]) -> Self {
+    let aabb = Aabb::from_points(points.iter().copied());
+    if aabb.is_empty() {
+      return Self::new([0.0; 3], 0.0);
+    }
+    let center = aabb.center();
+    let radius = points
+      .iter()
+      .map(|point| point.distance2(center))
+      .fold(0.0, f32::max)
+      .sqrt();
+    Self::new(center, radius)
+  }
+
+  /// The sphere after a transform made of rotations, translations and uniform scales.
+  pub fn transform(&self, matrix: &Matrix4<f32>) -> Self {
+    let scale = matrix.x.truncate().magnitude();
+    Self::new(
+      Point3::from_homogeneous(matrix * self.center.to_homogeneous()),
+      self.radius * scale,
+    )
+  }
+
+  /// The smallest sphere containing both spheres.
+  pub fn union(&self, other: &BoundingSphere) -> Self {
+    let offset = other.center - self.center;
+    let distance = offset.magnitude();
+    if distance + other.radius <= self.radius {
+      return *self;
+    }
+    if distance + self.radius <= other.radius {
+      return *other;
+    }
+    let radius = (distance + self.radius + other.radius) / 2.0;
+    let center = self.center + offset * ((radius - self.radius) / distance);
+    Self::new(center, radius)
+  }
+}
+
+/// A plane of the points where `normal.dot(point) + distance` is zero.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Plane {
+  pub normal: Vector3<f32>,
+  pub distance: f32,
+}
+
+impl Plane {
+  pub fn new(normal: impl Into<Vector3<f32>>, distance: f32) -> Self {
+    Self {
+      normal: normal.into(),
+      distance,
+    }
+  }
+
+  /// The plane of `(a, b, c, d)` with a normal of unit length. Planes at infinity have no normal
+  /// and keep everything in front of them.
+  fn from_coefficients(coefficients: Vector4<f32>) -> Self {
+    let normal = coefficients.truncate();
+    let length = normal.magnitude();
+    if length <= f32::EPSILON {
+      return Self::new([0.0; 3], f32::INFINITY);
+    }
+    Self::new(normal / length, coefficients.w / length)
+  }
+
+  /// Positive in front of the plane, on the side its normal points to.
+  pub fn signed_distance(&self, point: Point3<f32>) -> f32 {
+    self.normal.dot(point.to_vec()) + self.distance
+  }
+}
+
+/// The space a camera sees, bounded by planes facing inwards.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frustum {
+  /// Left, right, bottom, top, and the planes at depth 0 and 1.
+  pub planes: [Plane; 6],
+}
+
+impl Frustum {
+  /// Extracts the planes of a view-projection matrix mapping to wgpu's clip space, where depth
+  /// goes from 0 to 1. Works the same for reverse-Z and infinite projections.
+  pub fn from_matrix(view_projection: &Matrix4<f32>) -> Self {
+    let row = |i: usize| view_projection.row(i);
+    let planes = [
+      row(3) + row(0),
+      row(3) - row(0),
+      row(3) + row(1),
+      row(3) - row(1),
+      row(2),
+      row(3) - row(2),
+    ];
+    Self {
+      planes: planes.map(Plane::from_coefficients),
+    }
+  }
+
+  /// Whether any part of the sphere could be visible.
+  pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+    self
+      .planes
+      .iter()
+      .all(|plane| plane.signed_distance(sphere.center) >= -sphere.radius)
+  }
+
+  /// Whether any part of the box could be visible. Boxes outside near a corner of the frustum
+  /// may still pass, which only costs drawing them.
+  pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+    if aabb.is_empty() {
+      return false;
+    }
+    self.planes.iter().all(|plane| {
+      // The corner furthest along the normal is in front if any of the box is
+      let corner = Point3::new(
+        if plane.normal.x >= 0.0 {
+          aabb.max.x
+        } else {
+          aabb.min.x
+        },
+        if plane.normal.y >= 0.0 {
+          aabb.max.y
+        } else {
+          aabb.min.y
+        },
+        if plane.normal.z >= 0.0 {
+          aabb.max.z
+        } else {
+          aabb.min.z
+        },
+      );
+      plane.signed_distance(corner) >= 0.0
+    })
+  }
+}
+
+/// A half-line from `origin` along `direction`, which has unit length.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ray {
+  pub origin: Point3<f32>,
+  pub direction: Vector3<f32>,
+}
+
+impl Ray {
+  pub fn new(origin: impl Into<Point3<f32>>, direction: impl Into<Vector3<f32>>) -> Self {
+    Self {
+      origin: origin.into(),
+      direction: direction.into().normalize(),
+    }
+  }
+
+  pub fn at(&self, distance: f32) -> Point3<f32> {
+    self.origin + self.direction * distance
+  }
+
+  /// The ray after an affine transform. Distances along it change with any scaling.
+  pub fn transform(&self, matrix: &Matrix4<f32>) -> Self {
+    Self::new(
+      Point3::from_homogeneous(matrix * self.origin.to_homogeneous()),
+      (matrix * self.direction.extend(0.0)).truncate(),
+    )
+  }
+
+  /// The distance to where the ray enters the box, or 0 if it starts inside.
+  pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+    if aabb.is_empty() {
+      return None;
+    }
+    // Narrows down the distances the ray is between each pair of parallel faces
+    let (mut near, mut far) = (0.0f32, f32::INFINITY);
+    for axis in 0..3 {
+      if self.direction[axis] == 0.0 {
+        // Parallel to this pair of faces, so the ray is between them either always or never.
+        // Dividing by 0 would give NaN for rays starting on a face.
+        if self.origin[axis] < aabb.min[axis] || self.origin[axis] > aabb.max[axis] {
+          return None;
+        }
+        continue;
+      }
+      let inverse = 1.0 / self.direction[axis];
+      let a = (aabb.min[axis] - self.origin[axis]) * inverse;
+      let b = (aabb.max[axis] - self.origin[axis]) * inverse;
+      near = near.max(a.min(b));
+      far = far.min(a.max(b));
+    }
+    if near <= far {
+      Some(near)
+    } else {
+      None
+    }
+  }
+
+  /// The distance to where the ray enters the sphere, or 0 if it starts inside.
+  pub fn intersect_sphere(&self, sphere: &BoundingSphere) -> Option<f32> {
+    let offset = sphere.center - self.origin;
+    let along = offset.dot(self.direction);
+    let discriminant = along * along - offset.magnitude2() + sphere.radius * sphere.radius;
+    if discriminant < 0.0 {
+      return None;
+    }
+    let root = discriminant.sqrt();
+    if along + root < 0.0 {
+      return None;
+    }
+    Some((along - root).max(0.0))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    camera::{Camera, Projection},
+    texture::DepthMode,
+  };
+  use cgmath::Deg;
+
+  const PERSPECTIVE: Projection = Projection::Perspective {
+    fovy: cgmath::Rad(std::f32::consts::FRAC_PI_2),
+  };
+  const ORTHOGRAPHIC: Projection = Projection::Orthographic { height: 20.0 };
+  const DEPTH_MODES: [DepthMode; 2] = [DepthMode::Standard, DepthMode::ReverseZ];
+
+  /// The frustum of a camera at the origin looking down -Z, from 0.1 to 100 units away. It's
+  /// square, and 20 units wide 10 units away for both projections.
+  fn frustum(projection: Projection, depth_mode: DepthMode) -> Frustum {
+    let mut camera = Camera::new(
+      (0.0, 0.0, 0.0),
+      Deg(-90.0),
+      Deg(0.0),
+      100,
+      100,
+      Deg(90.0),
+      0.1,
+      100.0,
+    );
+    camera.projection = projection;
+    camera.depth_mode = depth_mode;
+    camera.frustum()
+  }
+
+  fn cube(center: [f32; 3], half_size: f32) -> Aabb {
+    let center = Point3::from(center);
+    Aabb::new(
+      center - Vector3::new(half_size, half_size, half_size),
+      center + Vector3::new(half_size, half_size, half_size),
+    )
+  }
+
+  #[test]
+  fn frustum_keeps_boxes_inside_or_straddling_it() {
+    for projection in [PERSPECTIVE, ORTHOGRAPHIC] {
+      for depth_mode in DEPTH_MODES {
+        let frustum = frustum(projection, depth_mode);
+        for (name, aabb) in [
+          ("inside", cube([0.0, 0.0, -10.0], 1.0)),
+          ("across the left plane", cube([-10.0, 0.0, -10.0], 2.0)),
+          ("across the top plane", cube([0.0, 10.0, -10.0], 2.0)),
+          ("across the near plane", cube([0.0, 0.0, -0.1], 0.5)),
+          ("across the far plane", cube([0.0, 0.0, -100.0], 5.0)),
+          ("around the frustum", cube([0.0, 0.0, 0.0], 1000.0)),
+        ] {
+          assert!(
+            frustum.intersects_aabb(&aabb),
+            "{} with {:?} and {:?}",
+            name,
+            projection,
+            depth_mode
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn frustum_culls_boxes_outside_of_it() {
+    for projection in [PERSPECTIVE, ORTHOGRAPHIC] {
+      for depth_mode in DEPTH_MODES {
+        let frustum = frustum(projection, depth_mode);
+        for (name, aabb) in [
+          ("right", cube([30.0, 0.0, -10.0], 2.0)),
+          ("below", cube([0.0, -30.0, -10.0], 2.0)),
+          ("behind", cube([0.0, 0.0, 10.0], 1.0)),
+          ("empty", Aabb::empty()),
+        ] {
+          assert!(
+            !frustum.intersects_aabb(&aabb),
+            "{} with {:?} and {:?}",
+            name,
+            projection,
+            depth_mode
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn only_reverse_z_perspective_has_an_infinite_far_plane() {
+    let far_away = cube([0.0, 0.0, -1000.0], 1.0);
+    assert!(frustum(PERSPECTIVE, DepthMode::ReverseZ).intersects_aabb(&far_away));
+    assert!(!frustum(PERSPECTIVE, DepthMode::Standard).intersects_aabb(&far_away));
+    for depth_mode in DEPTH_MODES {
+      assert!(!frustum(ORTHOGRAPHIC, depth_mode).intersects_aabb(&far_away));
+    }
+
+    // The plane at infinity keeps everything, but the near plane still culls
+    let reverse_z = frustum(PERSPECTIVE, DepthMode::ReverseZ);
+    assert_eq!(reverse_z.planes[4].distance, f32::INFINITY);
+    assert!(!reverse_z.intersects_aabb(&cube([0.0, 0.0, 0.5], 0.1)));
+  }
+
+  #[test]
+  fn frustum_and_sphere_tests_agree() {
+    let frustum = frustum(PERSPECTIVE, DepthMode::Standard);
+    assert!(frustum.intersects_sphere(&BoundingSphere::new([0.0, 0.0, -10.0], 1.0)));
+    assert!(frustum.intersects_sphere(&BoundingSphere::new([-10.5, 0.0, -10.0], 1.0)));
+    assert!(!frustum.intersects_sphere(&BoundingSphere::new([30.0, 0.0, -10.0], 1.0)));
+  }
+
+  #[test]
+  fn axis_aligned_rays_hit_and_miss() {
+    let unit = Aabb::new([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]);
+    let along_x = |y: f32, z: f32| Ray::new([-5.0, y, z], [1.0, 0.0, 0.0]);
+    assert_eq!(along_x(0.5, 0.5).intersect_aabb(&unit), Some(5.0));
+    assert_eq!(along_x(2.0, 0.5).intersect_aabb(&unit), None);
+    assert_eq!(along_x(0.5, -0.1).intersect_aabb(&unit), None);
+    // Rays grazing a face parallel to them still hit
+    assert_eq!(along_x(1.0, 0.5).intersect_aabb(&unit), Some(5.0));
+    assert_eq!(along_x(0.0, 0.0).intersect_aabb(&unit), Some(5.0));
+
+    let backwards = Ray::new([-5.0, 0.5, 0.5], [-1.0, 0.0, 0.0]);
+    assert_eq!(backwards.intersect_aabb(&unit), None);
+    let down = Ray::new([0.5, 3.0, 0.5], [0.0, -1.0, 0.0]);
+    assert_eq!(down.intersect_aabb(&unit), Some(2.0));
+  }
+
+  #[test]
+  fn rays_starting_inside_hit_at_zero() {
+    let unit = Aabb::new([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]);
+    let ray = Ray::new([0.5, 0.5, 0.5], [0.0, 0.0, 1.0]);
+    assert_eq!(ray.intersect_aabb(&unit), Some(0.0));
+    assert_eq!(ray.intersect_aabb(&Aabb::empty()), None);
+    let sphere = BoundingSphere::new([0.5, 0.5, 0.5], 1.0);
+    assert_eq!(ray.intersect_sphere(&sphere), Some(0.0));
+  }
+
+  #[test]
+  fn diagonal_rays_hit_boxes_and_spheres() {
+    let ray = Ray::new([-1.0, -1.0, -1.0], [1.0, 1.0, 1.0]);
+    let distance = ray
+      .intersect_aabb(&Aabb::new([0.0, 0.0, 0.0], [1.0, 1.0, 1.0]))
+      .unwrap();
+    assert!((distance - 3f32.sqrt()).abs() < 1e-5);
+    let distance = ray
+      .intersect_sphere(&BoundingSphere::new([2.0, 2.0, 2.0], 1.0))
+      .unwrap();
+    assert!((distance - (27f32.sqrt() - 1.0)).abs() < 1e-5);
+    assert_eq!(
+      ray.intersect_sphere(&BoundingSphere::new([2.0, -2.0, 2.0], 1.0)),
+      None
+    );
+  }
+}
@@ -1,7 +1,27 @@
+use crate::{
+  bounds::{Frustum, Ray},
+  texture::DepthMode,
+};
 use bytemuck::{Pod, Zeroable};
-use cgmath::{InnerSpace, Matrix4, Point3, Rad, Vector3};
-use std::{f32::consts::FRAC_PI_2, time::Duration};
-use winit::event::{ElementState, VirtualKeyCode};
+use cgmath::{InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Vector3, Vector4};
+use std::{
+  f32::consts::{FRAC_PI_2, PI},
+  time::Duration,
+};
+use winit::{
+  dpi::{PhysicalPosition, PhysicalSize},
+  event::{ElementState, MouseScrollDelta, VirtualKeyCode},
+};
+
+/// How the camera maps what it sees to the screen.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+  /// Things further away look smaller, with `fovy` the vertical field of view.
+  Perspective { fovy: Rad<f32> },
+  /// Parallel projection showing `height` world units from the bottom of the screen to the top,
+  /// whatever the distance, for technical top and side views.
+  Orthographic { height: f32 },
+}
 
 pub struct Camera {
   pub position: Point3<f32>,
@@ -9,7 +29,10 @@ pub struct Camera {
   pitch: Rad<f32>,
 
   aspect: f32,
-  fovy: Rad<f32>,
+  pub projection: Projection,
+  /// Reverse-Z puts the far plane of perspective projections at infinity, ignoring `far`. The
+  /// render pipelines and depth buffer have to use the same mode.
+  pub depth_mode: DepthMode,
   near: f32,
   far: f32,
 }
@@ -31,26 +54,97 @@ impl Camera {
       yaw: yaw.into(),
       pitch: pitch.into(),
       aspect: width as f32 / height as f32,
-      fovy: fovy.into(),
+      projection: Projection::Perspective { fovy: fovy.into() },
+      depth_mode: DepthMode::default(),
       near,
       far,
     }
   }
 
   pub fn view(&self) -> Matrix4<f32> {
-    Matrix4::look_to_rh(
-      self.position,
-      Vector3::new(self.yaw.0.cos(), self.pitch.0.sin(), self.yaw.0.sin()).normalize(),
-      Vector3::unit_y(),
-    )
+    Matrix4::look_to_rh(self.position, self.forward(), Vector3::unit_y())
+  }
+
+  /// The unit vector the camera looks along.
+  pub fn forward(&self) -> Vector3<f32> {
+    let (yaw_sin, yaw_cos) = self.yaw.0.sin_cos();
+    let (pitch_sin, pitch_cos) = self.pitch.0.sin_cos();
+    Vector3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin)
+  }
+
+  /// Turns the camera towards `target`, without rolling it.
+  pub fn look_at(&mut self, target: Point3<f32>) {
+    let direction = target - self.position;
+    if direction.magnitude2() == 0.0 {
+      return;
+    }
+    let direction = direction.normalize();
+    self.yaw = Rad(direction.z.atan2(direction.x));
+    self.pitch = Rad(direction.y.clamp(-1.0, 1.0).asin());
   }
 
   pub fn resize(&mut self, width: u32, height: u32) {
     self.aspect = width as f32 / height as f32;
   }
 
-  pub fn projection(&self) -> Matrix4<f32> {
-    OPENGL_TO_WGPU_MATRIX * cgmath::perspective(self.fovy, self.aspect, self.near, self.far)
+  /// The ray from the near plane through `position` in a window of `size`, for finding what's
+  /// under the cursor.
+  pub fn screen_ray(&self, position: PhysicalPosition<f64>, size: PhysicalSize<u32>) -> Ray {
+    let inverse = match (self.projection_matrix() * self.view()).invert() {
+      Some(inverse) => inverse,
+      None => return Ray::new(self.position, self.forward()),
+    };
+    let x = 2.0 * position.x as f32 / size.width as f32 - 1.0;
+    let y = 1.0 - 2.0 * position.y as f32 / size.height as f32;
+    let unproject = |depth| Point3::from_homogeneous(inverse * Vector4::new(x, y, depth, 1.0));
+    // Halfway through the depth range is at a finite distance even with an infinite far plane
+    let near = unproject(match self.depth_mode {
+      DepthMode::Standard => 0.0,
+      DepthMode::ReverseZ => 1.0,
+    });
+    Ray::new(near, unproject(0.5) - near)
+  }
+
+  /// The space the camera sees, for culling what's outside of it.
+  pub fn frustum(&self) -> Frustum {
+    Frustum::from_matrix(&(self.projection_matrix() * self.view()))
+  }
+
+  pub fn projection_matrix(&self) -> Matrix4<f32> {
+    match (self.projection, self.depth_mode) {
+      (Projection::Perspective { fovy }, DepthMode::Standard) => {
+        OPENGL_TO_WGPU_MATRIX * cgmath::perspective(fovy, self.aspect, self.near, self.far)
+      }
+      (Projection::Perspective { fovy }, DepthMode::ReverseZ) => {
+        // Maps the near plane to 1 and infinity to 0, already in wgpu's depth range
+        let f = 1.0 / (fovy.0 / 2.0).tan();
+        #[rustfmt::skip]
+        let projection = Matrix4::new(
+          f / self.aspect, 0.0, 0.0, 0.0,
+          0.0, f, 0.0, 0.0,
+          0.0, 0.0, 0.0, -1.0,
+          0.0, 0.0, self.near, 0.0,
+        );
+        projection
+      }
+      (Projection::Orthographic { height }, depth_mode) => {
+        let (half_width, half_height) = (height * self.aspect / 2.0, height / 2.0);
+        let projection = OPENGL_TO_WGPU_MATRIX
+          * cgmath::ortho(
+            -half_width,
+            half_width,
+            -half_height,
+            half_height,
+            self.near,
+            self.far,
+          );
+        // Orthographic projections keep their far plane, and just have their depth flipped
+        match depth_mode {
+          DepthMode::Standard => projection,
+          DepthMode::ReverseZ => REVERSE_Z_MATRIX * projection,
+        }
+      }
+    }
   }
 }
 
@@ -62,7 +156,26 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
   0.0, 0.0, 0.5, 1.0,
 );
 
+/// Maps depth `z` to `1 - z`, for projections with `w = 1`.
+#[rustfmt::skip]
+const REVERSE_Z_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
+  1.0, 0.0, 0.0, 0.0,
+  0.0, 1.0, 0.0, 0.0,
+  0.0, 0.0, -1.0, 0.0,
+  0.0, 0.0, 1.0, 1.0,
+);
+
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
+/// How much each line scrolled zooms in.
+const ZOOM_PER_LINE: f32 = 0.9;
+
+fn scroll_lines(delta: &MouseScrollDelta) -> f32 {
+  match delta {
+    MouseScrollDelta::LineDelta(_, lines) => *lines,
+    // Touchpads report pixels, roughly 20 to a line
+    MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => *y as f32 / 20.0,
+  }
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
@@ -81,12 +194,30 @@ impl CameraUniform {
   }
 
   pub fn update_view_proj(&mut self, camera: &Camera) {
-    self.view_pos = camera.position.to_homogeneous().into();
-    self.view_proj = (camera.projection() * camera.view()).into();
+    // Every pixel of an orthographic view is seen from the same direction
+    self.view_pos = match camera.projection {
+      Projection::Perspective { .. } => camera.position.to_homogeneous().into(),
+      Projection::Orthographic { .. } => (-camera.forward()).extend(0.0).into(),
+    };
+    self.view_proj = (camera.projection_matrix() * camera.view()).into();
   }
 }
 
-pub struct Controller {
+/// Turns input events into camera movement.
+pub trait CameraController {
+  /// Returns whether the key was used.
+  fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool;
+
+  /// Mouse movement while the left button is held.
+  fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64);
+
+  fn process_scroll(&mut self, _delta: &MouseScrollDelta) {}
+
+  fn update_camera(&mut self, camera: &mut Camera, dt: Duration);
+}
+
+/// Free flight, moving with WASD, Space and Left Control and looking around with the mouse.
+pub struct FlyController {
   amount_left: f32,
   amount_right: f32,
   amount_forward: f32,
@@ -95,12 +226,13 @@ pub struct Controller {
   amount_down: f32,
   rotate_horizontal: f32,
   rotate_vertical: f32,
+  scroll: f32,
   speed: f32,
   fast: bool,
   sensitivity: f32,
 }
 
-impl Controller {
+impl FlyController {
   pub fn new(speed: f32, sensitivity: f32) -> Self {
     Self {
       amount_left: 0.0,
@@ -111,13 +243,16 @@ impl Controller {
       amount_down: 0.0,
       rotate_horizontal: 0.0,
       rotate_vertical: 0.0,
+      scroll: 0.0,
       speed,
       fast: false,
       sensitivity,
     }
   }
+}
 
-  pub fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
+impl CameraController for FlyController {
+  fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
     let amount = if state == ElementState::Pressed {
       1.0
     } else {
@@ -156,12 +291,16 @@ impl Controller {
     }
   }
 
-  pub fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
+  fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
     self.rotate_horizontal = mouse_dx as f32;
     self.rotate_vertical = mouse_dy as f32;
   }
 
-  pub fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+  fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+    self.scroll += scroll_lines(delta);
+  }
+
+  fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
     let dt = dt.as_secs_f32();
 
     let speed = if self.fast {
@@ -191,6 +330,12 @@ impl Controller {
     self.rotate_horizontal = 0.0;
     self.rotate_vertical = 0.0;
 
+    // Flying closer doesn't make anything bigger in an orthographic view, so scrolling zooms
+    if let Projection::Orthographic { height } = &mut camera.projection {
+      *height *= ZOOM_PER_LINE.powf(self.scroll);
+    }
+    self.scroll = 0.0;
+
     // Keep the camera's angle from going too high/low.
     if camera.pitch < -Rad(SAFE_FRAC_PI_2) {
       camera.pitch = -Rad(SAFE_FRAC_PI_2);
@@ -199,3 +344,199 @@ impl Controller {
     }
   }
 }
+
+/// Circles around a target point, for inspecting a single model. Dragging the mouse orbits,
+/// dragging with Left Shift held pans the target and scrolling zooms in and out.
+pub struct OrbitController {
+  pub target: Point3<f32>,
+  pub distance: f32,
+  /// Angle around the Y axis of the camera's position as seen from the target.
+  pub azimuth: Rad<f32>,
+  /// Angle above the XZ plane of the camera's position as seen from the target.
+  pub elevation: Rad<f32>,
+  pub min_distance: f32,
+  pub max_distance: f32,
+  mouse_dx: f32,
+  mouse_dy: f32,
+  scroll: f32,
+  panning: bool,
+  /// Radians per unit of mouse movement.
+  sensitivity: f32,
+}
+
+impl OrbitController {
+  pub fn new(
+    target: impl Into<Point3<f32>>,
+    distance: f32,
+    azimuth: impl Into<Rad<f32>>,
+    elevation: impl Into<Rad<f32>>,
+    sensitivity: f32,
+  ) -> Self {
+    Self {
+      target: target.into(),
+      distance,
+      azimuth: azimuth.into(),
+      elevation: elevation.into(),
+      min_distance: 0.1,
+      max_distance: 1000.0,
+      mouse_dx: 0.0,
+      mouse_dy: 0.0,
+      scroll: 0.0,
+      panning: false,
+      sensitivity,
+    }
+  }
+
+  /// Orbits around the point `distance` in front of `camera`, so switching to this controller
+  /// doesn't move the camera.
+  pub fn from_camera(camera: &Camera, distance: f32, sensitivity: f32) -> Self {
+    let forward = camera.forward();
+    let to_camera = -forward;
+    Self::new(
+      camera.position + forward * distance,
+      distance,
+      Rad(to_camera.z.atan2(to_camera.x)),
+      Rad(to_camera.y.clamp(-1.0, 1.0).asin()),
+      sensitivity,
+    )
+  }
+}
+
+impl CameraController for OrbitController {
+  fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
+    match key {
+      VirtualKeyCode::LShift => {
+        self.panning = state == ElementState::Pressed;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
+    // Several events can arrive between two frames
+    self.mouse_dx += mouse_dx as f32;
+    self.mouse_dy += mouse_dy as f32;
+  }
+
+  fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+    self.scroll += scroll_lines(delta);
+  }
+
+  fn update_camera(&mut self, camera: &mut Camera, _dt: Duration) {
+    // Mouse movement is a distance rather than a rate, so it isn't scaled by the frame time
+    if self.panning {
+      // Move the target in the camera's plane, faster the further away it is
+      let forward = camera.forward();
+      let right = forward.cross(Vector3::unit_y()).normalize();
+      let up = right.cross(forward);
+      let scale = self.distance * self.sensitivity * 0.5;
+      self.target += (up * self.mouse_dy - right * self.mouse_dx) * scale;
+    } else {
+      self.azimuth += Rad(self.mouse_dx * self.sensitivity);
+      self.elevation += Rad(self.mouse_dy * self.sensitivity);
+      self.azimuth = Rad(self.azimuth.0.rem_euclid(2.0 * PI));
+      self.elevation = Rad(self.elevation.0.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2));
+    }
+    // Each line scrolled zooms by the same factor, however far away the target is
+    let distance =
+      (self.distance * ZOOM_PER_LINE.powf(self.scroll)).clamp(self.min_distance, self.max_distance);
+    // Moving closer doesn't make anything bigger in an orthographic view, so zoom it as well
+    if let Projection::Orthographic { height } = &mut camera.projection {
+      *height *= distance / self.distance;
+    }
+    self.distance = distance;
+    self.mouse_dx = 0.0;
+    self.mouse_dy = 0.0;
+    self.scroll = 0.0;
+
+    let (azimuth_sin, azimuth_cos) = self.azimuth.0.sin_cos();
+    let (elevation_sin, elevation_cos) = self.elevation.0.sin_cos();
+    let offset = Vector3::new(
+      elevation_cos * azimuth_cos,
+      elevation_sin,
+      elevation_cos * azimuth_sin,
+    );
+    camera.position = self.target + offset * self.distance;
+    camera.look_at(self.target);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cgmath::{assert_abs_diff_eq, Deg};
+
+  const SIZE: PhysicalSize<u32> = PhysicalSize::new(200, 100);
+  const DEPTH_MODES: [DepthMode; 2] = [DepthMode::Standard, DepthMode::ReverseZ];
+
+  /// A camera 10 units along +Z looking back at the origin, with a 90 degree field of view in
+  /// a window twice as wide as it's high.
+  fn camera(projection: Projection, depth_mode: DepthMode) -> Camera {
+    let mut camera = Camera::new(
+      (0.0, 0.0, 10.0),
+      Deg(-90.0),
+      Deg(0.0),
+      SIZE.width,
+      SIZE.height,
+      Deg(90.0),
+      0.1,
+      100.0,
+    );
+    camera.projection = projection;
+    camera.depth_mode = depth_mode;
+    camera
+  }
+
+  #[test]
+  fn screen_centre_rays_go_straight_ahead() {
+    let centre = PhysicalPosition::new(100.0, 50.0);
+    for projection in [
+      Projection::Perspective {
+        fovy: Deg(90.0).into(),
+      },
+      Projection::Orthographic { height: 4.0 },
+    ] {
+      for depth_mode in DEPTH_MODES {
+        let ray = camera(projection, depth_mode).screen_ray(centre, SIZE);
+        assert_abs_diff_eq!(ray.origin, Point3::new(0.0, 0.0, 9.9), epsilon = 1e-4);
+        assert_abs_diff_eq!(ray.direction, -Vector3::unit_z(), epsilon = 1e-4);
+      }
+    }
+  }
+
+  #[test]
+  fn perspective_rays_spread_with_the_field_of_view() {
+    for depth_mode in DEPTH_MODES {
+      let camera = camera(
+        Projection::Perspective {
+          fovy: Deg(90.0).into(),
+        },
+        depth_mode,
+      );
+      // The top edge is 45 degrees up, and the right edge twice as far to the side
+      let top = camera.screen_ray(PhysicalPosition::new(100.0, 0.0), SIZE);
+      assert_abs_diff_eq!(
+        top.direction,
+        Vector3::new(0.0, 1.0, -1.0).normalize(),
+        epsilon = 1e-4
+      );
+      let right = camera.screen_ray(PhysicalPosition::new(200.0, 50.0), SIZE);
+      assert_abs_diff_eq!(
+        right.direction,
+        Vector3::new(2.0, 0.0, -1.0).normalize(),
+        epsilon = 1e-4
+      );
+    }
+  }
+
+  #[test]
+  fn orthographic_rays_are_parallel() {
+    for depth_mode in DEPTH_MODES {
+      let camera = camera(Projection::Orthographic { height: 4.0 }, depth_mode);
+      let corner = camera.screen_ray(PhysicalPosition::new(200.0, 0.0), SIZE);
+      assert_abs_diff_eq!(corner.origin, Point3::new(4.0, 2.0, 9.9), epsilon = 1e-4);
+      assert_abs_diff_eq!(corner.direction, -Vector3::unit_z(), epsilon = 1e-4);
+    }
+  }
+}
@@ -0,0 +1,323 @@
+//! Conversion of equirectangular panoramas to cube textures.
+
+use crate::mipmap;
+use std::{
+  collections::HashMap,
+  num::NonZeroU32,
+  sync::{Arc, Mutex},
+};
+
+/// The layer of each face in a cube texture, which is also the order `Texture::from_cube_faces`
+/// takes them in.
+pub const FACES: [&str; 6] = ["+X", "-X", "+Y", "-Y", "+Z", "-Z"];
+
+/// The render pipelines `from_equirectangular` draws with, built the first time each format
+/// needs one.
+#[derive(Default)]
+pub struct EquirectangularPipelines {
+  pipelines: Mutex<HashMap<wgpu::TextureFormat, Arc<EquirectangularPipeline>>>,
+}
+
+struct EquirectangularPipeline {
+  pipeline: wgpu::RenderPipeline,
+  bind_group_layout: wgpu::BindGroupLayout,
+  sampler: wgpu::Sampler,
+}
+
+impl EquirectangularPipelines {
+  fn get(
+    &self,
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+  ) -> Arc<EquirectangularPipeline> {
+    let mut pipelines = self.pipelines.lock().unwrap();
+    pipelines
+      .entry(format)
+      .or_insert_with(|| Arc::new(EquirectangularPipeline::new(device, format)))
+      .clone()
+  }
+}
+
+impl EquirectangularPipeline {
+  fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+    let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+      label: Some("Equirectangular Shader"),
+      source: wgpu::ShaderSource::Wgsl(include_str!("cubemap.wgsl").into()),
+    });
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+      label: Some("Equirectangular Bind Group Layout"),
+      entries: &[
+        wgpu::BindGroupLayoutEntry {
+          binding: 0,
+          visibility: wgpu::ShaderStages::FRAGMENT,
+          ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+          },
+          count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+          binding: 1,
+          visibility: wgpu::ShaderStages::FRAGMENT,
+          ty: wgpu::BindingType::Sampler {
+            comparison: false,
+            filtering: true,
+          },
+          count: None,
+        },
+      ],
+    });
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      label: Some("Equirectangular Pipeline Layout"),
+      bind_group_layouts: &[&bind_group_layout],
+      push_constant_ranges: &[],
+    });
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      label: Some("Equirectangular Pipeline"),
+      layout: Some(&pipeline_layout),
+      vertex: wgpu::VertexState {
+        module: &shader,
+        entry_point: "vs_main",
+        buffers: &[],
+      },
+      fragment: Some(wgpu::FragmentState {
+        module: &shader,
+        entry_point: "fs_main",
+        targets: &[format.into()],
+      }),
+      primitive: wgpu::PrimitiveState::default(),
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+    });
+    // Longitude wraps around, latitude doesn't
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+      label: Some("Equirectangular Sampler"),
+      address_mode_u: wgpu::AddressMode::Repeat,
+      address_mode_v: wgpu::AddressMode::ClampToEdge,
+      mag_filter: wgpu::FilterMode::Linear,
+      min_filter: wgpu::FilterMode::Linear,
+      mipmap_filter: wgpu::FilterMode::Linear,
+      ..Default::default()
+    });
+    Self {
+      pipeline,
+      bind_group_layout,
+      sampler,
+    }
+  }
+}
+
+/// Renders the six faces of a cube texture from an equirectangular `panorama`, then fills in
+/// the rest of the mip chain.
+///
+/// `format` has to be renderable and filterable, and `panorama` filterable, since both are
+/// sampled with a linear filter. Faces smaller than the panorama sample its mip level closest
+/// to their own size, so it should have a full mip chain.
+#[allow(clippy::too_many_arguments)]
+pub fn from_equirectangular(
+  pipelines: &EquirectangularPipelines,
+  mipmaps: &mipmap::MipmapPipelines,
+  device: &wgpu::Device,
+  queue: &wgpu::Queue,
+  panorama: &wgpu::TextureView,
+  format: wgpu::TextureFormat,
+  face_size: u32,
+  mip_level_count: u32,
+) -> wgpu::Texture {
+  let texture = device.create_texture(&wgpu::TextureDescriptor {
+    label: Some("Cube Texture"),
+    size: wgpu::Extent3d {
+      width: face_size,
+      height: face_size,
+      depth_or_array_layers: 6,
+    },
+    mip_level_count,
+    sample_count: 1,
+    dimension: wgpu::TextureDimension::D2,
+    format,
+    usage: wgpu::TextureUsages::TEXTURE_BINDING
+      | wgpu::TextureUsages::COPY_DST
+      | wgpu::TextureUsages::COPY_SRC
+      | wgpu::TextureUsages::RENDER_ATTACHMENT,
+  });
+
+  let EquirectangularPipeline {
+    pipeline,
+    bind_group_layout,
+    sampler,
+  } = &*pipelines.get(device, format);
+  let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+    label: Some("Equirectangular Bind Group"),
+    layout: bind_group_layout,
+    entries: &[
+      wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(panorama),
+      },
+      wgpu::BindGroupEntry {
+        binding: 1,
+        resource: wgpu::BindingResource::Sampler(sampler),
+      },
+    ],
+  });
+
+  let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+    label: Some("Equirectangular Encoder"),
+  });
+  for face in 0..FACES.len() as u32 {
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+      label: Some("Cube Face View"),
+      dimension: Some(wgpu::TextureViewDimension::D2),
+      mip_level_count: NonZeroU32::new(1),
+      base_array_layer: face,
+      array_layer_count: NonZeroU32::new(1),
+      ..Default::default()
+    });
+    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+      label: Some("Equirectangular Pass"),
+      color_attachments: &[wgpu::RenderPassColorAttachment {
+        view: &view,
+        resolve_target: None,
+        ops: wgpu::Operations {
+          load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+          store: true,
+        },
+      }],
+      depth_stencil_attachment: None,
+    });
+    render_pass.set_pipeline(pipeline);
+    render_pass.set_bind_group(0, &bind_group, &[]);
+    render_pass.draw(0..3, face..face + 1);
+  }
+  queue.submit(std::iter::once(encoder.finish()));
+
+  mipmap::generate_gpu(
+    mipmaps,
+    device,
+    queue,
+    &texture,
+    format,
+    mip_level_count,
+    FACES.len() as u32,
+    false,
+  );
+  texture
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::texture::{HdrImage, ReadbackImage, SamplerCache, SamplerOptions, Texture};
+
+  /// Reads the centre pixel of one face of a float cube texture.
+  fn centre(device: &wgpu::Device, queue: &wgpu::Queue, cube: &Texture, face: u32) -> [f32; 4] {
+    match pollster::block_on(cube.read_level_to_image(device, queue, 0, face)) {
+      Ok(ReadbackImage::Rgba32F(image)) => {
+        let (width, height) = image.dimensions();
+        image.get_pixel(width / 2, height / 2).0
+      }
+      _ => panic!("expected a float readback"),
+    }
+  }
+
+  fn cube_from(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    samplers: &SamplerCache,
+    (width, height): (u32, u32),
+    pixel: impl Fn(u32, u32) -> [f32; 4],
+    face_size: u32,
+  ) -> Texture {
+    let image = HdrImage {
+      width,
+      height,
+      pixels: (0..width * height)
+        .map(|i| pixel(i % width, i / width))
+        .collect(),
+    };
+    let panorama = Texture::from_hdr(
+      "panorama",
+      device,
+      queue,
+      &image,
+      wgpu::TextureFormat::Rgba16Float,
+      samplers,
+      &SamplerOptions::default(),
+    )
+    .unwrap();
+    Texture::from_equirectangular(
+      "cube",
+      device,
+      queue,
+      &panorama,
+      face_size,
+      samplers,
+      &SamplerOptions::default(),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  #[ignore = "needs a GPU"]
+  fn faces_look_in_the_right_directions() {
+    let (device, queue) = crate::testing::device();
+    let samplers = SamplerCache::new();
+    // The sky is red and the ground is blue
+    let cube = cube_from(
+      &device,
+      &queue,
+      &samplers,
+      (64, 32),
+      |_, y| {
+        if y < 16 {
+          [1.0, 0.0, 0.0, 1.0]
+        } else {
+          [0.0, 0.0, 1.0, 1.0]
+        }
+      },
+      16,
+    );
+    assert_eq!(cube.size.depth_or_array_layers, 6);
+    assert_eq!(centre(&device, &queue, &cube, 2), [1.0, 0.0, 0.0, 1.0]);
+    assert_eq!(centre(&device, &queue, &cube, 3), [0.0, 0.0, 1.0, 1.0]);
+  }
+
+  #[test]
+  #[ignore = "needs a GPU"]
+  fn pipelines_are_built_once_per_format() {
+    let (device, queue) = crate::testing::device();
+    let samplers = SamplerCache::new();
+    for face_size in [4, 8] {
+      cube_from(
+        &device,
+        &queue,
+        &samplers,
+        (16, 8),
+        |_, _| [1.0; 4],
+        face_size,
+      );
+    }
+    let pipelines = samplers.equirectangular().pipelines.lock().unwrap();
+    assert_eq!(pipelines.len(), 1);
+  }
+
+  #[test]
+  #[ignore = "needs a GPU"]
+  fn small_faces_sample_smaller_mip_levels() {
+    let (device, queue) = crate::testing::device();
+    let samplers = SamplerCache::new();
+    // Stripes one texel wide, which only average out to grey in the smaller mip levels
+    let cube = cube_from(
+      &device,
+      &queue,
+      &samplers,
+      (256, 128),
+      |x, _| [(x % 2) as f32, 0.0, 0.0, 1.0],
+      4,
+    );
+    for face in [0, 1, 4, 5] {
+      let red = centre(&device, &queue, &cube, face)[0];
+      assert!((red - 0.5).abs() < 0.05, "face {} is {}", face, red);
+    }
+  }
+}
@@ -0,0 +1,52 @@
+struct VertexOutput {
+  [[builtin(position)]] pos: vec4<f32>;
+  // From -1 to 1 across the face, with y pointing down
+  [[location(0)]] face_coords: vec2<f32>;
+  [[location(1), interpolate(flat)]] face: u32;
+};
+
+// A single triangle covering the whole face, which is picked by the instance index
+[[stage(vertex)]]
+fn vs_main(
+  [[builtin(vertex_index)]] index: u32,
+  [[builtin(instance_index)]] face: u32,
+) -> VertexOutput {
+  let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+  var out: VertexOutput;
+  out.pos = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+  out.face_coords = uv * 2.0 - 1.0;
+  out.face = face;
+  return out;
+}
+
+[[group(0), binding(0)]] var t_panorama: texture_2d<f32>;
+[[group(0), binding(1)]] var s_panorama: sampler;
+
+let PI: f32 = 3.14159265359;
+
+// The direction through a point on a face, in the order +X, -X, +Y, -Y, +Z, -Z
+fn face_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+  switch (i32(face)) {
+    case 0: { return vec3<f32>(1.0, -uv.y, -uv.x); }
+    case 1: { return vec3<f32>(-1.0, -uv.y, uv.x); }
+    case 2: { return vec3<f32>(uv.x, 1.0, uv.y); }
+    case 3: { return vec3<f32>(uv.x, -1.0, -uv.y); }
+    case 4: { return vec3<f32>(uv.x, -uv.y, 1.0); }
+    default: { return vec3<f32>(-uv.x, -uv.y, -1.0); }
+  }
+}
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+  let direction = normalize(face_direction(in.face, in.face_coords));
+  let longitude = atan2(direction.z, direction.x);
+  let latitude = asin(clamp(direction.y, -1.0, 1.0));
+  let uv = vec2<f32>(longitude / (2.0 * PI) + 0.5, 0.5 - latitude / PI);
+  // Neighbouring pixels can be on opposite edges of the panorama, so pick the mip level from
+  // the size of the face instead of the derivatives of uv. A face covers a quarter of the
+  // panorama's width and half of its height.
+  let face_size = 2.0 / abs(dpdx(in.face_coords.x));
+  let panorama_size = vec2<f32>(textureDimensions(t_panorama));
+  let lod = log2(max(panorama_size.x / 4.0, panorama_size.y / 2.0) / face_size);
+  return textureSampleLevel(t_panorama, s_panorama, uv, max(lod, 0.0));
+}
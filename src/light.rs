@@ -32,14 +32,6 @@ impl LightUniform {
 }
 
 pub trait DrawLight<'a> {
-  fn draw_light_mesh(
-    &mut self,
-    mesh: &'a Mesh,
-    camera_bind_group: &'a wgpu::BindGroup,
-    light_bind_group: &'a wgpu::BindGroup,
-  ) {
-    self.draw_light_mesh_instanced(mesh, camera_bind_group, light_bind_group, 0..1)
-  }
   fn draw_light_mesh_instanced(
     &mut self,
     mesh: &'a Mesh,
@@ -77,7 +69,7 @@ where
     instances: Range<u32>,
   ) {
     self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-    self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+    self.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
     self.set_bind_group(0, camera_bind_group, &[]);
     self.set_bind_group(1, light_bind_group, &[]);
     self.draw_indexed(0..mesh.num_elements, 0, instances);
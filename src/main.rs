@@ -1,12 +1,20 @@
+mod bounds;
 mod camera;
+mod cubemap;
 mod light;
+mod mipmap;
 mod model;
+#[cfg(test)]
+mod testing;
 mod texture;
 
-use std::time::{Duration, Instant};
+use std::{
+  path::{Path, PathBuf},
+  time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use cgmath::{prelude::*, Matrix3, Matrix4, Quaternion};
+use cgmath::{prelude::*, Matrix3, Matrix4, Point3, Quaternion};
 use wgpu::util::DeviceExt;
 use winit::{
   event::*,
@@ -14,10 +22,11 @@ use winit::{
   window::{Window, WindowBuilder},
 };
 
-use camera::{Camera, CameraUniform};
+use bounds::{Frustum, Ray};
+use camera::{Camera, CameraController, CameraUniform, FlyController, OrbitController, Projection};
 use light::LightUniform;
-use model::{Model, ModelVertex, Vertex};
-use texture::Texture;
+use model::{BlendMode, Model, ModelLoadOptions, ModelVertex, Vertex};
+use texture::{DepthMode, Texture, TextureRegistry};
 
 // continue:
 // https://sotrh.github.io/learn-wgpu/beginner/tutorial7-instancing/#the-instance-buffer
@@ -28,14 +37,45 @@ struct Instance {
 }
 
 impl Instance {
+  pub fn matrix(&self) -> Matrix4<f32> {
+    Matrix4::from_translation(self.position) * Matrix4::from(self.rotation)
+  }
+
   pub fn data(&self) -> InstanceData {
-    let translation = Matrix4::from_translation(self.position);
-    let rotation = Matrix4::from(self.rotation);
     InstanceData {
-      model: (translation * rotation).into(),
+      model: self.matrix().into(),
       normal: Matrix3::from(self.rotation).into(),
     }
   }
+
+  /// Where the ray first hits the bounding box of the instance's model, in world space.
+  pub fn intersect_ray(&self, model: &Model, ray: &Ray) -> Option<Point3<f32>> {
+    let matrix = self.matrix();
+    ray.intersect_sphere(&model.bounding_sphere.transform(&matrix))?;
+    // The box is tighter in model space than around the rotated instance
+    let local = ray.transform(&matrix.invert()?);
+    let distance = local.intersect_aabb(&model.aabb)?;
+    Some(Point3::from_homogeneous(
+      matrix * local.at(distance).to_homogeneous(),
+    ))
+  }
+
+  /// Whether any of the model could be on screen, trying its cheaper bounding sphere first.
+  pub fn is_visible(&self, model: &Model, frustum: &Frustum) -> bool {
+    let matrix = self.matrix();
+    frustum.intersects_sphere(&model.bounding_sphere.transform(&matrix))
+      && frustum.intersects_aabb(&model.aabb.transform(&matrix))
+  }
+}
+
+/// Where a ray hit an instance.
+#[derive(Copy, Clone, Debug)]
+struct Hit {
+  /// Index into `State::instances`.
+  instance: usize,
+  /// From the origin of the ray.
+  distance: f32,
+  position: Point3<f32>,
 }
 
 #[repr(C)]
@@ -97,6 +137,14 @@ impl Vertex for InstanceData {
 const NUM_INSTANCES_PER_ROW: u32 = 10;
 const ANGULAR_VELOCITY: cgmath::Rad<f32> = cgmath::Rad(0.0); //cgmath::Rad(std::f32::consts::PI / 144.0);
 const SPACE_BETWEEN: f32 = 3.0;
+const FOVY: cgmath::Deg<f32> = cgmath::Deg(45.0);
+
+/// Which camera controller is active, switched with Tab.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum CameraMode {
+  Fly,
+  Orbit,
+}
 
 struct State {
   surface: wgpu::Surface,
@@ -107,26 +155,35 @@ struct State {
   camera: Camera,
   camera_uniform: CameraUniform,
   camera_buffer: wgpu::Buffer,
-  camera_controller: camera::Controller,
+  camera_mode: CameraMode,
+  camera_controller: Box<dyn CameraController>,
   camera_bind_group: wgpu::BindGroup,
   light_uniform: LightUniform,
   light_buffer: wgpu::Buffer,
   light_bind_group: wgpu::BindGroup,
   render_pipeline: wgpu::RenderPipeline,
+  /// Blends transparent materials over the opaque ones.
+  transparent_render_pipeline: wgpu::RenderPipeline,
   light_render_pipeline: wgpu::RenderPipeline,
   model: Model,
   depth_texture: Texture,
   instances: Vec<Instance>,
   instance_buffer: wgpu::Buffer,
+  /// How many instances at the start of the instance buffer passed culling.
+  visible_instances: u32,
   mouse_pressed: bool,
+  cursor_position: winit::dpi::PhysicalPosition<f64>,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_render_pipeline(
   label: &str,
   device: &wgpu::Device,
   layout: &wgpu::PipelineLayout,
   color_format: wgpu::TextureFormat,
+  blend: wgpu::BlendState,
   depth_format: Option<wgpu::TextureFormat>,
+  depth_mode: DepthMode,
   vertex_layouts: &[wgpu::VertexBufferLayout],
   shader: wgpu::ShaderModuleDescriptor,
 ) -> wgpu::RenderPipeline {
@@ -145,10 +202,7 @@ fn create_render_pipeline(
       entry_point: "fs_main",
       targets: &[wgpu::ColorTargetState {
         format: color_format,
-        blend: Some(wgpu::BlendState {
-          alpha: wgpu::BlendComponent::REPLACE,
-          color: wgpu::BlendComponent::REPLACE,
-        }),
+        blend: Some(blend),
         write_mask: wgpu::ColorWrites::ALL,
       }],
     }),
@@ -166,8 +220,10 @@ fn create_render_pipeline(
     },
     depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
       format,
-      depth_write_enabled: true,
-      depth_compare: wgpu::CompareFunction::Less,
+      // Blended surfaces are drawn back to front after everything else, and mustn't hide what's
+      // behind them
+      depth_write_enabled: blend == wgpu::BlendState::REPLACE,
+      depth_compare: depth_mode.compare(),
       stencil: wgpu::StencilState::default(),
       bias: wgpu::DepthBiasState::default(),
     }),
@@ -180,7 +236,9 @@ fn create_render_pipeline(
 }
 
 impl State {
-  async fn new(window: &Window) -> Result<Self> {
+  /// `depth_mode` is shared by the camera, every pipeline with a depth test and the depth
+  /// buffer. Without a `cache_dir`, the model is imported from scratch.
+  async fn new(window: &Window, depth_mode: DepthMode, cache_dir: Option<&Path>) -> Result<Self> {
     let size = window.inner_size();
 
     // Backends::all => Vulkan + Metal + DX12 + Browser WebGPU
@@ -198,7 +256,11 @@ impl State {
     let (device, queue) = adapter
       .request_device(
         &wgpu::DeviceDescriptor {
-          features: wgpu::Features::empty(),
+          // Compressed textures are decompressed on the CPU when these aren't available
+          features: adapter.features()
+            & (wgpu::Features::TEXTURE_COMPRESSION_BC
+              | wgpu::Features::TEXTURE_COMPRESSION_ETC2
+              | wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR),
           limits: wgpu::Limits::default(),
           label: None,
         },
@@ -215,17 +277,18 @@ impl State {
     };
     surface.configure(&device, &config);
 
-    let camera = Camera::new(
+    let mut camera = Camera::new(
       (0.0, 5.0, 10.0),
       cgmath::Deg(-90.0),
       cgmath::Deg(-20.0),
       config.width,
       config.height,
-      cgmath::Deg(45.0),
+      FOVY,
       0.1,
       100.0,
     );
-    let camera_controller = camera::Controller::new(4.0, 0.4);
+    camera.depth_mode = depth_mode;
+    let camera_controller = Box::new(FlyController::new(4.0, 0.4));
 
     let mut camera_uniform = CameraUniform::new();
     camera_uniform.update_view_proj(&camera);
@@ -331,12 +394,24 @@ impl State {
             },
             count: None,
           },
+          // material properties
+          wgpu::BindGroupLayoutEntry {
+            binding: 4,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+              ty: wgpu::BufferBindingType::Uniform,
+              has_dynamic_offset: false,
+              min_binding_size: None,
+            },
+            count: None,
+          },
         ],
       });
 
-    let depth_texture = Texture::create_depth_texture("depth_texture", &device, &config);
+    let depth_texture =
+      Texture::create_depth_texture("depth_texture", &device, &config, depth_mode);
 
-    let render_pipeline = {
+    let (render_pipeline, transparent_render_pipeline) = {
       let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Triangle Pipeline Layout"),
         bind_group_layouts: &[
@@ -346,17 +421,28 @@ impl State {
         ],
         push_constant_ranges: &[],
       });
-      create_render_pipeline(
-        "Render Pipeline",
-        &device,
-        &layout,
-        config.format,
-        Some(Texture::DEPTH_FORMAT),
-        &[ModelVertex::descriptor(), InstanceData::descriptor()],
-        wgpu::ShaderModuleDescriptor {
-          label: Some("Triangle Shader"),
-          source: wgpu::ShaderSource::Wgsl(include_str!("triangle.wgsl").into()),
-        },
+      let create = |label, blend| {
+        create_render_pipeline(
+          label,
+          &device,
+          &layout,
+          config.format,
+          blend,
+          Some(Texture::DEPTH_FORMAT),
+          depth_mode,
+          &[ModelVertex::descriptor(), InstanceData::descriptor()],
+          wgpu::ShaderModuleDescriptor {
+            label: Some("Triangle Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("triangle.wgsl").into()),
+          },
+        )
+      };
+      (
+        create("Render Pipeline", wgpu::BlendState::REPLACE),
+        create(
+          "Transparent Render Pipeline",
+          wgpu::BlendState::ALPHA_BLENDING,
+        ),
       )
     };
     let light_render_pipeline = {
@@ -370,7 +456,9 @@ impl State {
         &device,
         &layout,
         config.format,
+        wgpu::BlendState::REPLACE,
         Some(Texture::DEPTH_FORMAT),
+        depth_mode,
         &[ModelVertex::descriptor()],
         wgpu::ShaderModuleDescriptor {
           label: Some("Light Shader"),
@@ -379,7 +467,35 @@ impl State {
       )
     };
 
-    let model = Model::load("res/cube.obj", &device, &queue, &texture_bind_group_layout)?;
+    let textures = TextureRegistry::new(&device, &queue)?;
+    let options = ModelLoadOptions {
+      optimize: true,
+      ..Default::default()
+    };
+    let model = match cache_dir {
+      Some(cache_dir) => Model::load_cached(
+        "res/cube.obj",
+        cache_dir,
+        &device,
+        &queue,
+        &texture_bind_group_layout,
+        &textures,
+        &options,
+      )?,
+      None => Model::load(
+        "res/cube.obj",
+        &device,
+        &queue,
+        &texture_bind_group_layout,
+        &textures,
+        &options,
+      )?,
+    };
+    log::info!(
+      "{} textures loaded, using {:.1} MiB",
+      textures.len(),
+      textures.memory_usage() as f64 / (1024.0 * 1024.0)
+    );
 
     let instances = (0..NUM_INSTANCES_PER_ROW)
       .flat_map(|z| {
@@ -417,17 +533,21 @@ impl State {
       camera_uniform,
       camera_buffer,
       camera_bind_group,
+      camera_mode: CameraMode::Fly,
       camera_controller,
       light_uniform,
       light_buffer,
       light_bind_group,
       render_pipeline,
+      transparent_render_pipeline,
       light_render_pipeline,
       model,
       depth_texture,
+      visible_instances: instances.len() as u32,
       instances,
       instance_buffer,
       mouse_pressed: false,
+      cursor_position: winit::dpi::PhysicalPosition::new(0.0, 0.0),
     })
   }
 
@@ -438,15 +558,63 @@ impl State {
       self.config.height = new_size.height;
       self.surface.configure(&self.device, &self.config);
 
-      self.depth_texture =
-        Texture::create_depth_texture("depth_texture", &self.device, &self.config);
+      self.depth_texture = Texture::create_depth_texture(
+        "depth_texture",
+        &self.device,
+        &self.config,
+        self.camera.depth_mode,
+      );
 
       self.camera.resize(new_size.width, new_size.height);
     }
   }
 
+  /// Swaps the camera controller, keeping the camera where it is.
+  fn set_camera_mode(&mut self, mode: CameraMode) {
+    self.camera_controller = match mode {
+      CameraMode::Fly => Box::new(FlyController::new(4.0, 0.4)),
+      CameraMode::Orbit => {
+        // Orbit the point in front of the camera that's as far away as the origin
+        let distance = self.camera.position.to_vec().magnitude().max(1.0);
+        Box::new(OrbitController::from_camera(&self.camera, distance, 0.005))
+      }
+    };
+    self.camera_mode = mode;
+  }
+
+  /// Switches between perspective and orthographic projection, keeping things as far away as
+  /// the origin about the same size on screen.
+  fn toggle_projection(&mut self) {
+    let distance = self.camera.position.to_vec().magnitude().max(1.0);
+    self.camera.projection = match self.camera.projection {
+      Projection::Perspective { fovy } => Projection::Orthographic {
+        height: 2.0 * distance * (fovy / 2.0).tan(),
+      },
+      Projection::Orthographic { .. } => Projection::Perspective { fovy: FOVY.into() },
+    };
+  }
+
   fn input(&mut self, event: &DeviceEvent) -> bool {
     match event {
+      DeviceEvent::Key(KeyboardInput {
+        virtual_keycode: Some(VirtualKeyCode::Tab),
+        state: ElementState::Pressed,
+        ..
+      }) => {
+        self.set_camera_mode(match self.camera_mode {
+          CameraMode::Fly => CameraMode::Orbit,
+          CameraMode::Orbit => CameraMode::Fly,
+        });
+        true
+      }
+      DeviceEvent::Key(KeyboardInput {
+        virtual_keycode: Some(VirtualKeyCode::P),
+        state: ElementState::Pressed,
+        ..
+      }) => {
+        self.toggle_projection();
+        true
+      }
       DeviceEvent::Key(KeyboardInput {
         virtual_keycode: Some(key),
         state,
@@ -465,10 +633,33 @@ impl State {
         }
         true
       }
+      DeviceEvent::MouseWheel { delta } => {
+        self.camera_controller.process_scroll(delta);
+        true
+      }
       _ => false,
     }
   }
 
+  /// Finds the nearest instance under a position in the window, testing against the bounds of
+  /// its model.
+  fn pick(&self, position: winit::dpi::PhysicalPosition<f64>) -> Option<Hit> {
+    let ray = self.camera.screen_ray(position, self.size);
+    self
+      .instances
+      .iter()
+      .enumerate()
+      .filter_map(|(instance, data)| {
+        let position = data.intersect_ray(&self.model, &ray)?;
+        Some(Hit {
+          instance,
+          distance: position.distance(ray.origin),
+          position,
+        })
+      })
+      .min_by(|a, b| a.distance.total_cmp(&b.distance))
+  }
+
   fn update(&mut self, dt: Duration) {
     self.camera_controller.update_camera(&mut self.camera, dt);
     self.camera_uniform.update_view_proj(&self.camera);
@@ -481,16 +672,30 @@ impl State {
     for instance in &mut self.instances {
       instance.rotation = cgmath::Quaternion::from_angle_y(ANGULAR_VELOCITY) * instance.rotation;
     }
-    let instance_data = self
+    // Only visible instances are uploaded, packed at the start of the buffer
+    let frustum = self.camera.frustum();
+    let mut visible = self
       .instances
       .iter()
-      .map(Instance::data)
+      .filter(|instance| instance.is_visible(&self.model, &frustum))
       .collect::<Vec<_>>();
-    self.queue.write_buffer(
-      &self.instance_buffer,
-      0,
-      bytemuck::cast_slice(&instance_data),
-    );
+    if self.model.has_transparent_materials() {
+      // Blending only looks right drawing back to front
+      let forward = self.camera.forward();
+      let depth = |instance: &Instance| {
+        (Point3::from_vec(instance.position) - self.camera.position).dot(forward)
+      };
+      visible.sort_by(|a, b| depth(b).total_cmp(&depth(a)));
+    }
+    let instance_data = visible.into_iter().map(Instance::data).collect::<Vec<_>>();
+    self.visible_instances = instance_data.len() as u32;
+    if !instance_data.is_empty() {
+      self.queue.write_buffer(
+        &self.instance_buffer,
+        0,
+        bytemuck::cast_slice(&instance_data),
+      );
+    }
 
     let rotation =
       Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(60.0 * dt.as_secs_f32()));
@@ -536,7 +741,7 @@ impl State {
         depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
           view: &self.depth_texture.view,
           depth_ops: Some(wgpu::Operations {
-            load: wgpu::LoadOp::Clear(1.0),
+            load: wgpu::LoadOp::Clear(self.camera.depth_mode.clear_value()),
             store: true,
           }),
           stencil_ops: None,
@@ -553,9 +758,18 @@ impl State {
       render_pass.set_pipeline(&self.render_pipeline);
       render_pass.draw_model_instanced(
         &self.model,
+        BlendMode::Opaque,
+        &self.camera_bind_group,
+        &self.light_bind_group,
+        0..self.visible_instances,
+      );
+      render_pass.set_pipeline(&self.transparent_render_pipeline);
+      render_pass.draw_model_instanced(
+        &self.model,
+        BlendMode::Transparent,
         &self.camera_bind_group,
         &self.light_bind_group,
-        0..self.instances.len() as u32,
+        0..self.visible_instances,
       );
     }
 
@@ -571,11 +785,23 @@ fn main() -> Result<()> {
     "RUST_LOG",
     std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
   );
+  env_logger::init();
   let event_loop = EventLoop::new();
   let window = WindowBuilder::new().build(&event_loop).unwrap();
   window.set_title("wgpu-book");
 
-  let mut state = pollster::block_on(State::new(&window))?;
+  // Reverse-Z keeps far away geometry from z-fighting
+  let depth_mode = if std::env::args().any(|arg| arg == "--reverse-z") {
+    DepthMode::ReverseZ
+  } else {
+    DepthMode::Standard
+  };
+  // Imported models are cached in `--cache-dir <dir>`, so they load faster next time
+  let cache_dir = std::env::args()
+    .skip_while(|arg| arg != "--cache-dir")
+    .nth(1)
+    .map(PathBuf::from);
+  let mut state = pollster::block_on(State::new(&window, depth_mode, cache_dir.as_deref()))?;
   let mut last_render_time = Instant::now();
 
   event_loop.run(move |event, _, control_flow| match event {
@@ -602,6 +828,22 @@ fn main() -> Result<()> {
       WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
         state.resize(**new_inner_size);
       }
+      WindowEvent::CursorMoved { position, .. } => {
+        state.cursor_position = *position;
+      }
+      WindowEvent::MouseInput {
+        state: ElementState::Pressed,
+        button: MouseButton::Right,
+        ..
+      } => match state.pick(state.cursor_position) {
+        Some(hit) => log::info!(
+          "Picked instance {} at {:?}, {:.2} away",
+          hit.instance,
+          hit.position,
+          hit.distance
+        ),
+        None => log::info!("Nothing to pick under the cursor"),
+      },
       _ => {}
     },
     Event::RedrawRequested(_) => {
@@ -0,0 +1,487 @@
+//! Mip chain generation for RGBA textures.
+
+use std::{
+  collections::HashMap,
+  num::NonZeroU32,
+  sync::{Arc, Mutex},
+};
+
+/// How to fill in the mip levels below the first.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MipmapMode {
+  /// Render each level from the previous one with a linear filter. Fast, but only works for
+  /// formats that are renderable and filterable.
+  Gpu,
+  /// Box filter on the CPU before uploading. Slower, but works without a render pass and gives
+  /// the same results on every backend, which makes it useful for testing. The demo only uses
+  /// `Gpu`, so only the tests pick this.
+  #[cfg_attr(not(test), allow(dead_code))]
+  Cpu,
+}
+
+/// The number of levels in a full mip chain, down to 1x1.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+  32 - width.max(height).max(1).leading_zeros()
+}
+
+/// The size of mip `level` of a texture with the given base size.
+pub fn mip_size((width, height): (u32, u32), level: u32) -> (u32, u32) {
+  ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// The render pipelines `generate_gpu` draws with, built the first time each format needs one.
+#[derive(Default)]
+pub struct MipmapPipelines {
+  pipelines: Mutex<HashMap<(wgpu::TextureFormat, bool), Arc<MipmapPipeline>>>,
+}
+
+struct MipmapPipeline {
+  pipeline: wgpu::RenderPipeline,
+  bind_group_layout: wgpu::BindGroupLayout,
+  sampler: wgpu::Sampler,
+}
+
+impl MipmapPipelines {
+  fn get(
+    &self,
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    is_normal_map: bool,
+  ) -> Arc<MipmapPipeline> {
+    let mut pipelines = self.pipelines.lock().unwrap();
+    pipelines
+      .entry((format, is_normal_map))
+      .or_insert_with(|| Arc::new(MipmapPipeline::new(device, format, is_normal_map)))
+      .clone()
+  }
+}
+
+impl MipmapPipeline {
+  fn new(device: &wgpu::Device, format: wgpu::TextureFormat, is_normal_map: bool) -> Self {
+    let shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+      label: Some("Mipmap Shader"),
+      source: wgpu::ShaderSource::Wgsl(include_str!("mipmap.wgsl").into()),
+    });
+    let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+      label: Some("Mipmap Bind Group Layout"),
+      entries: &[
+        wgpu::BindGroupLayoutEntry {
+          binding: 0,
+          visibility: wgpu::ShaderStages::FRAGMENT,
+          ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+          },
+          count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+          binding: 1,
+          visibility: wgpu::ShaderStages::FRAGMENT,
+          ty: wgpu::BindingType::Sampler {
+            comparison: false,
+            filtering: true,
+          },
+          count: None,
+        },
+      ],
+    });
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      label: Some("Mipmap Pipeline Layout"),
+      bind_group_layouts: &[&bind_group_layout],
+      push_constant_ranges: &[],
+    });
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+      label: Some("Mipmap Pipeline"),
+      layout: Some(&pipeline_layout),
+      vertex: wgpu::VertexState {
+        module: &shader,
+        entry_point: "vs_main",
+        buffers: &[],
+      },
+      fragment: Some(wgpu::FragmentState {
+        module: &shader,
+        entry_point: if is_normal_map {
+          "fs_normal"
+        } else {
+          "fs_main"
+        },
+        targets: &[format.into()],
+      }),
+      primitive: wgpu::PrimitiveState::default(),
+      depth_stencil: None,
+      multisample: wgpu::MultisampleState::default(),
+    });
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+      label: Some("Mipmap Sampler"),
+      address_mode_u: wgpu::AddressMode::ClampToEdge,
+      address_mode_v: wgpu::AddressMode::ClampToEdge,
+      address_mode_w: wgpu::AddressMode::ClampToEdge,
+      mag_filter: wgpu::FilterMode::Linear,
+      min_filter: wgpu::FilterMode::Linear,
+      mipmap_filter: wgpu::FilterMode::Nearest,
+      ..Default::default()
+    });
+    Self {
+      pipeline,
+      bind_group_layout,
+      sampler,
+    }
+  }
+}
+
+/// Renders every mip level of `texture` below the first from the level above it, for each of
+/// its `array_layer_count` layers.
+///
+/// The texture needs `RENDER_ATTACHMENT` usage. With an sRGB `format` the hardware converts to
+/// linear before filtering, and normal maps are renormalised after filtering.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_gpu(
+  pipelines: &MipmapPipelines,
+  device: &wgpu::Device,
+  queue: &wgpu::Queue,
+  texture: &wgpu::Texture,
+  format: wgpu::TextureFormat,
+  mip_level_count: u32,
+  array_layer_count: u32,
+  is_normal_map: bool,
+) {
+  if mip_level_count < 2 {
+    return;
+  }
+  let MipmapPipeline {
+    pipeline,
+    bind_group_layout,
+    sampler,
+  } = &*pipelines.get(device, format, is_normal_map);
+
+  let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+    label: Some("Mipmap Encoder"),
+  });
+  for layer in 0..array_layer_count {
+    // Each layer of an array or cube texture is rendered through its own 2D views
+    let views = (0..mip_level_count)
+      .map(|level| {
+        texture.create_view(&wgpu::TextureViewDescriptor {
+          label: Some("Mipmap Level View"),
+          dimension: Some(wgpu::TextureViewDimension::D2),
+          base_mip_level: level,
+          mip_level_count: NonZeroU32::new(1),
+          base_array_layer: layer,
+          array_layer_count: NonZeroU32::new(1),
+          ..Default::default()
+        })
+      })
+      .collect::<Vec<_>>();
+    for level in 1..mip_level_count as usize {
+      let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Mipmap Bind Group"),
+        layout: bind_group_layout,
+        entries: &[
+          wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::TextureView(&views[level - 1]),
+          },
+          wgpu::BindGroupEntry {
+            binding: 1,
+            resource: wgpu::BindingResource::Sampler(sampler),
+          },
+        ],
+      });
+
+      let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Mipmap Pass"),
+        color_attachments: &[wgpu::RenderPassColorAttachment {
+          view: &views[level],
+          resolve_target: None,
+          ops: wgpu::Operations {
+            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+            store: true,
+          },
+        }],
+        depth_stencil_attachment: None,
+      });
+      render_pass.set_pipeline(pipeline);
+      render_pass.set_bind_group(0, &bind_group, &[]);
+      render_pass.draw(0..3, 0..1);
+    }
+  }
+  queue.submit(std::iter::once(encoder.finish()));
+}
+
+/// Box filters `rgba` down to 1x1, returning every level below the first. Odd sizes are filtered
+/// with three taps, so no row or column is left out.
+///
+/// Colour channels are averaged in linear space when `is_srgb` is set, and normal maps are
+/// renormalised after averaging.
+pub fn generate_cpu(
+  rgba: &[u8],
+  dimensions: (u32, u32),
+  is_srgb: bool,
+  is_normal_map: bool,
+) -> Vec<Vec<u8>> {
+  let srgb_to_linear = (0..=255u8)
+    .map(|c| {
+      let c = c as f32 / 255.0;
+      if c <= 0.04045 {
+        c / 12.92
+      } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+      }
+    })
+    .collect::<Vec<_>>();
+  let decode = |pixel: &[u8]| -> [f32; 4] {
+    let channel = |c: u8| {
+      if is_normal_map {
+        c as f32 / 255.0 * 2.0 - 1.0
+      } else if is_srgb {
+        srgb_to_linear[c as usize]
+      } else {
+        c as f32 / 255.0
+      }
+    };
+    [
+      channel(pixel[0]),
+      channel(pixel[1]),
+      channel(pixel[2]),
+      pixel[3] as f32 / 255.0,
+    ]
+  };
+  let encode = |[r, g, b, a]: [f32; 4]| -> [u8; 4] {
+    let to_u8 = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    let [r, g, b] = if is_normal_map {
+      let length = (r * r + g * g + b * b).sqrt();
+      let normal = if length > 1e-6 {
+        [r / length, g / length, b / length]
+      } else {
+        [0.0, 0.0, 1.0]
+      };
+      normal.map(|c| c * 0.5 + 0.5)
+    } else if is_srgb {
+      [r, g, b].map(|c| {
+        if c <= 0.0031308 {
+          c * 12.92
+        } else {
+          1.055 * c.powf(1.0 / 2.4) - 0.055
+        }
+      })
+    } else {
+      [r, g, b]
+    };
+    [to_u8(r), to_u8(g), to_u8(b), to_u8(a)]
+  };
+
+  let mut levels = Vec::<Vec<u8>>::new();
+  let mut size = dimensions;
+  for _ in 1..mip_level_count(dimensions.0, dimensions.1) {
+    let source = levels.last().map_or(rgba, Vec::as_slice);
+    let pixels = source.chunks_exact(4).map(decode).collect::<Vec<_>>();
+    levels.push(
+      downsample(&pixels, size)
+        .into_iter()
+        .flat_map(encode)
+        .collect(),
+    );
+    size = mip_size(size, 1);
+  }
+  levels
+}
+
+/// Box filters linear, floating point pixels down to 1x1, returning every level below the first.
+pub fn generate_cpu_f32(pixels: &[[f32; 4]], dimensions: (u32, u32)) -> Vec<Vec<[f32; 4]>> {
+  let mut levels = Vec::<Vec<[f32; 4]>>::new();
+  let mut size = dimensions;
+  for _ in 1..mip_level_count(dimensions.0, dimensions.1) {
+    let source = levels.last().map_or(pixels, Vec::as_slice);
+    levels.push(downsample(source, size));
+    size = mip_size(size, 1);
+  }
+  levels
+}
+
+/// Filters a level of `size` down to the next smaller one.
+fn downsample(source: &[[f32; 4]], size: (u32, u32)) -> Vec<[f32; 4]> {
+  let (width, height) = mip_size(size, 1);
+  let columns = (0..width).map(|x| taps(size.0, x)).collect::<Vec<_>>();
+  let mut data = Vec::with_capacity((width * height) as usize);
+  for y in 0..height {
+    let rows = taps(size.1, y);
+    for column in &columns {
+      let mut sum = [0.0; 4];
+      for &(sy, wy) in rows.iter().filter(|tap| tap.1 > 0.0) {
+        for &(sx, wx) in column.iter().filter(|tap| tap.1 > 0.0) {
+          let pixel = source[(sy * size.0 + sx) as usize];
+          for c in 0..4 {
+            sum[c] += pixel[c] * wx * wy;
+          }
+        }
+      }
+      data.push(sum);
+    }
+  }
+  data
+}
+
+/// The pixels along one axis of a level `source_size` wide that cover pixel `x` of the next
+/// smaller level, with their weights.
+///
+/// Even sizes average pairs of pixels. Odd sizes spread `2n + 1` pixels over `n` with three taps
+/// each, splitting the pixels where neighbours meet instead of dropping the last one.
+fn taps(source_size: u32, x: u32) -> [(u32, f32); 3] {
+  if source_size == 1 {
+    [(0, 1.0), (0, 0.0), (0, 0.0)]
+  } else if source_size % 2 == 1 {
+    let (n, total, xf) = ((source_size / 2) as f32, source_size as f32, x as f32);
+    [
+      (2 * x, (n - xf) / total),
+      (2 * x + 1, n / total),
+      (2 * x + 2, (xf + 1.0) / total),
+    ]
+  } else {
+    [(2 * x, 0.5), (2 * x + 1, 0.5), (0, 0.0)]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::texture::{ReadbackImage, SamplerCache, SamplerOptions, Texture, TextureKind};
+
+  const WHITE: [u8; 4] = [255, 255, 255, 255];
+  const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+  #[test]
+  fn mip_chain_sizes() {
+    assert_eq!(mip_level_count(1, 1), 1);
+    assert_eq!(mip_level_count(256, 256), 9);
+    assert_eq!(mip_level_count(5, 3), 3);
+    assert_eq!(mip_size((5, 3), 1), (2, 1));
+    assert_eq!(mip_size((5, 3), 2), (1, 1));
+
+    let levels = generate_cpu(&[0; 4 * 5 * 3], (5, 3), false, false);
+    let lens = levels.iter().map(Vec::len).collect::<Vec<_>>();
+    assert_eq!(lens, [4 * 2, 4]);
+  }
+
+  #[test]
+  fn srgb_is_averaged_in_linear_space() {
+    let rgba = [WHITE, BLACK, BLACK, WHITE].concat();
+    // Half of the light is 0.5 linear, which encodes to 188 in sRGB, not 128
+    assert_eq!(
+      generate_cpu(&rgba, (2, 2), true, false),
+      [vec![188, 188, 188, 255]]
+    );
+    assert_eq!(
+      generate_cpu(&rgba, (2, 2), false, false),
+      [vec![128, 128, 128, 255]]
+    );
+  }
+
+  #[test]
+  fn alpha_stays_linear() {
+    let rgba = [[0, 0, 0, 255], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 255]].concat();
+    assert_eq!(generate_cpu(&rgba, (2, 2), true, false)[0][3], 128);
+  }
+
+  #[test]
+  fn odd_sizes_use_every_row_and_column() {
+    let grey = |c: u8| [c, c, c, 255];
+    let rgba = [grey(0), grey(100), grey(200)].concat();
+    assert_eq!(
+      generate_cpu(&rgba, (3, 1), false, false),
+      [vec![100, 100, 100, 255]]
+    );
+
+    // Five pixels spread over two, the middle one counting half for each
+    let rgba = [grey(0), grey(50), grey(100), grey(150), grey(200)].concat();
+    assert_eq!(
+      generate_cpu(&rgba, (1, 5), false, false),
+      [[grey(40), grey(160)].concat(), grey(100).to_vec()]
+    );
+
+    let pixels = (0..9).map(|i| [i as f32; 4]).collect::<Vec<_>>();
+    let levels = generate_cpu_f32(&pixels, (3, 3));
+    assert_eq!(levels.len(), 1);
+    assert!((levels[0][0][0] - 4.0).abs() < 1e-5, "{:?}", levels);
+  }
+
+  #[test]
+  fn normals_are_renormalised() {
+    let x = [255, 128, 128, 255];
+    let z = [128, 128, 255, 255];
+    let rgba = [x, z, z, x].concat();
+    let level = &generate_cpu(&rgba, (2, 2), false, true)[0];
+    let normal = [0, 1, 2].map(|c| level[c] as f32 / 255.0 * 2.0 - 1.0);
+    let length = normal.iter().map(|c| c * c).sum::<f32>().sqrt();
+    assert!(
+      (length - 1.0).abs() < 0.01,
+      "{:?} has length {}",
+      normal,
+      length
+    );
+    assert!((normal[0] - normal[2]).abs() < 0.01 && normal[1].abs() < 0.01);
+  }
+
+  #[test]
+  fn float_levels_average_linearly() {
+    let pixels = [[1.0, 2.0, 3.0, 1.0], [3.0, 2.0, 1.0, 0.0]];
+    assert_eq!(
+      generate_cpu_f32(&pixels, (2, 1)),
+      [vec![[2.0, 2.0, 2.0, 0.5]]]
+    );
+  }
+
+  #[test]
+  #[ignore = "needs a GPU"]
+  fn gpu_matches_cpu() {
+    let (device, queue) = crate::testing::device();
+    let samplers = SamplerCache::new();
+    let dimensions = (32, 16);
+    let rgba = (0..dimensions.0 * dimensions.1)
+      .flat_map(|i| {
+        let (x, y) = (i % dimensions.0, i / dimensions.0);
+        [(x * 8) as u8, (y * 16) as u8, ((x ^ y) * 8) as u8, 255]
+      })
+      .collect::<Vec<_>>();
+
+    for kind in [TextureKind::Color, TextureKind::Linear, TextureKind::Normal] {
+      let texture = |mipmaps| {
+        Texture::from_raw_rgba_with_mipmaps(
+          "mipmap test",
+          &device,
+          &queue,
+          &rgba,
+          dimensions,
+          kind,
+          &samplers,
+          &SamplerOptions::default(),
+          mipmaps,
+        )
+        .unwrap()
+      };
+      let (gpu, cpu) = (texture(MipmapMode::Gpu), texture(MipmapMode::Cpu));
+      for level in 1..gpu.mip_level_count {
+        let read = |texture: &Texture| match pollster::block_on(
+          texture.read_level_to_image(&device, &queue, level, 0),
+        ) {
+          Ok(ReadbackImage::Rgba8(image)) => image,
+          _ => panic!("expected an RGBA8 readback"),
+        };
+        let (gpu, cpu) = (read(&gpu), read(&cpu));
+        for (a, b) in gpu.as_raw().iter().zip(cpu.as_raw()) {
+          // Filtering hardware rounds differently from the CPU
+          assert!(
+            (*a as i32 - *b as i32).abs() <= 2,
+            "{:?} level {} differs: {} vs {}",
+            kind,
+            level,
+            a,
+            b
+          );
+        }
+      }
+    }
+    // Color is sRGB, Linear and Normal share a format but filter differently
+    assert_eq!(samplers.mipmaps().pipelines.lock().unwrap().len(), 3);
+  }
+}
@@ -0,0 +1,34 @@
+struct VertexOutput {
+  [[builtin(position)]] pos: vec4<f32>;
+  [[location(0)]] tex_coords: vec2<f32>;
+};
+
+// A single triangle covering the whole target, with (0, 0) in the top left corner
+[[stage(vertex)]]
+fn vs_main([[builtin(vertex_index)]] index: u32) -> VertexOutput {
+  let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+  var out: VertexOutput;
+  out.pos = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+  out.tex_coords = uv;
+  return out;
+}
+
+[[group(0), binding(0)]] var t_source: texture_2d<f32>;
+[[group(0), binding(1)]] var s_source: sampler;
+
+// sRGB views decode before filtering and encode when writing, so this averages in linear space
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+  return textureSample(t_source, s_source, in.tex_coords);
+}
+
+// Averaged normals are shorter than unit length, which would darken the lighting at a distance
+[[stage(fragment)]]
+fn fs_normal(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+  let color = textureSample(t_source, s_source, in.tex_coords);
+  let normal = color.xyz * 2.0 - 1.0;
+  if (dot(normal, normal) < 0.000001) {
+    return vec4<f32>(0.5, 0.5, 1.0, color.a);
+  }
+  return vec4<f32>(normalize(normal) * 0.5 + 0.5, color.a);
+}
@@ -1,30 +1,44 @@
-use crate::texture::Texture;
-use anyhow::{Context, Result};
-use cgmath::{InnerSpace, Vector2, Vector3};
-use std::{ops::Range, path::Path};
-use tobj::*;
+mod atlas;
+mod cache;
+mod geometry;
+mod gltf;
+mod obj;
+mod optimize;
+pub mod tangents;
+
+use crate::{
+  bounds::{Aabb, BoundingSphere},
+  texture::{
+    AtlasOptions, CompressedImage, SamplerCache, SamplerOptions, Texture, TextureKind,
+    TextureRegistry,
+  },
+};
+use anyhow::{bail, Context, Result};
+use cgmath::Vector3;
+use std::{
+  ops::Range,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
 use wgpu::util::DeviceExt;
 
 fn v3(v: impl Into<Vector3<f32>>) -> Vector3<f32> {
   v.into()
 }
 
-fn v2(v: impl Into<Vector2<f32>>) -> Vector2<f32> {
-  v.into()
-}
-
 pub trait Vertex {
   fn descriptor<'a>() -> wgpu::VertexBufferLayout<'a>;
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ModelVertex {
   position: [f32; 3],
   tex_coords: [f32; 2],
   normal: [f32; 3],
-  tangent: [f32; 3],
-  bitangent: [f32; 3],
+  /// Tangent in `xyz` and the handedness of the tangent frame in `w`, so that
+  /// `bitangent = w * cross(normal, tangent)`.
+  tangent: [f32; 4],
 }
 
 impl Vertex for ModelVertex {
@@ -56,217 +70,587 @@ impl Vertex for ModelVertex {
         wgpu::VertexAttribute {
           offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
           shader_location: 3,
-          format: wgpu::VertexFormat::Float32x3,
-        },
-        // bitangent
-        wgpu::VertexAttribute {
-          offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-          shader_location: 4,
-          format: wgpu::VertexFormat::Float32x3,
+          format: wgpu::VertexFormat::Float32x4,
         },
       ],
     }
   }
 }
 
+/// Scalar material properties, laid out to match the `Material` uniform in `triangle.wgsl`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+  ambient: [f32; 3],
+  shininess: f32,
+  diffuse: [f32; 3],
+  opacity: f32,
+  specular: [f32; 3],
+  /// LOD bias of the diffuse texture's `SamplerOptions`, set by `Material::new`.
+  diffuse_lod_bias: f32,
+  normal_lod_bias: f32,
+  /// 1 if the normal map only stores X and Y, set by `Material::new`.
+  normal_xy: u32,
+  // Uniform structs are padded to a multiple of 16 bytes
+  _padding: [u32; 2],
+}
+
+impl MaterialUniform {
+  pub fn new(
+    ambient: [f32; 3],
+    diffuse: [f32; 3],
+    specular: [f32; 3],
+    shininess: f32,
+    opacity: f32,
+  ) -> Self {
+    Self {
+      ambient,
+      shininess,
+      diffuse,
+      opacity,
+      specular,
+      diffuse_lod_bias: 0.0,
+      normal_lod_bias: 0.0,
+      normal_xy: 0,
+      _padding: [0; 2],
+    }
+  }
+
+  /// Reads `Ka`, `Kd`, `Ks`, `Ns` and `d` from an MTL material.
+  pub fn from_mtl(mat: &tobj::Material) -> Self {
+    Self::new(
+      mat.ambient,
+      mat.diffuse,
+      mat.specular,
+      mat.shininess,
+      mat.dissolve,
+    )
+  }
+}
+
+impl Default for MaterialUniform {
+  /// What the shader used before materials had properties: a little ambient light, the texture's
+  /// colour and a white highlight.
+  fn default() -> Self {
+    Self::new([0.1; 3], [1.0; 3], [1.0; 3], 32.0, 1.0)
+  }
+}
+
+/// How a material's meshes are drawn over what's behind them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+  /// Replacing what's behind them and writing depth.
+  Opaque,
+  /// Alpha blended, after the opaque meshes and back to front.
+  Transparent,
+}
+
+/// A texture as one material samples it. Textures loaded from the same file are shared between
+/// materials, but each material samples them with its own options.
+#[derive(Clone)]
+pub struct MaterialTexture {
+  pub texture: Arc<Texture>,
+  pub sampler: Arc<wgpu::Sampler>,
+  pub sampler_options: SamplerOptions,
+}
+
+impl MaterialTexture {
+  pub fn new(
+    device: &wgpu::Device,
+    texture: Arc<Texture>,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Self {
+    Self {
+      texture,
+      sampler: samplers.get(device, sampler_options),
+      sampler_options: *sampler_options,
+    }
+  }
+}
+
+impl From<Arc<Texture>> for MaterialTexture {
+  /// Samples the texture with the options it was created with.
+  fn from(texture: Arc<Texture>) -> Self {
+    Self {
+      sampler: texture.sampler.clone(),
+      sampler_options: texture.sampler_options,
+      texture,
+    }
+  }
+}
+
 pub struct Material {
-  pub name: String,
-  pub diffuse_texture: Texture,
-  pub normal_texture: Texture,
+  /// Only read through `bind_group`, but holding the textures here keeps them alive and shared
+  /// in the `TextureRegistry`, which only keeps weak references.
+  #[allow(dead_code)]
+  pub diffuse_texture: MaterialTexture,
+  /// Kept alive like `diffuse_texture`.
+  #[allow(dead_code)]
+  pub normal_texture: MaterialTexture,
+  pub uniform: MaterialUniform,
   pub bind_group: wgpu::BindGroup,
 }
 
+impl Material {
+  pub fn new(
+    device: &wgpu::Device,
+    name: &str,
+    diffuse_texture: MaterialTexture,
+    normal_texture: MaterialTexture,
+    mut uniform: MaterialUniform,
+    layout: &wgpu::BindGroupLayout,
+  ) -> Self {
+    uniform.diffuse_lod_bias = diffuse_texture.sampler_options.lod_bias;
+    uniform.normal_lod_bias = normal_texture.sampler_options.lod_bias;
+    uniform.normal_xy = normal_texture.texture.has_two_channels() as u32;
+    let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      label: Some(&format!("{} Material Buffer", name)),
+      contents: bytemuck::cast_slice(&[uniform]),
+      usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+      layout,
+      entries: &[
+        // diffuse texture
+        wgpu::BindGroupEntry {
+          binding: 0,
+          resource: wgpu::BindingResource::TextureView(&diffuse_texture.texture.view),
+        },
+        wgpu::BindGroupEntry {
+          binding: 1,
+          resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+        },
+        // normal map
+        wgpu::BindGroupEntry {
+          binding: 2,
+          resource: wgpu::BindingResource::TextureView(&normal_texture.texture.view),
+        },
+        wgpu::BindGroupEntry {
+          binding: 3,
+          resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+        },
+        // material properties
+        wgpu::BindGroupEntry {
+          binding: 4,
+          resource: uniform_buffer.as_entire_binding(),
+        },
+      ],
+      label: Some(name),
+    });
+
+    Self {
+      diffuse_texture,
+      normal_texture,
+      uniform,
+      bind_group,
+    }
+  }
+
+  /// Materials that aren't fully opaque are alpha blended.
+  pub fn blend_mode(&self) -> BlendMode {
+    if self.uniform.opacity < 1.0 {
+      BlendMode::Transparent
+    } else {
+      BlendMode::Opaque
+    }
+  }
+}
+
 pub struct Mesh {
-  pub name: String,
   pub vertex_buffer: wgpu::Buffer,
   pub index_buffer: wgpu::Buffer,
+  /// `Uint16` for meshes with few enough vertices, halving the size of the index buffer.
+  pub index_format: wgpu::IndexFormat,
   pub num_elements: u32,
   pub material: usize,
+  pub aabb: Aabb,
+  pub bounding_sphere: BoundingSphere,
+}
+
+impl Mesh {
+  pub fn new(
+    device: &wgpu::Device,
+    name: &str,
+    vertices: &[ModelVertex],
+    indices: &[u32],
+    material: usize,
+  ) -> Self {
+    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      label: Some(&format!("{} Vertex Buffer", name)),
+      contents: bytemuck::cast_slice(vertices),
+      usage: wgpu::BufferUsages::VERTEX,
+    });
+    // Every index of a mesh with at most 2^16 vertices fits in 16 bits
+    let indices_u16;
+    let (index_format, index_data): (_, &[u8]) = if vertices.len() <= 1 << 16 {
+      indices_u16 = indices.iter().map(|&i| i as u16).collect::<Vec<_>>();
+      (
+        wgpu::IndexFormat::Uint16,
+        bytemuck::cast_slice(&indices_u16),
+      )
+    } else {
+      (wgpu::IndexFormat::Uint32, bytemuck::cast_slice(indices))
+    };
+    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+      label: Some(&format!("{} Index Buffer", name)),
+      contents: index_data,
+      usage: wgpu::BufferUsages::INDEX,
+    });
+    let positions = vertices
+      .iter()
+      .map(|vertex| vertex.position.into())
+      .collect::<Vec<_>>();
+
+    Self {
+      vertex_buffer,
+      index_buffer,
+      index_format,
+      num_elements: indices.len() as u32,
+      material,
+      aabb: Aabb::from_points(positions.iter().copied()),
+      bounding_sphere: BoundingSphere::from_points(&positions),
+    }
+  }
+}
+
+/// How to generate vertex normals for meshes that don't have any.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NormalMode {
+  /// Angle-weighted average of the normals of the surrounding faces.
+  Smooth,
+  /// Every face uses its own normal, giving a faceted look. The demo's model has normals, so
+  /// only the tests pick this.
+  #[cfg_attr(not(test), allow(dead_code))]
+  Flat,
+}
+
+/// How to handle meshes that don't have texture coordinates. The demo's model has them, so only
+/// the tests pick `Planar` and `Box`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TexCoordMode {
+  /// Leave texture coordinates at zero and don't generate tangents from them.
+  Skip,
+  /// Project the mesh onto the plane of its two largest bounding box axes.
+  #[cfg_attr(not(test), allow(dead_code))]
+  Planar,
+  /// Project each vertex along the dominant axis of its normal.
+  #[cfg_attr(not(test), allow(dead_code))]
+  Box,
+}
+
+/// The axis that points up in the source asset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UpAxis {
+  /// Y-up, the convention used by the renderer and by glTF.
+  Y,
+  /// Z-up, used by Blender, 3ds Max and most CAD tools. Converted by rotating -90 degrees
+  /// around X, so `(x, y, z)` becomes `(x, z, -y)`. The demo's model is Y-up, so only the
+  /// tests pick this.
+  #[cfg_attr(not(test), allow(dead_code))]
+  Z,
+}
+
+/// Conversions applied while importing a model, so that assets from different tools can be
+/// used without preprocessing them externally.
+#[derive(Copy, Clone, Debug)]
+pub struct ModelLoadOptions {
+  /// Split polygons into triangle fans. When disabled, OBJ meshes containing faces other than
+  /// triangles fail to load.
+  pub triangulate: bool,
+  /// Share vertices between OBJ face corners with the same position, normal and texture
+  /// coordinate. When disabled, every face corner gets its own vertex.
+  pub merge_indices: bool,
+  /// Uniform scale applied to all positions, e.g. `0.01` for assets modelled in centimetres.
+  pub scale: f32,
+  pub up_axis: UpAxis,
+  /// Replace `v` with `1 - v`, for assets that put the texture origin at the bottom left.
+  pub flip_v: bool,
+  /// Reverse the winding order of every triangle, for assets with clockwise front faces.
+  pub flip_winding: bool,
+  /// Used for meshes without normals.
+  pub normals: NormalMode,
+  /// Used for meshes without texture coordinates.
+  pub tex_coords: TexCoordMode,
+  /// Reorder triangles and vertices for the GPU's vertex cache, less overdraw and sequential
+  /// vertex fetches. Worth it for large scanned or generated meshes, whose indices are rarely
+  /// in a cache friendly order.
+  pub optimize: bool,
+  /// Sampling for every texture of the model. MTL texture options and glTF samplers override
+  /// the parts they specify.
+  pub sampler: SamplerOptions,
+  /// Pack the textures of all materials into shared atlases, so drawing the model switches
+  /// bind groups less often. Only materials whose meshes don't tile their textures are packed.
+  pub atlas: Option<AtlasOptions>,
+}
+
+impl Default for ModelLoadOptions {
+  fn default() -> Self {
+    Self {
+      triangulate: true,
+      merge_indices: true,
+      scale: 1.0,
+      up_axis: UpAxis::Y,
+      flip_v: false,
+      flip_winding: false,
+      normals: NormalMode::Smooth,
+      tex_coords: TexCoordMode::Skip,
+      optimize: false,
+      sampler: SamplerOptions::default(),
+      atlas: None,
+    }
+  }
 }
 
 pub struct Model {
   pub meshes: Vec<Mesh>,
   pub materials: Vec<Material>,
+  /// Bounds of all meshes, in model space.
+  pub aabb: Aabb,
+  pub bounding_sphere: BoundingSphere,
 }
 
 impl Model {
+  pub fn new(meshes: Vec<Mesh>, materials: Vec<Material>) -> Self {
+    let non_empty = || meshes.iter().filter(|mesh| !mesh.aabb.is_empty());
+    let aabb = non_empty().fold(Aabb::empty(), |aabb, mesh| aabb.union(&mesh.aabb));
+    let bounding_sphere = non_empty()
+      .map(|mesh| mesh.bounding_sphere)
+      .reduce(|a, b| a.union(&b))
+      .unwrap_or_else(|| BoundingSphere::new([0.0; 3], 0.0));
+    Self {
+      meshes,
+      materials,
+      aabb,
+      bounding_sphere,
+    }
+  }
+
+  /// Whether any meshes have to be drawn with `BlendMode::Transparent`.
+  pub fn has_transparent_materials(&self) -> bool {
+    self
+      .materials
+      .iter()
+      .any(|material| material.blend_mode() == BlendMode::Transparent)
+  }
+
+  /// Loads a model, picking the importer based on the file extension.
+  ///
+  /// Supported formats are Wavefront OBJ (`.obj`) and glTF 2.0 (`.gltf`, `.glb`).
+  /// Material maps missing from the asset are replaced with the registry's defaults.
   pub fn load<P: AsRef<Path>>(
     path: P,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout,
+    registry: &TextureRegistry,
+    options: &ModelLoadOptions,
   ) -> Result<Self> {
-    let path = path.as_ref();
-    let (obj_models, obj_materials) = tobj::load_obj(
-      path,
-      &LoadOptions {
-        triangulate: true,
-        single_index: true,
-        ..Default::default()
-      },
-    )?;
-
-    let obj_materials = obj_materials?;
-
-    // We're assuming that the texture files are stored with the obj file
-    let containing_folder = path.parent().context("Directory has no parent")?;
-
-    let mut materials = Vec::with_capacity(obj_materials.len());
-    for mat in obj_materials {
-      let diffuse_texture = Texture::load(
-        containing_folder.join(mat.diffuse_texture),
-        device,
-        queue,
-        false,
-      )?;
-      let normal_texture = Texture::load(
-        containing_folder.join(mat.normal_texture),
-        device,
-        queue,
-        true,
-      )?;
-      materials.push(Material {
-        name: mat.name,
-        bind_group: device.create_bind_group(&wgpu::BindGroupDescriptor {
-          layout,
-          entries: &[
-            // diffuse texture
-            wgpu::BindGroupEntry {
-              binding: 0,
-              resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-            },
-            wgpu::BindGroupEntry {
-              binding: 1,
-              resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-            },
-            // normal map
-            wgpu::BindGroupEntry {
-              binding: 2,
-              resource: wgpu::BindingResource::TextureView(&normal_texture.view),
-            },
-            wgpu::BindGroupEntry {
-              binding: 3,
-              resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
-            },
-          ],
-          label: None,
-        }),
-        diffuse_texture,
-        normal_texture,
-      });
-    }
+    let data = ModelData::import(path, options)?;
+    Self::from_data(&data, device, queue, layout, registry)
+  }
 
-    let mut meshes = Vec::with_capacity(obj_models.len());
-    for model in obj_models {
-      let num_vertices = model.mesh.positions.len() / 3;
-      let mut vertices = Vec::with_capacity(num_vertices);
-      for i in 0..num_vertices {
-        vertices.push(ModelVertex {
-          position: [
-            model.mesh.positions[i * 3],
-            model.mesh.positions[i * 3 + 1],
-            model.mesh.positions[i * 3 + 2],
-          ],
-          tex_coords: [model.mesh.texcoords[i * 2], model.mesh.texcoords[i * 2 + 1]],
-          normal: [
-            model.mesh.normals[i * 3],
-            model.mesh.normals[i * 3 + 1],
-            model.mesh.normals[i * 3 + 2],
-          ],
-          tangent: [0.0; 3],
-          bitangent: [0.0; 3],
-        });
+  /// Like `load`, but goes through a binary cache in `cache_dir`.
+  ///
+  /// The first load imports the model as usual and writes the cache. Later loads map the cache
+  /// into memory and upload it directly, as long as none of the source files or `options`
+  /// changed since it was written.
+  #[allow(clippy::too_many_arguments)]
+  pub fn load_cached<P: AsRef<Path>, C: AsRef<Path>>(
+    path: P,
+    cache_dir: C,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    registry: &TextureRegistry,
+    options: &ModelLoadOptions,
+  ) -> Result<Self> {
+    // Absolute, so the same model gets the same cache from any working directory
+    let path = path
+      .as_ref()
+      .canonicalize()
+      .with_context(|| format!("Failed to find {:?}", path.as_ref()))?;
+    let path = path.as_path();
+    let cache_path = cache::cache_path(cache_dir.as_ref(), path);
+    match cache::load(&cache_path, options, device, queue, layout, registry) {
+      Ok(Some(model)) => {
+        log::info!("Loaded {:?} from cache {:?}", path, cache_path);
+        return Ok(model);
       }
+      Ok(None) => log::info!(
+        "Cache {:?} is missing or stale, importing {:?}",
+        cache_path,
+        path
+      ),
+      Err(e) => log::warn!("Ignoring unreadable cache {:?}: {:?}", cache_path, e),
+    }
 
-      let indices = &model.mesh.indices;
-      let mut triangles_included = (0..vertices.len()).collect::<Vec<_>>();
-
-      for idx in indices.chunks(3) {
-        let v = (
-          vertices[idx[0] as usize],
-          vertices[idx[1] as usize],
-          vertices[idx[2] as usize],
-        );
-        let pos = (v3(v.0.position), v3(v.1.position), v3(v.2.position));
-        let uv = (v2(v.0.tex_coords), v2(v.1.tex_coords), v2(v.2.tex_coords));
-        let dpos = (pos.1 - pos.0, pos.2 - pos.0);
-        let duv = (uv.1 - uv.0, uv.2 - uv.0);
-        let r = 1.0 / (duv.0.x * duv.1.y - duv.0.y * duv.1.x);
-        let tangent = (dpos.0 * duv.1.y - dpos.1 * duv.0.y) * r;
-        let bitangent = (dpos.1 * duv.0.x - dpos.0 * duv.1.x) * r;
-
-        vertices[idx[0] as usize].tangent = (tangent + v3(v.0.tangent)).into();
-        vertices[idx[1] as usize].tangent = (tangent + v3(v.1.tangent)).into();
-        vertices[idx[2] as usize].tangent = (tangent + v3(v.2.tangent)).into();
-        vertices[idx[0] as usize].bitangent = (bitangent + v3(v.0.bitangent)).into();
-        vertices[idx[1] as usize].bitangent = (bitangent + v3(v.1.bitangent)).into();
-        vertices[idx[2] as usize].bitangent = (bitangent + v3(v.2.bitangent)).into();
-
-        triangles_included[idx[0] as usize] += 1;
-        triangles_included[idx[1] as usize] += 1;
-        triangles_included[idx[2] as usize] += 1;
-      }
+    let data = ModelData::import(path, options)?;
+    if let Err(e) = cache::write(&cache_path, &data, options) {
+      log::warn!("Failed to write cache {:?}: {:?}", cache_path, e);
+    }
+    Self::from_data(&data, device, queue, layout, registry)
+  }
 
-      for (i, n) in triangles_included.into_iter().enumerate() {
-        let denom = 1.0 / n as f32;
-        let mut v = &mut vertices[i];
-        v.tangent = (v3(v.tangent) * denom).normalize().into();
-        v.bitangent = (v3(v.bitangent) * denom).normalize().into();
-      }
+  /// Uploads imported model data to the GPU, sharing textures through `registry`. Materials
+  /// without a texture use the registry's defaults.
+  pub fn from_data(
+    data: &ModelData,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    registry: &TextureRegistry,
+  ) -> Result<Self> {
+    let textures = data
+      .textures
+      .iter()
+      .map(|texture| {
+        let create = || match &texture.image {
+          TextureImage::Rgba(image) => Texture::from_raw_rgba(
+            &texture.label,
+            device,
+            queue,
+            image,
+            image.dimensions(),
+            texture.kind,
+            &registry.samplers,
+            &texture.sampler,
+          ),
+          TextureImage::Compressed(image) => Texture::from_compressed(
+            &texture.label,
+            device,
+            queue,
+            image,
+            texture.kind,
+            &registry.samplers,
+            &texture.sampler,
+          ),
+        };
+        match &texture.path {
+          Some(path) => registry.get_or_insert_with(path, texture.kind, create),
+          None => Ok(Arc::new(create()?)),
+        }
+        .map(|shared| MaterialTexture::new(device, shared, &registry.samplers, &texture.sampler))
+      })
+      .collect::<Result<Vec<_>>>()?;
 
-      let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some(&format!("{:?} Vertex Buffer", path)),
-        contents: bytemuck::cast_slice(&vertices),
-        usage: wgpu::BufferUsages::VERTEX,
-      });
-      let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some(&format!("{:?} Index Buffer", path)),
-        contents: bytemuck::cast_slice(&model.mesh.indices),
-        usage: wgpu::BufferUsages::INDEX,
-      });
-
-      meshes.push(Mesh {
-        name: model.name,
-        vertex_buffer,
-        index_buffer,
-        num_elements: model.mesh.indices.len() as u32,
-        material: model.mesh.material_id.unwrap_or(0),
-      });
-    }
+    let defaults = &registry.defaults;
+    let materials = data
+      .materials
+      .iter()
+      .map(|material| {
+        Material::new(
+          device,
+          &material.name,
+          material
+            .diffuse_texture
+            .map_or_else(|| defaults.diffuse.clone().into(), |i| textures[i].clone()),
+          material
+            .normal_texture
+            .map_or_else(|| defaults.normal.clone().into(), |i| textures[i].clone()),
+          material.uniform,
+          layout,
+        )
+      })
+      .collect();
 
-    Ok(Self { meshes, materials })
+    let meshes = data
+      .meshes
+      .iter()
+      .map(|mesh| {
+        Mesh::new(
+          device,
+          &mesh.name,
+          &mesh.vertices,
+          &mesh.indices,
+          mesh.material,
+        )
+      })
+      .collect();
+
+    Ok(Self::new(meshes, materials))
   }
 }
 
-pub trait DrawModel<'a> {
-  fn draw_mesh(
-    &mut self,
-    mesh: &'a Mesh,
-    material: &'a Material,
-    camera_bind_group: &'a wgpu::BindGroup,
-    light_bind_group: &'a wgpu::BindGroup,
-  ) {
-    self.draw_mesh_instanced(mesh, material, camera_bind_group, light_bind_group, 0..1)
+/// A mesh that has been imported but not uploaded yet.
+pub struct MeshData {
+  pub name: String,
+  pub vertices: Vec<ModelVertex>,
+  pub indices: Vec<u32>,
+  pub material: usize,
+}
+
+/// A material that has been imported but not uploaded yet. Textures are indices into
+/// `ModelData::textures`, with `None` standing for the default texture.
+pub struct MaterialData {
+  pub name: String,
+  pub uniform: MaterialUniform,
+  pub diffuse_texture: Option<usize>,
+  pub normal_texture: Option<usize>,
+}
+
+/// The pixels of an imported texture.
+pub enum TextureImage {
+  /// Decoded to RGBA, with the mip chain generated on upload.
+  Rgba(image::RgbaImage),
+  /// A mip chain from a KTX2 or DDS file, uploaded compressed when the device supports it.
+  Compressed(CompressedImage),
+}
+
+impl TextureImage {
+  /// Reads a KTX2 or DDS container as it is, and decodes any other image file to RGBA.
+  pub fn open(path: &Path) -> Result<Self> {
+    if CompressedImage::is_container(path) {
+      return Ok(Self::Compressed(CompressedImage::open(path)?));
+    }
+    let image = image::open(path).with_context(|| format!("Failed to load texture {:?}", path))?;
+    Ok(Self::Rgba(image.to_rgba8()))
   }
-  fn draw_mesh_instanced(
-    &mut self,
-    mesh: &'a Mesh,
-    material: &'a Material,
-    camera_bind_group: &'a wgpu::BindGroup,
-    light_bind_group: &'a wgpu::BindGroup,
-    instances: Range<u32>,
-  );
-  fn draw_model(
-    &mut self,
-    model: &'a Model,
-    camera_bind_group: &'a wgpu::BindGroup,
-    light_bind_group: &'a wgpu::BindGroup,
-  ) {
-    self.draw_model_instanced(model, camera_bind_group, light_bind_group, 0..1)
+}
+
+/// A texture that has been imported but not uploaded yet.
+pub struct TextureData {
+  pub label: String,
+  /// The image file the texture was loaded from, if any. Textures from the same file are
+  /// shared between models through the `TextureRegistry`.
+  pub path: Option<PathBuf>,
+  pub image: TextureImage,
+  pub kind: TextureKind,
+  pub sampler: SamplerOptions,
+}
+
+/// The CPU-side result of importing a model, before anything is uploaded to the GPU.
+pub struct ModelData {
+  pub meshes: Vec<MeshData>,
+  pub materials: Vec<MaterialData>,
+  pub textures: Vec<TextureData>,
+  /// Every file the model was imported from, used to invalidate caches.
+  pub sources: Vec<PathBuf>,
+}
+
+impl ModelData {
+  /// Imports a model, picking the importer based on the file extension.
+  pub fn import<P: AsRef<Path>>(path: P, options: &ModelLoadOptions) -> Result<Self> {
+    let path = path.as_ref();
+    let extension = path
+      .extension()
+      .and_then(|ext| ext.to_str())
+      .map(|ext| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+      Some("obj") => Self::import_obj(path, options),
+      Some("gltf") | Some("glb") => Self::import_gltf(path, options),
+      _ => bail!("Unsupported model format: {:?}", path),
+    }
   }
+}
+
+pub trait DrawModel<'a> {
+  /// Draws the meshes whose material has the given blend mode, which needs a pipeline blending
+  /// the same way.
   fn draw_model_instanced(
     &mut self,
     model: &'a Model,
+    blend_mode: BlendMode,
     camera_bind_group: &'a wgpu::BindGroup,
     light_bind_group: &'a wgpu::BindGroup,
     instances: Range<u32>,
@@ -276,37 +660,30 @@ impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
 where
   'b: 'a,
 {
-  fn draw_mesh_instanced(
-    &mut self,
-    mesh: &'b Mesh,
-    material: &'a Material,
-    camera_bind_group: &'b wgpu::BindGroup,
-    light_bind_group: &'a wgpu::BindGroup,
-    instances: Range<u32>,
-  ) {
-    self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-    self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-    self.set_bind_group(0, &material.bind_group, &[]);
-    self.set_bind_group(1, camera_bind_group, &[]);
-    self.set_bind_group(2, light_bind_group, &[]);
-    self.draw_indexed(0..mesh.num_elements, 0, instances);
-  }
-
   fn draw_model_instanced(
     &mut self,
     model: &'a Model,
+    blend_mode: BlendMode,
     camera_bind_group: &'a wgpu::BindGroup,
     light_bind_group: &'a wgpu::BindGroup,
     instances: Range<u32>,
   ) {
-    for mesh in model.meshes.iter() {
-      self.draw_mesh_instanced(
-        mesh,
-        &model.materials[mesh.material],
-        camera_bind_group,
-        light_bind_group,
-        instances.clone(),
-      );
+    self.set_bind_group(1, camera_bind_group, &[]);
+    self.set_bind_group(2, light_bind_group, &[]);
+    // Meshes sharing a material, e.g. through a texture atlas, share its bind group too
+    let mut bound_material = None;
+    let meshes = model
+      .meshes
+      .iter()
+      .filter(|mesh| model.materials[mesh.material].blend_mode() == blend_mode);
+    for mesh in meshes {
+      if bound_material != Some(mesh.material) {
+        self.set_bind_group(0, &model.materials[mesh.material].bind_group, &[]);
+        bound_material = Some(mesh.material);
+      }
+      self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+      self.set_index_buffer(mesh.index_buffer.slice(..), mesh.index_format);
+      self.draw_indexed(0..mesh.num_elements, 0, instances.clone());
     }
   }
 }
@@ -0,0 +1,350 @@
+//! Packing the textures of a model into atlases, so its materials can share one bind group.
+
+use super::{MaterialData, ModelData, TextureData, TextureImage};
+use crate::texture::{AtlasLayout, AtlasOptions, TextureKind};
+use anyhow::Result;
+use std::borrow::Cow;
+
+/// How far texture coordinates may stray outside 0 to 1 before a mesh counts as tiling.
+const UV_TOLERANCE: f32 = 1e-3;
+
+/// The diffuse and normal texture of a material.
+type Slots = (Option<usize>, Option<usize>);
+
+impl ModelData {
+  /// Packs the textures of every material into one diffuse and one normal atlas, remaps the
+  /// texture coordinates of its meshes to match, and merges materials that end up identical.
+  ///
+  /// Materials with compressed textures, meshes that tile their textures or samplers that
+  /// differ from the first atlased texture keep their own textures. Nothing changes if fewer
+  /// than two materials could share the atlases, or they don't fit.
+  pub fn pack_textures(&mut self, options: &AtlasOptions) -> Result<()> {
+    let mut sampler = None;
+    let mut entries = Vec::<Slots>::new();
+    let mut material_entries = vec![None; self.materials.len()];
+    for (index, material) in self.materials.iter().enumerate() {
+      let slots = (material.diffuse_texture, material.normal_texture);
+      let textures = [slots.0, slots.1]
+        .iter()
+        .flatten()
+        .map(|&texture| &self.textures[texture])
+        .collect::<Vec<_>>();
+      let first = match textures.first() {
+        Some(first) => first,
+        None => continue,
+      };
+      let tiles = self
+        .meshes
+        .iter()
+        .filter(|mesh| mesh.material == index)
+        .flat_map(|mesh| &mesh.vertices)
+        .flat_map(|vertex| vertex.tex_coords)
+        .any(|c| !(-UV_TOLERANCE..=1.0 + UV_TOLERANCE).contains(&c));
+      let compressed = textures
+        .iter()
+        .any(|texture| matches!(texture.image, TextureImage::Compressed(_)));
+      if tiles || compressed {
+        log::debug!(
+          "Material {:?} {}, leaving it out of the atlas",
+          material.name,
+          if tiles {
+            "tiles its textures"
+          } else {
+            "has compressed textures"
+          }
+        );
+        continue;
+      }
+      let sampler = *sampler.get_or_insert(first.sampler);
+      if textures.iter().any(|texture| texture.sampler != sampler) {
+        log::debug!(
+          "Material {:?} samples its textures differently, leaving it out of the atlas",
+          material.name
+        );
+        continue;
+      }
+
+      let entry = match entries.iter().position(|&entry| entry == slots) {
+        Some(entry) => entry,
+        None => {
+          entries.push(slots);
+          entries.len() - 1
+        }
+      };
+      material_entries[index] = Some(entry);
+    }
+    let sampler = match sampler.filter(|_| entries.len() >= 2) {
+      Some(sampler) => sampler,
+      None => return Ok(()),
+    };
+
+    let rgba = |texture: usize| match &self.textures[texture].image {
+      TextureImage::Rgba(image) => image,
+      TextureImage::Compressed(_) => unreachable!("compressed textures aren't atlased"),
+    };
+    // Regions take the size of the diffuse texture, and normal maps are scaled to fit
+    let sizes = entries
+      .iter()
+      .map(|&(diffuse, normal)| rgba(diffuse.or(normal).unwrap()).dimensions())
+      .collect::<Vec<_>>();
+    let layout = match AtlasLayout::pack(&sizes, options) {
+      Ok(layout) => layout,
+      Err(e) => {
+        log::warn!("Not packing textures into an atlas: {:?}", e);
+        return Ok(());
+      }
+    };
+    let compose = |slot: fn(&Slots) -> Option<usize>, fill: [u8; 4]| {
+      if entries.iter().all(|entry| slot(entry).is_none()) {
+        return Ok(None);
+      }
+      let images = entries
+        .iter()
+        .zip(&sizes)
+        .map(|(entry, &(width, height))| match slot(entry) {
+          Some(texture) if rgba(texture).dimensions() == (width, height) => {
+            Cow::Borrowed(rgba(texture))
+          }
+          Some(texture) => Cow::Owned(image::imageops::resize(
+            rgba(texture),
+            width,
+            height,
+            image::imageops::FilterType::Triangle,
+          )),
+          None => Cow::Owned(image::RgbaImage::from_pixel(
+            width,
+            height,
+            image::Rgba(fill),
+          )),
+        })
+        .collect::<Vec<_>>();
+      let images = images.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+      layout.compose(&images).map(Some)
+    };
+    // Missing maps are filled with what the default textures hold
+    let diffuse = compose(|slots| slots.0, [255, 255, 255, 255])?;
+    let normal = compose(|slots| slots.1, [128, 128, 255, 255])?;
+
+    let mut push = |label: &str, image: image::RgbaImage, kind: TextureKind| {
+      self.textures.push(TextureData {
+        label: label.to_string(),
+        path: None,
+        image: TextureImage::Rgba(image),
+        kind,
+        sampler,
+      });
+      self.textures.len() - 1
+    };
+    let diffuse = diffuse.map(|image| push("Diffuse Atlas", image, TextureKind::Color));
+    let normal = normal.map(|image| push("Normal Atlas", image, TextureKind::Normal));
+    for (material, entry) in self.materials.iter_mut().zip(&material_entries) {
+      if entry.is_some() {
+        material.diffuse_texture = diffuse;
+        material.normal_texture = normal;
+      }
+    }
+    for mesh in &mut self.meshes {
+      if let Some(&Some(entry)) = material_entries.get(mesh.material) {
+        let region = &layout.regions[entry];
+        for vertex in &mut mesh.vertices {
+          vertex.tex_coords = region.transform(vertex.tex_coords);
+        }
+      }
+    }
+
+    self.remove_unused_textures();
+    self.merge_materials();
+    Ok(())
+  }
+
+  fn remove_unused_textures(&mut self) {
+    let mut used = vec![false; self.textures.len()];
+    for material in &self.materials {
+      for texture in [material.diffuse_texture, material.normal_texture]
+        .into_iter()
+        .flatten()
+      {
+        used[texture] = true;
+      }
+    }
+    let new_indices = used
+      .iter()
+      .scan(0, |next, &used| {
+        let index = *next;
+        *next += used as usize;
+        Some(index)
+      })
+      .collect::<Vec<_>>();
+    let mut used = used.into_iter();
+    self.textures.retain(|_| used.next().unwrap());
+    for material in &mut self.materials {
+      for texture in [&mut material.diffuse_texture, &mut material.normal_texture]
+        .into_iter()
+        .flatten()
+      {
+        *texture = new_indices[*texture];
+      }
+    }
+  }
+
+  /// Merges materials with the same properties and textures, and sorts meshes by material so
+  /// that meshes sharing one are drawn back to back.
+  fn merge_materials(&mut self) {
+    let mut materials = Vec::<MaterialData>::new();
+    let mut new_indices = Vec::with_capacity(self.materials.len());
+    for material in self.materials.drain(..) {
+      let same = |other: &MaterialData| {
+        bytemuck::bytes_of(&other.uniform) == bytemuck::bytes_of(&material.uniform)
+          && other.diffuse_texture == material.diffuse_texture
+          && other.normal_texture == material.normal_texture
+      };
+      match materials.iter().position(same) {
+        Some(index) => new_indices.push(index),
+        None => {
+          new_indices.push(materials.len());
+          materials.push(material);
+        }
+      }
+    }
+    self.materials = materials;
+    for mesh in &mut self.meshes {
+      if let Some(&index) = new_indices.get(mesh.material) {
+        mesh.material = index;
+      }
+    }
+    self.meshes.sort_by_key(|mesh| mesh.material);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    model::{MaterialUniform, MeshData, ModelVertex},
+    texture::{BlockFormat, CompressedImage, SamplerOptions},
+  };
+
+  const TEX_COORDS: [[f32; 2]; 3] = [[0.0, 0.0], [1.0, 0.0], [0.5, 1.0]];
+
+  fn rgba(size: u32, colour: [u8; 4]) -> TextureImage {
+    TextureImage::Rgba(image::RgbaImage::from_pixel(
+      size,
+      size,
+      image::Rgba(colour),
+    ))
+  }
+
+  /// One material and one triangle per texture, with texture coordinates scaled by `uv_scale`.
+  fn model(textures: Vec<(TextureImage, f32)>) -> ModelData {
+    let mut data = ModelData {
+      meshes: Vec::new(),
+      materials: Vec::new(),
+      textures: Vec::new(),
+      sources: Vec::new(),
+    };
+    for (i, (image, uv_scale)) in textures.into_iter().enumerate() {
+      data.textures.push(TextureData {
+        label: format!("texture {}", i),
+        path: None,
+        image,
+        kind: TextureKind::Color,
+        sampler: SamplerOptions::default(),
+      });
+      data.materials.push(MaterialData {
+        name: format!("material {}", i),
+        uniform: MaterialUniform::new([0.1; 3], [i as f32; 3], [0.5; 3], 32.0, 1.0),
+        diffuse_texture: Some(i),
+        normal_texture: None,
+      });
+      data.meshes.push(MeshData {
+        name: format!("mesh {}", i),
+        vertices: TEX_COORDS
+          .iter()
+          .map(|&[u, v]| ModelVertex {
+            position: [u, v, 0.0],
+            tex_coords: [u * uv_scale, v * uv_scale],
+            normal: [0.0, 0.0, 1.0],
+            tangent: [1.0, 0.0, 0.0, 1.0],
+          })
+          .collect(),
+        indices: vec![0, 1, 2],
+        material: i,
+      });
+    }
+    data
+  }
+
+  fn mesh<'a>(data: &'a ModelData, name: &str) -> &'a MeshData {
+    data.meshes.iter().find(|mesh| mesh.name == name).unwrap()
+  }
+
+  fn diffuse<'a>(data: &'a ModelData, mesh: &MeshData) -> &'a TextureData {
+    let material = &data.materials[mesh.material];
+    &data.textures[material.diffuse_texture.unwrap()]
+  }
+
+  #[test]
+  fn small_textures_share_an_atlas() {
+    let red = [255, 0, 0, 255];
+    let green = [0, 255, 0, 255];
+    let mut data = model(vec![(rgba(4, red), 1.0), (rgba(8, green), 1.0)]);
+    data.pack_textures(&AtlasOptions::default()).unwrap();
+
+    assert_eq!(data.textures.len(), 1);
+    assert_eq!(data.textures[0].label, "Diffuse Atlas");
+    assert_eq!(data.materials.len(), 2);
+    for (name, colour) in [("mesh 0", red), ("mesh 1", green)] {
+      let mesh = mesh(&data, name);
+      assert_eq!(diffuse(&data, mesh).label, "Diffuse Atlas");
+      assert_eq!(data.materials[mesh.material].normal_texture, None);
+
+      // The middle of each triangle still samples its own texture
+      let atlas = match &data.textures[0].image {
+        TextureImage::Rgba(image) => image,
+        TextureImage::Compressed(_) => unreachable!(),
+      };
+      let [u, v] = [0, 1].map(|i| mesh.vertices.iter().map(|v| v.tex_coords[i]).sum::<f32>() / 3.0);
+      let pixel = atlas.get_pixel(
+        (u * atlas.width() as f32) as u32,
+        (v * atlas.height() as f32) as u32,
+      );
+      assert_eq!(pixel.0, colour, "{}", name);
+    }
+  }
+
+  #[test]
+  fn tiling_and_compressed_textures_are_left_alone() {
+    let compressed = CompressedImage::new(BlockFormat::Bc1, true, (4, 4), 1, vec![0; 8]).unwrap();
+    let mut data = model(vec![
+      (rgba(4, [255; 4]), 1.0),
+      (rgba(4, [255; 4]), 1.0),
+      (rgba(4, [255; 4]), 2.0),
+      (TextureImage::Compressed(compressed), 1.0),
+    ]);
+    data.pack_textures(&AtlasOptions::default()).unwrap();
+
+    for (name, label, uv_scale) in [("mesh 2", "texture 2", 2.0), ("mesh 3", "texture 3", 1.0)] {
+      let mesh = mesh(&data, name);
+      assert_eq!(diffuse(&data, mesh).label, label);
+      let tex_coords = mesh
+        .vertices
+        .iter()
+        .map(|v| v.tex_coords)
+        .collect::<Vec<_>>();
+      assert_eq!(
+        tex_coords,
+        TEX_COORDS.map(|[u, v]| [u * uv_scale, v * uv_scale])
+      );
+    }
+    assert_eq!(diffuse(&data, mesh(&data, "mesh 0")).label, "Diffuse Atlas");
+    assert_eq!(data.textures.len(), 3);
+  }
+
+  #[test]
+  fn a_single_material_isnt_atlased() {
+    let mut data = model(vec![(rgba(4, [255; 4]), 1.0)]);
+    data.pack_textures(&AtlasOptions::default()).unwrap();
+    assert_eq!(data.textures[0].label, "texture 0");
+    assert_eq!(data.meshes[0].vertices[1].tex_coords, [1.0, 0.0]);
+  }
+}
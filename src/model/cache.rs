@@ -0,0 +1,937 @@
+//! A binary cache of imported models, so that large assets only go through the importers once.
+//!
+//! The file starts with a `Header`, followed by tables of fixed size records for the source
+//! files, textures, materials and meshes. The records point at blobs holding the strings,
+//! pixels, vertices and indices, each aligned to 16 bytes so they can be used straight from the
+//! memory mapped file.
+//!
+//! Source files are only read again to check the cache when their size or modification time
+//! changed since it was written.
+
+use super::{
+  Material, MaterialTexture, MaterialUniform, Mesh, Model, ModelData, ModelLoadOptions,
+  ModelVertex, TextureImage,
+};
+use crate::texture::{
+  compressed::ASTC_BLOCK_SIZES, BlockFormat, CompressedImage, SamplerOptions, Texture, TextureKind,
+  TextureRegistry,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+  fs,
+  mem::{size_of, size_of_val},
+  num::NonZeroU8,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+const MAGIC: [u8; 8] = *b"WGPUMDL\0";
+/// Bump this whenever the layout of the file or the output of the importers changes.
+const VERSION: u32 = 7;
+const BLOB_ALIGNMENT: usize = 16;
+/// Marks a material without a texture, which uses the default texture instead.
+const NO_TEXTURE: u32 = u32::MAX;
+/// How recently a source may have been modified for another change to keep its modification
+/// time, on file systems with coarse timestamps.
+const MODIFIED_RESOLUTION: Duration = Duration::from_secs(2);
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Header {
+  magic: [u8; 8],
+  version: u32,
+  num_sources: u32,
+  num_textures: u32,
+  num_materials: u32,
+  num_meshes: u32,
+  _padding: u32,
+  /// Hash of the cache version and the import options.
+  options_hash: u64,
+}
+
+/// A range of bytes in the file.
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Blob {
+  offset: u64,
+  len: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SourceRecord {
+  path: Blob,
+  stamp: Stamp,
+  /// Hash of the file's contents, checked when its stamp changes.
+  hash: u64,
+}
+
+/// The size and modification time of a file, which change along with its contents.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+struct Stamp {
+  len: u64,
+  /// Nanoseconds since the Unix epoch, or 0 for files modified too recently to trust it.
+  modified: u64,
+}
+
+impl Stamp {
+  fn of(path: &str) -> Option<Self> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?;
+    let settled = SystemTime::now()
+      .duration_since(modified)
+      .is_ok_and(|age| age >= MODIFIED_RESOLUTION);
+    Some(Self {
+      len: metadata.len(),
+      modified: match modified.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) if settled => since_epoch.as_nanos() as u64,
+        _ => 0,
+      },
+    })
+  }
+
+  /// Whether a file with this stamp is known to be unchanged since it had `other`.
+  fn matches(&self, other: &Stamp) -> bool {
+    self.modified != 0 && self == other
+  }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TextureRecord {
+  label: Blob,
+  /// Empty for textures that weren't loaded from their own file.
+  path: Blob,
+  /// RGBA pixels, or every mip level of a compressed texture.
+  pixels: Blob,
+  width: u32,
+  height: u32,
+  /// Index into `TEXTURE_KINDS`.
+  kind: u32,
+  sampler: SamplerRecord,
+  /// `RGBA_FORMAT`, or one past the index of the compressed format in `block_formats`.
+  format: u32,
+  srgb: u32,
+  mip_level_count: u32,
+}
+
+/// `SamplerOptions` with the enums stored as their index.
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SamplerRecord {
+  address_modes: [u32; 3],
+  filters: [u32; 3],
+  /// 0 when anisotropic filtering is off.
+  anisotropy_clamp: u32,
+  lod_bias: f32,
+}
+
+const ADDRESS_MODES: [wgpu::AddressMode; 4] = [
+  wgpu::AddressMode::ClampToEdge,
+  wgpu::AddressMode::Repeat,
+  wgpu::AddressMode::MirrorRepeat,
+  wgpu::AddressMode::ClampToBorder,
+];
+const FILTER_MODES: [wgpu::FilterMode; 2] = [wgpu::FilterMode::Nearest, wgpu::FilterMode::Linear];
+const TEXTURE_KINDS: [TextureKind; 5] = [
+  TextureKind::Color,
+  TextureKind::Linear,
+  TextureKind::Normal,
+  TextureKind::NormalXy,
+  TextureKind::Mask,
+];
+/// Marks a decoded RGBA texture, whose mip chain is generated when it's uploaded.
+const RGBA_FORMAT: u32 = 0;
+
+/// Every `BlockFormat`, so they can be stored as their index.
+fn block_formats() -> impl Iterator<Item = BlockFormat> {
+  [
+    BlockFormat::Bc1,
+    BlockFormat::Bc2,
+    BlockFormat::Bc3,
+    BlockFormat::Bc4 { signed: false },
+    BlockFormat::Bc4 { signed: true },
+    BlockFormat::Bc5 { signed: false },
+    BlockFormat::Bc5 { signed: true },
+    BlockFormat::Bc6h { signed: false },
+    BlockFormat::Bc6h { signed: true },
+    BlockFormat::Bc7,
+    BlockFormat::Etc2Rgb,
+    BlockFormat::Etc2RgbA1,
+    BlockFormat::Etc2Rgba8,
+    BlockFormat::EacR11 { signed: false },
+    BlockFormat::EacR11 { signed: true },
+    BlockFormat::EacRg11 { signed: false },
+    BlockFormat::EacRg11 { signed: true },
+    BlockFormat::Rgba8,
+    BlockFormat::Bgra8,
+  ]
+  .into_iter()
+  .chain(
+    ASTC_BLOCK_SIZES
+      .iter()
+      .map(|&block| BlockFormat::Astc { block }),
+  )
+}
+
+/// The index of `value` in `values`, for storing enums.
+fn index_of<T: PartialEq + std::fmt::Debug>(
+  values: impl IntoIterator<Item = T>,
+  value: T,
+) -> Result<u32> {
+  match values.into_iter().position(|v| v == value) {
+    Some(index) => Ok(index as u32),
+    None => bail!("{:?} can't be stored in the cache", value),
+  }
+}
+
+impl SamplerRecord {
+  fn new(options: &SamplerOptions) -> Result<Self> {
+    Ok(Self {
+      address_modes: [
+        index_of(ADDRESS_MODES, options.address_mode_u)?,
+        index_of(ADDRESS_MODES, options.address_mode_v)?,
+        index_of(ADDRESS_MODES, options.address_mode_w)?,
+      ],
+      filters: [
+        index_of(FILTER_MODES, options.mag_filter)?,
+        index_of(FILTER_MODES, options.min_filter)?,
+        index_of(FILTER_MODES, options.mipmap_filter)?,
+      ],
+      anisotropy_clamp: options
+        .anisotropy_clamp
+        .map_or(0, |clamp| clamp.get() as u32),
+      lod_bias: options.lod_bias,
+    })
+  }
+
+  fn options(&self) -> Result<SamplerOptions> {
+    let address_mode = |i: u32| {
+      ADDRESS_MODES
+        .get(i as usize)
+        .copied()
+        .context("Invalid address mode")
+    };
+    let filter = |i: u32| {
+      FILTER_MODES
+        .get(i as usize)
+        .copied()
+        .context("Invalid filter mode")
+    };
+    Ok(SamplerOptions {
+      address_mode_u: address_mode(self.address_modes[0])?,
+      address_mode_v: address_mode(self.address_modes[1])?,
+      address_mode_w: address_mode(self.address_modes[2])?,
+      mag_filter: filter(self.filters[0])?,
+      min_filter: filter(self.filters[1])?,
+      mipmap_filter: filter(self.filters[2])?,
+      anisotropy_clamp: NonZeroU8::new(self.anisotropy_clamp.min(u8::MAX as u32) as u8),
+      lod_bias: self.lod_bias,
+    })
+  }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialRecord {
+  name: Blob,
+  uniform: MaterialUniform,
+  diffuse_texture: u32,
+  normal_texture: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MeshRecord {
+  name: Blob,
+  vertices: Blob,
+  indices: Blob,
+  material: u32,
+  _padding: u32,
+}
+
+/// The cache file for the model at `source`. The path is hashed into the file name so models
+/// with the same name in different folders don't overwrite each other's cache.
+pub(super) fn cache_path(cache_dir: &Path, source: &Path) -> PathBuf {
+  let stem = source
+    .file_stem()
+    .map_or_else(|| "model".into(), |stem| stem.to_string_lossy());
+  let path_hash = xxh3_64(source.to_string_lossy().as_bytes());
+  cache_dir.join(format!("{}-{:016x}.meshcache", stem, path_hash))
+}
+
+fn options_hash(options: &ModelLoadOptions) -> u64 {
+  let mut hasher = Xxh3::new();
+  hasher.update(&VERSION.to_le_bytes());
+  hasher.update(format!("{:?}", options).as_bytes());
+  hasher.digest()
+}
+
+/// Hashes the contents of a file. Returns `None` if it can't be read anymore.
+fn content_hash(path: &str) -> Option<u64> {
+  fs::read(path).ok().map(|contents| xxh3_64(&contents))
+}
+
+/// Serializes `data` into a cache file at `path`.
+pub(super) fn write(path: &Path, data: &ModelData, options: &ModelLoadOptions) -> Result<()> {
+  // Stored absolute, so the cache can be checked from any working directory
+  let sources = data
+    .sources
+    .iter()
+    .map(|source| {
+      let source = fs::canonicalize(source).context("Source files changed while importing")?;
+      Ok(source.to_string_lossy().into_owned())
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  let tables_len = size_of::<Header>()
+    + sources.len() * size_of::<SourceRecord>()
+    + data.textures.len() * size_of::<TextureRecord>()
+    + data.materials.len() * size_of::<MaterialRecord>()
+    + data.meshes.len() * size_of::<MeshRecord>();
+  let mut blobs = Blobs {
+    base: align(tables_len),
+    bytes: Vec::new(),
+  };
+
+  let source_records = sources
+    .iter()
+    .map(|source| {
+      // Stamped first, so changes while hashing make the stamp stale rather than the hash
+      let stamp = Stamp::of(source).context("Source files changed while importing")?;
+      Ok(SourceRecord {
+        path: blobs.push(source.as_bytes()),
+        stamp,
+        hash: content_hash(source).context("Source files changed while importing")?,
+      })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let texture_records = data
+    .textures
+    .iter()
+    .map(|texture| {
+      let label = blobs.push(texture.label.as_bytes());
+      let path = blobs.push(
+        texture
+          .path
+          .as_ref()
+          .map(|path| path.canonicalize().unwrap_or_else(|_| path.clone()))
+          .map_or_else(String::new, |path| path.to_string_lossy().into_owned())
+          .as_bytes(),
+      );
+      let (pixels, width, height, format, srgb, mip_level_count) = match &texture.image {
+        TextureImage::Rgba(image) => (
+          blobs.push(image),
+          image.width(),
+          image.height(),
+          RGBA_FORMAT,
+          false,
+          1,
+        ),
+        TextureImage::Compressed(image) => (
+          blobs.push(&image.data),
+          image.width,
+          image.height,
+          index_of(block_formats(), image.format)? + 1,
+          image.srgb,
+          image.mip_level_count,
+        ),
+      };
+      Ok(TextureRecord {
+        label,
+        path,
+        pixels,
+        width,
+        height,
+        kind: index_of(TEXTURE_KINDS, texture.kind)?,
+        sampler: SamplerRecord::new(&texture.sampler)?,
+        format,
+        srgb: srgb as u32,
+        mip_level_count,
+      })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let material_records = data
+    .materials
+    .iter()
+    .map(|material| MaterialRecord {
+      name: blobs.push(material.name.as_bytes()),
+      uniform: material.uniform,
+      diffuse_texture: material.diffuse_texture.map_or(NO_TEXTURE, |i| i as u32),
+      normal_texture: material.normal_texture.map_or(NO_TEXTURE, |i| i as u32),
+    })
+    .collect::<Vec<_>>();
+  let mesh_records = data
+    .meshes
+    .iter()
+    .map(|mesh| MeshRecord {
+      name: blobs.push(mesh.name.as_bytes()),
+      vertices: blobs.push(bytemuck::cast_slice(&mesh.vertices)),
+      indices: blobs.push(bytemuck::cast_slice(&mesh.indices)),
+      material: mesh.material as u32,
+      _padding: 0,
+    })
+    .collect::<Vec<_>>();
+
+  let header = Header {
+    magic: MAGIC,
+    version: VERSION,
+    num_sources: sources.len() as u32,
+    num_textures: texture_records.len() as u32,
+    num_materials: material_records.len() as u32,
+    num_meshes: mesh_records.len() as u32,
+    _padding: 0,
+    options_hash: options_hash(options),
+  };
+
+  let mut bytes = Vec::with_capacity(blobs.base + blobs.bytes.len());
+  bytes.extend_from_slice(bytemuck::bytes_of(&header));
+  bytes.extend_from_slice(bytemuck::cast_slice(&source_records));
+  bytes.extend_from_slice(bytemuck::cast_slice(&texture_records));
+  bytes.extend_from_slice(bytemuck::cast_slice(&material_records));
+  bytes.extend_from_slice(bytemuck::cast_slice(&mesh_records));
+  bytes.resize(blobs.base, 0);
+  bytes.extend_from_slice(&blobs.bytes);
+
+  write_file(path, &bytes)
+}
+
+/// Writes to a temporary file first and renames it over `path`, so a crash never leaves a
+/// truncated cache behind and mappings of the old file stay valid.
+fn write_file(path: &Path, bytes: &[u8]) -> Result<()> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+  }
+  let temp_path = path.with_extension("meshcache.tmp");
+  fs::write(&temp_path, bytes).with_context(|| format!("Failed to write {:?}", temp_path))?;
+  fs::rename(&temp_path, path).with_context(|| format!("Failed to rename {:?}", temp_path))?;
+  Ok(())
+}
+
+/// The tables of a cache file that's still up to date.
+struct Contents<'a> {
+  reader: Reader<'a>,
+  textures: &'a [TextureRecord],
+  materials: &'a [MaterialRecord],
+  meshes: &'a [MeshRecord],
+}
+
+/// Reads the tables of the cache file at `path` mapped to `bytes`. Returns `Ok(None)` if it was
+/// written by a different version, with different options or from sources that changed since.
+///
+/// Sources whose stamp changed but whose contents didn't get their stamp updated in the file, so
+/// they aren't hashed again next time.
+fn read<'a>(
+  path: &Path,
+  bytes: &'a [u8],
+  options: &ModelLoadOptions,
+) -> Result<Option<Contents<'a>>> {
+  let reader = Reader { bytes };
+  let header = reader.records::<Header>(0, 1)?[0];
+  if header.magic != MAGIC
+    || header.version != VERSION
+    || header.options_hash != options_hash(options)
+  {
+    return Ok(None);
+  }
+
+  let mut offset = size_of::<Header>();
+  let source_records = reader.records::<SourceRecord>(offset, header.num_sources)?;
+  let sources_offset = offset;
+  offset += size_of_val(source_records);
+  let textures = reader.records::<TextureRecord>(offset, header.num_textures)?;
+  offset += size_of_val(textures);
+  let materials = reader.records::<MaterialRecord>(offset, header.num_materials)?;
+  offset += size_of_val(materials);
+  let meshes = reader.records::<MeshRecord>(offset, header.num_meshes)?;
+
+  let mut updated_sources = source_records.to_vec();
+  let mut updated = false;
+  for record in &mut updated_sources {
+    let source = reader.str(record.path)?;
+    let stamp = match Stamp::of(source) {
+      Some(stamp) => stamp,
+      None => return Ok(None),
+    };
+    if stamp.matches(&record.stamp) {
+      continue;
+    }
+    if content_hash(source) != Some(record.hash) {
+      return Ok(None);
+    }
+    record.stamp = stamp;
+    updated = true;
+  }
+  if updated {
+    let mut bytes = bytes.to_vec();
+    let records: &[u8] = bytemuck::cast_slice(&updated_sources);
+    bytes[sources_offset..sources_offset + records.len()].copy_from_slice(records);
+    if let Err(e) = write_file(path, &bytes) {
+      log::warn!("Failed to update source stamps in {:?}: {:?}", path, e);
+    }
+  }
+
+  Ok(Some(Contents {
+    reader,
+    textures,
+    materials,
+    meshes,
+  }))
+}
+
+/// Loads a model from the cache file at `path`. Returns `Ok(None)` if there is no cache, or if
+/// it was written by a different version or from different sources or options.
+pub(super) fn load(
+  path: &Path,
+  options: &ModelLoadOptions,
+  device: &wgpu::Device,
+  queue: &wgpu::Queue,
+  layout: &wgpu::BindGroupLayout,
+  registry: &TextureRegistry,
+) -> Result<Option<Model>> {
+  let file = match fs::File::open(path) {
+    Ok(file) => file,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e).with_context(|| format!("Failed to open {:?}", path)),
+  };
+  // SAFETY: the cache is only ever replaced by renaming a new file over it, never modified in
+  // place, so the mapping can't change underneath us
+  let mmap =
+    unsafe { memmap2::Mmap::map(&file) }.with_context(|| format!("Failed to map {:?}", path))?;
+  let Contents {
+    reader,
+    textures: texture_records,
+    materials: material_records,
+    meshes: mesh_records,
+  } = match read(path, &mmap, options)? {
+    Some(contents) => contents,
+    None => return Ok(None),
+  };
+
+  let textures = texture_records
+    .iter()
+    .map(|record| {
+      let kind = *TEXTURE_KINDS
+        .get(record.kind as usize)
+        .context("Invalid texture kind")?;
+      let sampler = record.sampler.options()?;
+      let max_mip_level_count = crate::mipmap::mip_level_count(record.width, record.height);
+      if record.width == 0
+        || record.height == 0
+        || !(1..=max_mip_level_count).contains(&record.mip_level_count)
+      {
+        bail!(
+          "Invalid texture size {}x{} with {} mip levels",
+          record.width,
+          record.height,
+          record.mip_level_count
+        );
+      }
+      let create = || {
+        let label = reader.str(record.label)?;
+        let pixels = reader.blob(record.pixels)?;
+        let dimensions = (record.width, record.height);
+        if record.format == RGBA_FORMAT {
+          return Texture::from_raw_rgba(
+            label,
+            device,
+            queue,
+            pixels,
+            dimensions,
+            kind,
+            &registry.samplers,
+            &sampler,
+          );
+        }
+        let format = block_formats()
+          .nth(record.format as usize - 1)
+          .context("Invalid texture format")?;
+        let image = CompressedImage::new(
+          format,
+          record.srgb != 0,
+          dimensions,
+          record.mip_level_count,
+          pixels.to_vec(),
+        )?;
+        Texture::from_compressed(
+          label,
+          device,
+          queue,
+          &image,
+          kind,
+          &registry.samplers,
+          &sampler,
+        )
+      };
+      match reader.str(record.path)? {
+        "" => Ok(Arc::new(create()?)),
+        path => registry.get_or_insert_with(Path::new(path), kind, create),
+      }
+      .map(|shared| MaterialTexture::new(device, shared, &registry.samplers, &sampler))
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let texture = |index: u32, default: &Arc<Texture>| match index {
+    NO_TEXTURE => Ok(MaterialTexture::from(default.clone())),
+    index => textures
+      .get(index as usize)
+      .cloned()
+      .context("Texture index out of range"),
+  };
+
+  let materials = material_records
+    .iter()
+    .map(|record| {
+      Ok(Material::new(
+        device,
+        reader.str(record.name)?,
+        texture(record.diffuse_texture, &registry.defaults.diffuse)?,
+        texture(record.normal_texture, &registry.defaults.normal)?,
+        record.uniform,
+        layout,
+      ))
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  let meshes = mesh_records
+    .iter()
+    .map(|record| {
+      if record.material as usize >= materials.len() {
+        bail!("Material index out of range");
+      }
+      Ok(Mesh::new(
+        device,
+        reader.str(record.name)?,
+        reader.slice::<ModelVertex>(record.vertices)?,
+        reader.slice::<u32>(record.indices)?,
+        record.material as usize,
+      ))
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  Ok(Some(Model::new(meshes, materials)))
+}
+
+fn align(offset: usize) -> usize {
+  offset.div_ceil(BLOB_ALIGNMENT) * BLOB_ALIGNMENT
+}
+
+/// Collects blob contents, handing out their final offsets in the file.
+struct Blobs {
+  base: usize,
+  bytes: Vec<u8>,
+}
+
+impl Blobs {
+  fn push(&mut self, data: &[u8]) -> Blob {
+    self.bytes.resize(align(self.bytes.len()), 0);
+    let blob = Blob {
+      offset: (self.base + self.bytes.len()) as u64,
+      len: data.len() as u64,
+    };
+    self.bytes.extend_from_slice(data);
+    blob
+  }
+}
+
+/// Bounds and alignment checked access to a mapped cache file.
+struct Reader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn range(&self, offset: usize, len: usize) -> Result<&'a [u8]> {
+    offset
+      .checked_add(len)
+      .and_then(|end| self.bytes.get(offset..end))
+      .context("Cache file is truncated")
+  }
+
+  fn records<T: bytemuck::Pod>(&self, offset: usize, count: u32) -> Result<&'a [T]> {
+    let bytes = self.range(offset, count as usize * size_of::<T>())?;
+    bytemuck::try_cast_slice(bytes).map_err(|e| anyhow::anyhow!("Misaligned record: {:?}", e))
+  }
+
+  fn blob(&self, blob: Blob) -> Result<&'a [u8]> {
+    self.range(blob.offset as usize, blob.len as usize)
+  }
+
+  fn slice<T: bytemuck::Pod>(&self, blob: Blob) -> Result<&'a [T]> {
+    bytemuck::try_cast_slice(self.blob(blob)?)
+      .map_err(|e| anyhow::anyhow!("Misaligned blob: {:?}", e))
+  }
+
+  fn str(&self, blob: Blob) -> Result<&'a str> {
+    std::str::from_utf8(self.blob(blob)?).context("Invalid string in cache")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::{MaterialData, MeshData, TextureData};
+
+  /// A directory of its own for each test, as tests run in parallel.
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("model-cache-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  /// Writes a source file with a modification time old enough to be trusted.
+  fn write_source(path: &Path, contents: &[u8], age_secs: u64) {
+    fs::write(path, contents).unwrap();
+    fs::File::options()
+      .write(true)
+      .open(path)
+      .unwrap()
+      .set_modified(SystemTime::now() - Duration::from_secs(age_secs))
+      .unwrap();
+  }
+
+  fn model_data(sources: Vec<PathBuf>) -> ModelData {
+    let vertex = |x: f32| ModelVertex {
+      position: [x, 1.0, 2.0],
+      tex_coords: [0.5, 0.25],
+      normal: [0.0, 0.0, 1.0],
+      tangent: [1.0, 0.0, 0.0, 1.0],
+    };
+    ModelData {
+      meshes: vec![MeshData {
+        name: "mesh".into(),
+        vertices: vec![vertex(0.0), vertex(1.0), vertex(2.0)],
+        indices: vec![0, 1, 2],
+        material: 0,
+      }],
+      materials: vec![MaterialData {
+        name: "material".into(),
+        uniform: MaterialUniform::new([0.1; 3], [0.8; 3], [0.5; 3], 324.0, 0.5),
+        diffuse_texture: Some(0),
+        normal_texture: Some(1),
+      }],
+      textures: vec![
+        TextureData {
+          label: "diffuse".into(),
+          path: Some(sources[1].clone()),
+          image: TextureImage::Rgba(image::RgbaImage::from_fn(3, 2, |x, y| {
+            image::Rgba([x as u8, y as u8, 7, 255])
+          })),
+          kind: TextureKind::Color,
+          sampler: SamplerOptions {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            anisotropy_clamp: NonZeroU8::new(8),
+            lod_bias: -0.5,
+            ..Default::default()
+          },
+        },
+        TextureData {
+          label: "normal".into(),
+          path: None,
+          image: TextureImage::Compressed(
+            CompressedImage::new(
+              BlockFormat::Bc5 { signed: false },
+              false,
+              (4, 4),
+              1,
+              vec![9; 16],
+            )
+            .unwrap(),
+          ),
+          kind: TextureKind::NormalXy,
+          sampler: SamplerOptions::default(),
+        },
+      ],
+      sources,
+    }
+  }
+
+  #[test]
+  fn round_trip_preserves_model_data() {
+    let dir = temp_dir("round-trip");
+    let sources = vec![dir.join("model.obj"), dir.join("diffuse.png")];
+    write_source(&sources[0], b"v 0 0 0", 60);
+    write_source(&sources[1], b"not really a png", 60);
+    let data = model_data(sources);
+    let options = ModelLoadOptions::default();
+    let path = dir.join("model.meshcache");
+    write(&path, &data, &options).unwrap();
+
+    let bytes = fs::read(&path).unwrap();
+    let contents = read(&path, &bytes, &options)
+      .unwrap()
+      .expect("cache is up to date");
+    let reader = &contents.reader;
+
+    assert_eq!(contents.textures.len(), 2);
+    let diffuse = &contents.textures[0];
+    assert_eq!(reader.str(diffuse.label).unwrap(), "diffuse");
+    assert_eq!(
+      Path::new(reader.str(diffuse.path).unwrap()),
+      data.sources[1].canonicalize().unwrap()
+    );
+    match &data.textures[0].image {
+      TextureImage::Rgba(image) => assert_eq!(
+        reader.blob(diffuse.pixels).unwrap(),
+        image.as_raw().as_slice()
+      ),
+      TextureImage::Compressed(_) => unreachable!(),
+    }
+    assert_eq!(
+      (diffuse.width, diffuse.height, diffuse.format),
+      (3, 2, RGBA_FORMAT)
+    );
+    assert_eq!(TEXTURE_KINDS[diffuse.kind as usize], TextureKind::Color);
+    assert_eq!(diffuse.sampler.options().unwrap(), data.textures[0].sampler);
+    let normal = &contents.textures[1];
+    assert_eq!(reader.str(normal.path).unwrap(), "");
+    assert_eq!(
+      block_formats().nth(normal.format as usize - 1),
+      Some(BlockFormat::Bc5 { signed: false })
+    );
+    assert_eq!(reader.blob(normal.pixels).unwrap(), &[9; 16]);
+    assert_eq!(TEXTURE_KINDS[normal.kind as usize], TextureKind::NormalXy);
+
+    let material = &contents.materials[0];
+    assert_eq!(reader.str(material.name).unwrap(), "material");
+    assert_eq!(
+      bytemuck::bytes_of(&material.uniform),
+      bytemuck::bytes_of(&data.materials[0].uniform)
+    );
+    assert_eq!((material.diffuse_texture, material.normal_texture), (0, 1));
+
+    let mesh = &contents.meshes[0];
+    assert_eq!(reader.str(mesh.name).unwrap(), "mesh");
+    assert_eq!(
+      reader.blob(mesh.vertices).unwrap(),
+      bytemuck::cast_slice::<_, u8>(&data.meshes[0].vertices)
+    );
+    assert_eq!(reader.slice::<u32>(mesh.indices).unwrap(), &[0, 1, 2]);
+    assert_eq!(mesh.material, 0);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn changed_options_or_sources_make_the_cache_stale() {
+    let dir = temp_dir("stale");
+    let sources = vec![dir.join("model.obj"), dir.join("diffuse.png")];
+    write_source(&sources[0], b"v 0 0 0", 60);
+    write_source(&sources[1], b"not really a png", 60);
+    let options = ModelLoadOptions::default();
+    let path = dir.join("model.meshcache");
+    write(&path, &model_data(sources.clone()), &options).unwrap();
+
+    let bytes = fs::read(&path).unwrap();
+    let scaled = ModelLoadOptions {
+      scale: 2.0,
+      ..Default::default()
+    };
+    assert!(read(&path, &bytes, &scaled).unwrap().is_none());
+
+    // Same size, so only the modification time gives the change away
+    write_source(&sources[0], b"v 1 0 0", 30);
+    assert!(read(&path, &bytes, &options).unwrap().is_none());
+
+    fs::remove_file(&sources[0]).unwrap();
+    assert!(read(&path, &bytes, &options).unwrap().is_none());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn touched_sources_are_hashed_and_restamped() {
+    let dir = temp_dir("touched");
+    let sources = vec![dir.join("model.obj"), dir.join("diffuse.png")];
+    write_source(&sources[0], b"v 0 0 0", 60);
+    write_source(&sources[1], b"not really a png", 60);
+    let options = ModelLoadOptions::default();
+    let path = dir.join("model.meshcache");
+    write(&path, &model_data(sources.clone()), &options).unwrap();
+
+    write_source(&sources[0], b"v 0 0 0", 30);
+    let bytes = fs::read(&path).unwrap();
+    assert!(read(&path, &bytes, &options).unwrap().is_some());
+
+    let bytes = fs::read(&path).unwrap();
+    let record = Reader { bytes: &bytes }
+      .records::<SourceRecord>(size_of::<Header>(), 2)
+      .unwrap()[0];
+    assert_eq!(
+      record.stamp,
+      Stamp::of(sources[0].to_str().unwrap()).unwrap()
+    );
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn relative_sources_are_stored_absolute() {
+    let dir = temp_dir("relative");
+    // The test's files, reached from the working directory through the root
+    let to_root = std::env::current_dir()
+      .unwrap()
+      .components()
+      .skip(1)
+      .map(|_| "..")
+      .collect::<PathBuf>();
+    let relative = |name: &str| to_root.join(dir.strip_prefix("/").unwrap()).join(name);
+    let sources = vec![relative("model.obj"), relative("diffuse.png")];
+    write_source(&sources[0], b"v 0 0 0", 60);
+    write_source(&sources[1], b"not really a png", 60);
+    let options = ModelLoadOptions::default();
+    let path = dir.join("model.meshcache");
+    write(&path, &model_data(sources), &options).unwrap();
+
+    let bytes = fs::read(&path).unwrap();
+    let reader = Reader { bytes: &bytes };
+    let records = reader
+      .records::<SourceRecord>(size_of::<Header>(), 2)
+      .unwrap();
+    for (record, name) in records.iter().zip(["model.obj", "diffuse.png"]) {
+      let stored = Path::new(reader.str(record.path).unwrap());
+      assert!(stored.is_absolute(), "{:?} is relative", stored);
+      assert_eq!(stored, dir.join(name).canonicalize().unwrap());
+    }
+    let contents = read(&path, &bytes, &options).unwrap().unwrap();
+    let texture_path = Path::new(reader.str(contents.textures[0].path).unwrap());
+    assert!(texture_path.is_absolute(), "{:?} is relative", texture_path);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn recently_modified_sources_are_always_hashed() {
+    let dir = temp_dir("recent");
+    let source = dir.join("model.obj");
+    fs::write(&source, b"v 0 0 0").unwrap();
+    let stamp = Stamp::of(source.to_str().unwrap()).unwrap();
+    assert_eq!(stamp.modified, 0);
+    assert!(!stamp.matches(&stamp));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn foreign_files_are_rejected_without_panicking() {
+    let options = ModelLoadOptions::default();
+    let path = Path::new("foreign.meshcache");
+    assert!(read(path, b"short", &options).is_err());
+    let mut bytes = vec![0u8; 4096];
+    assert!(read(path, &bytes, &options).unwrap().is_none());
+
+    // A valid header with tables pointing past the end of the file
+    let header = Header {
+      magic: MAGIC,
+      version: VERSION,
+      num_sources: 0,
+      num_textures: u32::MAX,
+      num_materials: 0,
+      num_meshes: 0,
+      _padding: 0,
+      options_hash: options_hash(&options),
+    };
+    bytes[..size_of::<Header>()].copy_from_slice(bytemuck::bytes_of(&header));
+    assert!(read(path, &bytes, &options).is_err());
+
+    assert!(index_of(TEXTURE_KINDS, TextureKind::Mask).is_ok());
+    assert!(index_of(TEXTURE_KINDS[..2].iter(), &TextureKind::Mask).is_err());
+  }
+}
@@ -0,0 +1,371 @@
+use super::{tangents, v3, ModelLoadOptions, ModelVertex, NormalMode, TexCoordMode, UpAxis};
+use crate::bounds::Aabb;
+use cgmath::{InnerSpace, Point3, Vector3};
+use std::collections::HashMap;
+
+/// Applies the scale, axis conversion, texture coordinate flip and winding flip from `options`.
+pub(super) fn apply_conversions(
+  vertices: &mut [ModelVertex],
+  indices: &mut [u32],
+  options: &ModelLoadOptions,
+) {
+  let convert_axis = |[x, y, z]: [f32; 3]| match options.up_axis {
+    UpAxis::Y => [x, y, z],
+    UpAxis::Z => [x, z, -y],
+  };
+
+  for vertex in vertices {
+    vertex.position = convert_axis(vertex.position).map(|c| c * options.scale);
+    vertex.normal = convert_axis(vertex.normal);
+    let [x, y, z, mut w] = vertex.tangent;
+    if options.flip_v {
+      vertex.tex_coords[1] = 1.0 - vertex.tex_coords[1];
+      // Flipping v mirrors the texture, so the bitangent points the other way
+      w = -w;
+    }
+    let [x, y, z] = convert_axis([x, y, z]);
+    vertex.tangent = [x, y, z, w];
+  }
+
+  if options.flip_winding {
+    for triangle in indices.chunks_exact_mut(3) {
+      triangle.swap(1, 2);
+    }
+  }
+}
+
+/// Generates whichever of normals, texture coordinates and tangents the source asset didn't
+/// provide, according to `options`.
+///
+/// Generating flat normals gives every triangle its own vertices, so `indices` may be rewritten.
+pub(super) fn fill_missing_attributes(
+  name: &str,
+  vertices: &mut Vec<ModelVertex>,
+  indices: &mut Vec<u32>,
+  has_normals: bool,
+  has_tex_coords: bool,
+  options: &ModelLoadOptions,
+) {
+  if !has_normals {
+    log::info!("Generating {:?} normals for {}", options.normals, name);
+    match options.normals {
+      NormalMode::Smooth => smooth_normals(vertices, indices),
+      NormalMode::Flat => flat_normals(vertices, indices),
+    }
+  }
+
+  if !has_tex_coords {
+    log::info!(
+      "{} has no texture coordinates, using {:?}",
+      name,
+      options.tex_coords
+    );
+    match options.tex_coords {
+      TexCoordMode::Skip => {
+        // Without texture coordinates there is nothing to derive a tangent space from,
+        // any basis around the normal will do since normal maps can't be sampled anyway
+        arbitrary_tangents(vertices);
+        return;
+      }
+      TexCoordMode::Planar => planar_tex_coords(vertices),
+      TexCoordMode::Box => box_tex_coords(vertices),
+    }
+  }
+
+  tangents::generate_for_vertices(vertices, indices);
+}
+
+/// Angle-weighted vertex normals. Vertices at the same position share a normal, so seams in the
+/// texture coordinates don't show up as seams in the shading.
+fn smooth_normals(vertices: &mut [ModelVertex], indices: &[u32]) {
+  let key = |v: &ModelVertex| v.position.map(f32::to_bits);
+  let mut normals = HashMap::<[u32; 3], Vector3<f32>>::new();
+
+  for idx in indices.chunks_exact(3) {
+    let pos = [0, 1, 2].map(|i| v3(vertices[idx[i] as usize].position));
+    let face_normal = (pos[1] - pos[0]).cross(pos[2] - pos[0]);
+    if face_normal.magnitude2() == 0.0 {
+      continue;
+    }
+    let face_normal = face_normal.normalize();
+    for i in 0..3 {
+      let a = pos[(i + 1) % 3] - pos[i];
+      let b = pos[(i + 2) % 3] - pos[i];
+      if a.magnitude2() == 0.0 || b.magnitude2() == 0.0 {
+        continue;
+      }
+      let angle = a.angle(b).0;
+      *normals
+        .entry(key(&vertices[idx[i] as usize]))
+        .or_insert_with(|| Vector3::new(0.0, 0.0, 0.0)) += face_normal * angle;
+    }
+  }
+
+  for vertex in vertices {
+    vertex.normal = match normals.get(&key(vertex)) {
+      Some(normal) if normal.magnitude2() > 0.0 => normal.normalize().into(),
+      _ => [0.0, 1.0, 0.0],
+    };
+  }
+}
+
+/// Gives each triangle its own three vertices, all facing along the triangle's normal.
+fn flat_normals(vertices: &mut Vec<ModelVertex>, indices: &mut Vec<u32>) {
+  let mut flat = Vec::with_capacity(indices.len());
+  for idx in indices.chunks_exact(3) {
+    let mut triangle = [0, 1, 2].map(|i| vertices[idx[i] as usize]);
+    let pos = triangle.map(|v| v3(v.position));
+    let face_normal = (pos[1] - pos[0]).cross(pos[2] - pos[0]);
+    let normal = if face_normal.magnitude2() > 0.0 {
+      face_normal.normalize().into()
+    } else {
+      [0.0, 1.0, 0.0]
+    };
+    for vertex in &mut triangle {
+      vertex.normal = normal;
+    }
+    flat.extend_from_slice(&triangle);
+  }
+
+  *indices = (0..flat.len() as u32).collect();
+  *vertices = flat;
+}
+
+/// Projects the mesh onto the plane spanned by the two largest axes of its bounding box,
+/// stretching the texture once across it.
+fn planar_tex_coords(vertices: &mut [ModelVertex]) {
+  let aabb = Aabb::from_points(vertices.iter().map(|vertex| vertex.position.into()));
+  let (min, extent) = (aabb.min, aabb.extent());
+  let mut axes = [0, 1, 2];
+  axes.sort_by(|&a, &b| extent[b].total_cmp(&extent[a]));
+  let (u, v) = (axes[0], axes[1]);
+
+  for vertex in vertices {
+    vertex.tex_coords = [
+      (vertex.position[u] - min[u]) / extent[u].max(f32::EPSILON),
+      1.0 - (vertex.position[v] - min[v]) / extent[v].max(f32::EPSILON),
+    ];
+  }
+}
+
+/// Projects each vertex along the dominant axis of its normal, with the texture spanning the
+/// largest side of the bounding box once.
+fn box_tex_coords(vertices: &mut [ModelVertex]) {
+  let aabb = Aabb::from_points(vertices.iter().map(|vertex| vertex.position.into()));
+  let (min, extent) = (aabb.min, aabb.extent());
+  let size = extent.x.max(extent.y).max(extent.z).max(f32::EPSILON);
+
+  for vertex in vertices {
+    let p = (Point3::from(vertex.position) - min) / size;
+    let n = vertex.normal.map(f32::abs);
+    vertex.tex_coords = if n[0] >= n[1] && n[0] >= n[2] {
+      [p.z, 1.0 - p.y]
+    } else if n[1] >= n[2] {
+      [p.x, 1.0 - p.z]
+    } else {
+      [p.x, 1.0 - p.y]
+    };
+  }
+}
+
+/// Picks any tangent perpendicular to each vertex normal.
+fn arbitrary_tangents(vertices: &mut [ModelVertex]) {
+  for vertex in vertices {
+    let normal = v3(vertex.normal);
+    let helper = if normal.x.abs() < 0.9 {
+      Vector3::unit_x()
+    } else {
+      Vector3::unit_y()
+    };
+    let tangent = normal.cross(helper).cross(normal).normalize();
+    vertex.tangent = [tangent.x, tangent.y, tangent.z, 1.0];
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn vertex(position: [f32; 3]) -> ModelVertex {
+    ModelVertex {
+      position,
+      tex_coords: [0.0; 2],
+      normal: [0.0; 3],
+      tangent: [0.0; 4],
+    }
+  }
+
+  fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+    for i in 0..3 {
+      assert!(
+        (actual[i] - expected[i]).abs() < 1e-5,
+        "expected {:?}, got {:?}",
+        expected,
+        actual
+      );
+    }
+  }
+
+  /// Fills in normals, and texture coordinates too unless `tex_coords` is `None`.
+  fn fill(
+    vertices: &mut Vec<ModelVertex>,
+    indices: &mut Vec<u32>,
+    normals: NormalMode,
+    tex_coords: Option<TexCoordMode>,
+  ) {
+    let options = ModelLoadOptions {
+      normals,
+      tex_coords: tex_coords.unwrap_or(TexCoordMode::Skip),
+      ..Default::default()
+    };
+    fill_missing_attributes(
+      "test",
+      vertices,
+      indices,
+      false,
+      tex_coords.is_none(),
+      &options,
+    );
+  }
+
+  #[test]
+  fn flat_normals_face_along_the_triangle() {
+    let mut vertices = vec![
+      vertex([0.0, 0.0, 0.0]),
+      vertex([1.0, 0.0, 0.0]),
+      vertex([0.0, 1.0, 0.0]),
+      vertex([0.0, 0.0, -1.0]),
+    ];
+    let mut indices = vec![0, 1, 2, 0, 1, 3];
+    fill(&mut vertices, &mut indices, NormalMode::Flat, None);
+
+    // The shared corners are split, so each triangle keeps its own normal
+    assert_eq!(indices, (0..6).collect::<Vec<_>>());
+    assert_eq!(vertices.len(), 6);
+    for vertex in &vertices[..3] {
+      assert_close(vertex.normal, [0.0, 0.0, 1.0]);
+    }
+    for vertex in &vertices[3..] {
+      assert_close(vertex.normal, [0.0, 1.0, 0.0]);
+    }
+  }
+
+  #[test]
+  fn smooth_normals_are_shared_at_a_position() {
+    // A wall facing +z and a floor facing +y, meeting at right angles along the x axis. Every
+    // triangle has its own vertices, as if the texture coordinates had a seam there.
+    let mut vertices = vec![
+      vertex([0.0, 0.0, 0.0]),
+      vertex([1.0, 0.0, 0.0]),
+      vertex([0.0, 1.0, 0.0]),
+      vertex([0.0, 0.0, 0.0]),
+      vertex([1.0, 0.0, 0.0]),
+      vertex([0.0, 0.0, -1.0]),
+    ];
+    let mut indices = vec![0, 1, 2, 3, 4, 5];
+    fill(&mut vertices, &mut indices, NormalMode::Smooth, None);
+
+    assert_eq!(vertices.len(), 6);
+    // Both triangles have the same angle at the shared corners, so they count equally
+    let shared = Vector3::new(0.0, 1.0, 1.0).normalize().into();
+    for i in [0, 1, 3, 4] {
+      assert_close(vertices[i].normal, shared);
+    }
+    assert_close(vertices[2].normal, [0.0, 0.0, 1.0]);
+    assert_close(vertices[5].normal, [0.0, 1.0, 0.0]);
+  }
+
+  #[test]
+  fn z_up_is_converted_to_y_up() {
+    let mut vertices = vec![ModelVertex {
+      position: [1.0, 2.0, 3.0],
+      tex_coords: [0.5, 0.25],
+      normal: [0.0, 0.0, 1.0],
+      tangent: [0.0, 1.0, 0.0, 1.0],
+    }];
+    let options = ModelLoadOptions {
+      up_axis: UpAxis::Z,
+      scale: 2.0,
+      ..Default::default()
+    };
+    apply_conversions(&mut vertices, &mut [], &options);
+
+    let vertex = vertices[0];
+    assert_close(vertex.position, [2.0, 6.0, -4.0]);
+    assert_close(vertex.normal, [0.0, 1.0, 0.0]);
+    assert_eq!(vertex.tangent, [0.0, 0.0, -1.0, 1.0]);
+    assert_eq!(vertex.tex_coords, [0.5, 0.25]);
+  }
+
+  #[test]
+  fn flipping_v_and_winding() {
+    let mut vertices = vec![ModelVertex {
+      tex_coords: [0.5, 0.25],
+      tangent: [1.0, 0.0, 0.0, 1.0],
+      ..vertex([0.0; 3])
+    }];
+    let mut indices = [0, 1, 2, 3, 4, 5];
+    let options = ModelLoadOptions {
+      flip_v: true,
+      flip_winding: true,
+      ..Default::default()
+    };
+    apply_conversions(&mut vertices, &mut indices, &options);
+
+    assert_eq!(vertices[0].tex_coords, [0.5, 0.75]);
+    assert_eq!(vertices[0].tangent, [1.0, 0.0, 0.0, -1.0]);
+    assert_eq!(indices, [0, 2, 1, 3, 5, 4]);
+  }
+
+  #[test]
+  fn planar_tex_coords_span_the_two_largest_axes() {
+    // 4 wide along x, 2 deep along z and flat in y
+    let mut vertices = vec![
+      vertex([-2.0, 0.0, 0.0]),
+      vertex([2.0, 0.0, 0.0]),
+      vertex([2.0, 0.0, -2.0]),
+      vertex([-2.0, 0.0, -2.0]),
+    ];
+    let mut indices = vec![0, 1, 2, 0, 2, 3];
+    fill(
+      &mut vertices,
+      &mut indices,
+      NormalMode::Smooth,
+      Some(TexCoordMode::Planar),
+    );
+
+    let tex_coords = vertices.iter().map(|v| v.tex_coords).collect::<Vec<_>>();
+    assert_eq!(tex_coords, [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]]);
+    // Tangents are generated from the new texture coordinates
+    for vertex in &vertices {
+      assert_eq!(vertex.tangent, [1.0, 0.0, 0.0, 1.0]);
+    }
+  }
+
+  #[test]
+  fn box_tex_coords_project_along_the_normal() {
+    let mut vertices = vec![
+      ModelVertex {
+        normal: [1.0, 0.0, 0.0],
+        ..vertex([1.0, 0.5, 0.25])
+      },
+      ModelVertex {
+        normal: [0.0, -1.0, 0.0],
+        ..vertex([0.5, 0.0, 1.0])
+      },
+      ModelVertex {
+        normal: [0.0, 0.0, 1.0],
+        ..vertex([0.0, 1.0, 0.0])
+      },
+    ];
+    let mut indices = vec![0, 1, 2];
+    let options = ModelLoadOptions {
+      tex_coords: TexCoordMode::Box,
+      ..Default::default()
+    };
+    fill_missing_attributes("test", &mut vertices, &mut indices, true, false, &options);
+
+    let tex_coords = vertices.iter().map(|v| v.tex_coords).collect::<Vec<_>>();
+    assert_eq!(tex_coords, [[0.25, 0.5], [0.5, 0.0], [0.0, 0.0]]);
+  }
+}
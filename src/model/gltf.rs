@@ -0,0 +1,404 @@
+use super::{
+  geometry, optimize, MaterialData, MaterialUniform, MeshData, ModelData, ModelLoadOptions,
+  ModelVertex, TextureData, TextureImage,
+};
+use crate::texture::{SamplerOptions, TextureKind};
+use anyhow::{Context, Result};
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, SquareMatrix, Vector3, Vector4};
+use image::{DynamicImage, ImageBuffer};
+use std::{collections::HashMap, path::Path};
+
+/// Decodes glTF textures, once per texture and kind.
+struct Textures<'a> {
+  images: &'a [::gltf::image::Data],
+  /// Folder that external image URIs are relative to.
+  containing_folder: &'a Path,
+  sampler: SamplerOptions,
+  textures: Vec<TextureData>,
+  indices: HashMap<(usize, TextureKind), usize>,
+}
+
+impl Textures<'_> {
+  fn get(
+    &mut self,
+    label: &str,
+    texture: &::gltf::Texture<'_>,
+    kind: TextureKind,
+  ) -> Result<usize> {
+    if let Some(&index) = self.indices.get(&(texture.index(), kind)) {
+      return Ok(index);
+    }
+    let path = match texture.source().source() {
+      ::gltf::image::Source::Uri { uri, .. } if !uri.starts_with("data:") => {
+        Some(self.containing_folder.join(uri))
+      }
+      _ => None,
+    };
+    self.textures.push(TextureData {
+      label: label.to_string(),
+      path,
+      image: TextureImage::Rgba(
+        to_dynamic_image(&self.images[texture.source().index()])?.to_rgba8(),
+      ),
+      kind,
+      sampler: sampler_options(&texture.sampler(), &self.sampler),
+    });
+    let index = self.textures.len() - 1;
+    self.indices.insert((texture.index(), kind), index);
+    Ok(index)
+  }
+}
+
+impl ModelData {
+  /// Imports a glTF 2.0 asset (`.gltf` with external or embedded buffers, or binary `.glb`).
+  ///
+  /// The node hierarchy of the default scene is flattened: every mesh instance becomes its own
+  /// set of meshes with the node's world transform baked into the vertices.
+  pub fn import_gltf<P: AsRef<Path>>(path: P, options: &ModelLoadOptions) -> Result<Self> {
+    let path = path.as_ref();
+    let (document, buffers, images) =
+      ::gltf::import(path).with_context(|| format!("Failed to import {:?}", path))?;
+
+    // External buffers and images are resolved relative to the glTF file
+    let mut sources = vec![path.to_path_buf()];
+    let containing_folder = path.parent().unwrap_or_else(|| Path::new(""));
+    let uris = document
+      .buffers()
+      .filter_map(|buffer| match buffer.source() {
+        ::gltf::buffer::Source::Uri(uri) => Some(uri),
+        ::gltf::buffer::Source::Bin => None,
+      })
+      .chain(document.images().filter_map(|image| match image.source() {
+        ::gltf::image::Source::Uri { uri, .. } => Some(uri),
+        ::gltf::image::Source::View { .. } => None,
+      }));
+    for uri in uris {
+      if !uri.starts_with("data:") {
+        sources.push(containing_folder.join(uri));
+      }
+    }
+
+    let mut textures = Textures {
+      images: &images,
+      containing_folder,
+      sampler: options.sampler,
+      textures: Vec::new(),
+      indices: HashMap::new(),
+    };
+
+    let mut materials = Vec::with_capacity(document.materials().len() + 1);
+    for material in document.materials() {
+      let name = material
+        .name()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:?} material {}", path, material.index().unwrap_or(0)));
+      materials.push(load_material(&name, &material, &mut textures)?);
+    }
+    // Primitives without a material use the glTF default material, created on first use
+    let mut default_material = None;
+
+    let scene = document
+      .default_scene()
+      .or_else(|| document.scenes().next())
+      .context("glTF file contains no scenes")?;
+
+    let mut meshes = Vec::new();
+    let mut stack = scene
+      .nodes()
+      .map(|node| (node, Matrix4::identity()))
+      .collect::<Vec<_>>();
+    while let Some((node, parent_transform)) = stack.pop() {
+      let transform = parent_transform * Matrix4::from(node.transform().matrix());
+      stack.extend(node.children().map(|child| (child, transform)));
+
+      let mesh = match node.mesh() {
+        Some(mesh) => mesh,
+        None => continue,
+      };
+      let mesh_name = mesh
+        .name()
+        .or_else(|| node.name())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:?} mesh {}", path, mesh.index()));
+
+      for primitive in mesh.primitives() {
+        if primitive.mode() != ::gltf::mesh::Mode::Triangles {
+          log::warn!(
+            "Skipping primitive {} of {}: unsupported mode {:?}",
+            primitive.index(),
+            mesh_name,
+            primitive.mode()
+          );
+          continue;
+        }
+
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let (vertices, indices) = load_primitive(&mesh_name, &reader, transform, options)
+          .with_context(|| {
+            format!(
+              "Failed to load primitive {} of {}",
+              primitive.index(),
+              mesh_name
+            )
+          })?;
+
+        let material = match primitive.material().index() {
+          Some(index) => index,
+          None => match default_material {
+            Some(index) => index,
+            None => {
+              materials.push(load_material(
+                "default",
+                &primitive.material(),
+                &mut textures,
+              )?);
+              *default_material.insert(materials.len() - 1)
+            }
+          },
+        };
+        meshes.push(MeshData {
+          name: mesh_name.clone(),
+          vertices,
+          indices,
+          material,
+        });
+      }
+    }
+
+    let mut data = Self {
+      meshes,
+      materials,
+      textures: textures.textures,
+      sources,
+    };
+    if let Some(atlas) = &options.atlas {
+      data.pack_textures(atlas)?;
+    }
+    Ok(data)
+  }
+}
+
+fn load_primitive<'a, 's, F>(
+  name: &str,
+  reader: &::gltf::mesh::Reader<'a, 's, F>,
+  transform: Matrix4<f32>,
+  options: &ModelLoadOptions,
+) -> Result<(Vec<ModelVertex>, Vec<u32>)>
+where
+  F: Clone + Fn(::gltf::Buffer<'a>) -> Option<&'s [u8]>,
+{
+  let normal_transform = Matrix3::from_cols(
+    transform.x.truncate(),
+    transform.y.truncate(),
+    transform.z.truncate(),
+  )
+  .invert()
+  .context("Node transform is not invertible")?
+  .transpose();
+
+  let positions = reader
+    .read_positions()
+    .context("Primitive has no positions")?;
+  let normals = reader
+    .read_normals()
+    .map(|normals| normals.collect::<Vec<_>>());
+  let tex_coords = reader
+    .read_tex_coords(0)
+    .map(|tex_coords| tex_coords.into_f32().collect::<Vec<_>>());
+  // Tangents are only meaningful relative to the normals they were authored for
+  let tangents = reader
+    .read_tangents()
+    .filter(|_| normals.is_some())
+    .map(|tangents| tangents.collect::<Vec<_>>());
+
+  // A mirroring transform flips the winding order of every triangle, and the handedness of
+  // every tangent frame
+  let mirrored = transform.determinant() < 0.0;
+  let handedness = if mirrored { -1.0 } else { 1.0 };
+
+  let mut vertices = positions
+    .enumerate()
+    .map(|(i, position)| {
+      let position = transform * Vector4::new(position[0], position[1], position[2], 1.0);
+      let normal = match &normals {
+        Some(normals) => (normal_transform * Vector3::from(normals[i])).normalize(),
+        None => Vector3::new(0.0, 0.0, 0.0),
+      };
+      let tangent = match &tangents {
+        Some(tangents) => {
+          let [x, y, z, w] = tangents[i];
+          let tangent = (transform * Vector4::new(x, y, z, 0.0))
+            .truncate()
+            .normalize();
+          [tangent.x, tangent.y, tangent.z, w * handedness]
+        }
+        None => [0.0; 4],
+      };
+      ModelVertex {
+        position: position.truncate().into(),
+        tex_coords: tex_coords
+          .as_ref()
+          .map_or([0.0; 2], |tex_coords| tex_coords[i]),
+        normal: normal.into(),
+        tangent,
+      }
+    })
+    .collect::<Vec<_>>();
+
+  let mut indices = match reader.read_indices() {
+    Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+    None => (0..vertices.len() as u32).collect(),
+  };
+  if mirrored {
+    for triangle in indices.chunks_mut(3) {
+      triangle.swap(1, 2);
+    }
+  }
+
+  geometry::apply_conversions(&mut vertices, &mut indices, options);
+
+  if tangents.is_none() {
+    geometry::fill_missing_attributes(
+      name,
+      &mut vertices,
+      &mut indices,
+      normals.is_some(),
+      tex_coords.is_some(),
+      options,
+    );
+  }
+  if options.optimize {
+    optimize::optimize_mesh(name, &mut vertices, &mut indices);
+  }
+
+  Ok((vertices, indices))
+}
+
+fn load_material(
+  name: &str,
+  material: &::gltf::Material<'_>,
+  textures: &mut Textures<'_>,
+) -> Result<MaterialData> {
+  let pbr = material.pbr_metallic_roughness();
+
+  // Textures are optional in glTF, the base colour factor is applied through the material uniform
+  let diffuse_texture = pbr
+    .base_color_texture()
+    .map(|info| {
+      textures.get(
+        &format!("{} diffuse", name),
+        &info.texture(),
+        TextureKind::Color,
+      )
+    })
+    .transpose()?;
+  let normal_texture = material
+    .normal_texture()
+    .map(|normal| {
+      textures.get(
+        &format!("{} normal", name),
+        &normal.texture(),
+        TextureKind::Normal,
+      )
+    })
+    .transpose()?;
+
+  // Approximate the metallic-roughness model with Blinn-Phong parameters: dielectrics reflect
+  // about 4% of the light, and the exponent follows from alpha = roughness^2 (2 / alpha^2 - 2)
+  let [r, g, b, a] = pbr.base_color_factor();
+  // Only blended materials are see-through, the others ignore alpha (or use it as a cutoff)
+  let opacity = match material.alpha_mode() {
+    gltf::material::AlphaMode::Blend => a,
+    _ => 1.0,
+  };
+  let metallic = pbr.metallic_factor();
+  let roughness = pbr.roughness_factor().max(0.01);
+  let specular = [r, g, b].map(|c| 0.04 + (c - 0.04) * metallic);
+  let shininess = (2.0 / roughness.powi(4) - 2.0).clamp(1.0, 1024.0);
+
+  Ok(MaterialData {
+    name: name.to_string(),
+    // glTF has no ambient term, so the materials reflect a little of the light all around
+    uniform: MaterialUniform::new([0.1; 3], [r, g, b], specular, shininess, opacity),
+    diffuse_texture,
+    normal_texture,
+  })
+}
+
+/// Overrides the parts of `base` that the glTF sampler specifies.
+fn sampler_options(
+  sampler: &::gltf::texture::Sampler<'_>,
+  base: &SamplerOptions,
+) -> SamplerOptions {
+  use ::gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+  let address_mode = |mode| match mode {
+    WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+    WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+    WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+  };
+  let mut options = SamplerOptions {
+    address_mode_u: address_mode(sampler.wrap_s()),
+    address_mode_v: address_mode(sampler.wrap_t()),
+    ..*base
+  };
+  if let Some(filter) = sampler.mag_filter() {
+    options.mag_filter = match filter {
+      MagFilter::Nearest => wgpu::FilterMode::Nearest,
+      MagFilter::Linear => wgpu::FilterMode::Linear,
+    };
+  }
+  if let Some(filter) = sampler.min_filter() {
+    // wgpu samplers can't turn mipmapping off, filters without it use the nearest level instead
+    let (min_filter, mipmap_filter) = match filter {
+      MinFilter::Nearest | MinFilter::NearestMipmapNearest => {
+        (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest)
+      }
+      MinFilter::Linear | MinFilter::LinearMipmapNearest => {
+        (wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest)
+      }
+      MinFilter::NearestMipmapLinear => (wgpu::FilterMode::Nearest, wgpu::FilterMode::Linear),
+      MinFilter::LinearMipmapLinear => (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear),
+    };
+    options.min_filter = min_filter;
+    options.mipmap_filter = mipmap_filter;
+  }
+  options
+}
+
+fn to_dynamic_image(data: &::gltf::image::Data) -> Result<DynamicImage> {
+  use ::gltf::image::Format;
+
+  let (width, height) = (data.width, data.height);
+  let pixels8 = || data.pixels.clone();
+  let pixels16 = || {
+    data
+      .pixels
+      .chunks_exact(2)
+      .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+      .collect::<Vec<_>>()
+  };
+  let image = match data.format {
+    Format::R8 => ImageBuffer::from_raw(width, height, pixels8()).map(DynamicImage::ImageLuma8),
+    Format::R8G8 => ImageBuffer::from_raw(width, height, pixels8()).map(DynamicImage::ImageLumaA8),
+    Format::R8G8B8 => ImageBuffer::from_raw(width, height, pixels8()).map(DynamicImage::ImageRgb8),
+    Format::R8G8B8A8 => {
+      ImageBuffer::from_raw(width, height, pixels8()).map(DynamicImage::ImageRgba8)
+    }
+    Format::B8G8R8 => ImageBuffer::from_raw(width, height, pixels8()).map(DynamicImage::ImageBgr8),
+    Format::B8G8R8A8 => {
+      ImageBuffer::from_raw(width, height, pixels8()).map(DynamicImage::ImageBgra8)
+    }
+    Format::R16 => ImageBuffer::from_raw(width, height, pixels16()).map(DynamicImage::ImageLuma16),
+    Format::R16G16 => {
+      ImageBuffer::from_raw(width, height, pixels16()).map(DynamicImage::ImageLumaA16)
+    }
+    Format::R16G16B16 => {
+      ImageBuffer::from_raw(width, height, pixels16()).map(DynamicImage::ImageRgb16)
+    }
+    Format::R16G16B16A16 => {
+      ImageBuffer::from_raw(width, height, pixels16()).map(DynamicImage::ImageRgba16)
+    }
+  };
+  image.context("Image data does not match its dimensions")
+}
@@ -0,0 +1,386 @@
+use super::{
+  geometry, optimize, MaterialData, MaterialUniform, MeshData, ModelData, ModelLoadOptions,
+  ModelVertex, TextureData, TextureImage,
+};
+use crate::texture::{SamplerOptions, TextureKind};
+use anyhow::{bail, Context, Result};
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+};
+use tobj::LoadOptions;
+
+impl ModelData {
+  /// Imports a Wavefront OBJ file along with the MTL materials it references.
+  pub fn import_obj<P: AsRef<Path>>(path: P, options: &ModelLoadOptions) -> Result<Self> {
+    let path = path.as_ref();
+    let (obj_models, obj_materials) = tobj::load_obj(
+      path,
+      &LoadOptions {
+        triangulate: options.triangulate,
+        single_index: options.merge_indices,
+        ..Default::default()
+      },
+    )?;
+
+    let obj_materials = obj_materials?;
+
+    // We're assuming that the texture files are stored with the obj file
+    let containing_folder = path.parent().context("Directory has no parent")?;
+
+    let mut sources = vec![path.to_path_buf()];
+    sources.extend(material_libraries(path)?);
+
+    // Each texture statement is decoded once, even when several materials share it
+    let mut textures = Vec::new();
+    let mut texture_indices = HashMap::<(String, TextureKind), usize>::new();
+    let mut load_texture = |statement: &str, kind: TextureKind| -> Result<usize> {
+      let key = (statement.to_string(), kind);
+      if let Some(&index) = texture_indices.get(&key) {
+        return Ok(index);
+      }
+      let (file, sampler) = parse_texture_statement(statement, &options.sampler)
+        .with_context(|| format!("Invalid texture statement {:?} in {:?}", statement, path))?;
+      let texture_path = containing_folder.join(file);
+      let image = TextureImage::open(&texture_path)?;
+      textures.push(TextureData {
+        label: texture_path.to_string_lossy().into_owned(),
+        path: Some(texture_path.clone()),
+        image,
+        kind,
+        sampler,
+      });
+      sources.push(texture_path);
+      texture_indices.insert(key, textures.len() - 1);
+      Ok(textures.len() - 1)
+    };
+
+    let mut materials = Vec::with_capacity(obj_materials.len());
+    for mat in obj_materials {
+      let diffuse_texture = if mat.diffuse_texture.is_empty() {
+        log::warn!(
+          "Material {:?} in {:?} has no diffuse texture (map_Kd), using the default",
+          mat.name,
+          path
+        );
+        None
+      } else {
+        Some(load_texture(&mat.diffuse_texture, TextureKind::Color)?)
+      };
+      let normal_texture = if mat.normal_texture.is_empty() {
+        log::warn!(
+          "Material {:?} in {:?} has no normal map (map_Bump), using the default",
+          mat.name,
+          path
+        );
+        None
+      } else {
+        Some(load_texture(&mat.normal_texture, TextureKind::Normal)?)
+      };
+      materials.push(MaterialData {
+        uniform: MaterialUniform::from_mtl(&mat),
+        name: mat.name,
+        diffuse_texture,
+        normal_texture,
+      });
+    }
+
+    // Meshes without a usable material, e.g. from an OBJ without `mtllib` or `usemtl`, share a
+    // default one, created on first use
+    let mut default_material = None;
+
+    let mut meshes = Vec::with_capacity(obj_models.len());
+    for model in obj_models {
+      let mesh = model.mesh;
+      let has_normals = !mesh.normals.is_empty();
+      let has_tex_coords = !mesh.texcoords.is_empty();
+
+      if mesh.face_arities.iter().any(|&arity| arity != 3) {
+        bail!(
+          "Mesh {:?} in {:?} has faces that aren't triangles, load it with `triangulate`",
+          model.name,
+          path
+        );
+      }
+
+      let vertex = |position: usize, tex_coords: usize, normal: usize| ModelVertex {
+        position: [
+          mesh.positions[position * 3],
+          mesh.positions[position * 3 + 1],
+          mesh.positions[position * 3 + 2],
+        ],
+        tex_coords: if has_tex_coords {
+          [
+            mesh.texcoords[tex_coords * 2],
+            mesh.texcoords[tex_coords * 2 + 1],
+          ]
+        } else {
+          [0.0; 2]
+        },
+        normal: if has_normals {
+          [
+            mesh.normals[normal * 3],
+            mesh.normals[normal * 3 + 1],
+            mesh.normals[normal * 3 + 2],
+          ]
+        } else {
+          [0.0; 3]
+        },
+        tangent: [0.0; 4],
+      };
+
+      let (mut vertices, mut indices) = if options.merge_indices {
+        // tobj already merged the position, normal and texture coordinate indices
+        let num_vertices = mesh.positions.len() / 3;
+        let vertices = (0..num_vertices)
+          .map(|i| vertex(i, i, i))
+          .collect::<Vec<_>>();
+        (vertices, mesh.indices.clone())
+      } else {
+        // Give every face corner its own vertex
+        let vertices = (0..mesh.indices.len())
+          .map(|i| {
+            vertex(
+              mesh.indices[i] as usize,
+              mesh.texcoord_indices.get(i).copied().unwrap_or(0) as usize,
+              mesh.normal_indices.get(i).copied().unwrap_or(0) as usize,
+            )
+          })
+          .collect::<Vec<_>>();
+        (vertices, (0..mesh.indices.len() as u32).collect())
+      };
+
+      geometry::apply_conversions(&mut vertices, &mut indices, options);
+      geometry::fill_missing_attributes(
+        &model.name,
+        &mut vertices,
+        &mut indices,
+        has_normals,
+        has_tex_coords,
+        options,
+      );
+      if options.optimize {
+        optimize::optimize_mesh(&model.name, &mut vertices, &mut indices);
+      }
+
+      let material = match mesh.material_id.filter(|&id| id < materials.len()) {
+        Some(id) => id,
+        None => {
+          log::warn!(
+            "Mesh {:?} in {:?} has no material, using the default",
+            model.name,
+            path
+          );
+          *default_material.get_or_insert_with(|| {
+            materials.push(MaterialData {
+              name: "default".to_string(),
+              uniform: MaterialUniform::default(),
+              diffuse_texture: None,
+              normal_texture: None,
+            });
+            materials.len() - 1
+          })
+        }
+      };
+      meshes.push(MeshData {
+        name: model.name,
+        vertices,
+        indices,
+        material,
+      });
+    }
+
+    let mut data = Self {
+      meshes,
+      materials,
+      textures,
+      sources,
+    };
+    if let Some(atlas) = &options.atlas {
+      data.pack_textures(atlas)?;
+    }
+    Ok(data)
+  }
+}
+
+/// The MTL files referenced by `mtllib` statements, which tobj doesn't report.
+fn material_libraries(path: &Path) -> Result<Vec<PathBuf>> {
+  let containing_folder = path.parent().context("Directory has no parent")?;
+  let obj = std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+  Ok(
+    obj
+      .lines()
+      .filter_map(|line| line.trim().strip_prefix("mtllib "))
+      .flat_map(str::split_whitespace)
+      .map(|file| containing_folder.join(file))
+      .collect(),
+  )
+}
+
+/// Splits an MTL texture statement like `-clamp on -s 2 2 bricks.png` into the file name and
+/// the sampler options it asks for. Options that don't affect sampling, and options this parser
+/// doesn't know, are skipped.
+fn parse_texture_statement(
+  statement: &str,
+  base: &SamplerOptions,
+) -> Result<(String, SamplerOptions)> {
+  let mut sampler = *base;
+  let mut rest = statement.trim();
+  loop {
+    let (option, after) = split_word(rest);
+    if !option.starts_with('-') {
+      break;
+    }
+    rest = after;
+    match option {
+      "-clamp" => {
+        let (value, after) = split_word(rest);
+        rest = after;
+        sampler = match value {
+          "on" => sampler.with_address_mode(wgpu::AddressMode::ClampToEdge),
+          "off" => sampler.with_address_mode(wgpu::AddressMode::Repeat),
+          value => bail!("Expected on or off after -clamp, got {:?}", value),
+        }
+      }
+      "-blendu" | "-blendv" | "-bm" | "-boost" | "-cc" | "-imfchan" | "-texres" | "-type" => {
+        let (value, after) = split_word(rest);
+        if value.is_empty() {
+          bail!("Missing value for {}", option);
+        }
+        rest = after;
+        log::debug!("Ignoring texture option {}", option);
+      }
+      "-mm" => {
+        for _ in 0..2 {
+          let (value, after) = split_word(rest);
+          if value.is_empty() {
+            bail!("Expected two values after -mm");
+          }
+          rest = after;
+        }
+        log::debug!("Ignoring texture option {}", option);
+      }
+      // Offset, scale and turbulence take one to three numbers
+      "-o" | "-s" | "-t" => {
+        rest = skip_numbers(rest, 3);
+        log::warn!("Ignoring unsupported texture option {}", option);
+      }
+      // Exporters add options of their own, which are assumed to only take numbers
+      _ => {
+        rest = skip_numbers(rest, usize::MAX);
+        log::warn!("Ignoring unknown texture option {}", option);
+      }
+    }
+  }
+
+  // The file name is whatever is left, spaces included
+  if rest.is_empty() {
+    bail!("Missing file name");
+  }
+  Ok((rest.to_string(), sampler))
+}
+
+/// Splits the first word off `text`, returning it and what follows it.
+fn split_word(text: &str) -> (&str, &str) {
+  let end = text.find(char::is_whitespace).unwrap_or(text.len());
+  (&text[..end], text[end..].trim_start())
+}
+
+/// Skips up to `max` words that are numbers.
+fn skip_numbers(mut text: &str, max: usize) -> &str {
+  for _ in 0..max {
+    match split_word(text) {
+      (word, after) if word.parse::<f32>().is_ok() => text = after,
+      _ => break,
+    }
+  }
+  text
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Writes `contents` to a file in a directory of its own, as tests run in parallel.
+  fn write_obj(name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("obj-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("model.obj");
+    std::fs::write(&path, contents).unwrap();
+    path
+  }
+
+  const TRIANGLES: &str = "\
+v 0 0 0
+v 1 0 0
+v 0 1 0
+vn 0 0 1
+vt 0 0
+o first
+f 1/1/1 2/1/1 3/1/1
+o second
+f 3/1/1 2/1/1 1/1/1
+";
+
+  #[test]
+  fn objs_without_materials_share_a_default_one() {
+    let path = write_obj("no-mtl", TRIANGLES);
+    let data = ModelData::import_obj(&path, &ModelLoadOptions::default()).unwrap();
+    assert_eq!(data.materials.len(), 1);
+    assert!(data.textures.is_empty());
+    let material = &data.materials[0];
+    assert_eq!(
+      (material.diffuse_texture, material.normal_texture),
+      (None, None)
+    );
+    assert_eq!(data.meshes.len(), 2);
+    assert!(data.meshes.iter().all(|mesh| mesh.material == 0));
+  }
+
+  fn parse(statement: &str) -> Result<(String, SamplerOptions)> {
+    parse_texture_statement(statement, &SamplerOptions::default())
+  }
+
+  #[test]
+  fn clamp_sets_the_address_mode() {
+    let (file, sampler) = parse("-clamp on bricks.png").unwrap();
+    assert_eq!(file, "bricks.png");
+    assert_eq!(sampler.address_mode_u, wgpu::AddressMode::ClampToEdge);
+    assert_eq!(sampler.address_mode_v, wgpu::AddressMode::ClampToEdge);
+
+    let base = SamplerOptions::default().with_address_mode(wgpu::AddressMode::ClampToEdge);
+    let (_, sampler) = parse_texture_statement("-clamp off bricks.png", &base).unwrap();
+    assert_eq!(sampler.address_mode_u, wgpu::AddressMode::Repeat);
+  }
+
+  #[test]
+  fn options_that_dont_affect_sampling_are_skipped() {
+    let statement = "-mm 0.1 0.9 -blendu off -s 2 2 -o 0.5 bricks.png";
+    assert_eq!(
+      parse(statement).unwrap(),
+      ("bricks.png".to_string(), SamplerOptions::default())
+    );
+  }
+
+  #[test]
+  fn unknown_options_are_skipped_with_their_numbers() {
+    assert_eq!(parse("-vendor 1 2.5 bricks.png").unwrap().0, "bricks.png");
+    assert_eq!(parse("-flag bricks.png").unwrap().0, "bricks.png");
+  }
+
+  #[test]
+  fn missing_values_are_errors() {
+    assert!(parse("-clamp").is_err());
+    assert!(parse("-clamp bricks.png").is_err());
+    assert!(parse("-mm 0.5").is_err());
+    assert!(parse("-blendu").is_err());
+    assert!(parse("-clamp on").is_err());
+  }
+
+  #[test]
+  fn file_names_keep_their_spaces() {
+    let (file, _) = parse("-clamp on  old  red bricks.png ").unwrap();
+    assert_eq!(file, "old  red bricks.png");
+  }
+}
@@ -0,0 +1,552 @@
+//! Tangent space generation ported from the MikkTSpace reference implementation
+//! (`genTangSpaceDefault`), which is what Blender, Substance and the glTF spec bake normal maps
+//! against.
+//!
+//! Per triangle, the tangent is the direction of increasing `u`. Vertices are identified by their
+//! position, normal and texture coordinate rather than by index. The corners around a vertex are
+//! grouped by walking from triangle to triangle across shared edges, and only triangles whose
+//! texture mapping has the same orientation join a group. A group's tangent is the average of its
+//! triangles' tangents, projected onto the plane of the vertex normal and weighted by the angle
+//! of each triangle's corner, and carries the handedness of the tangent frame in `w`, so that
+//! `bitangent = w * cross(normal, tangent)`.
+//!
+//! Where every tangent in a group is parallel to the normal, MikkTSpace outputs a zero tangent;
+//! this picks an arbitrary perpendicular one instead so the shader has something to normalize.
+
+use super::ModelVertex;
+use cgmath::{InnerSpace, Vector2, Vector3};
+use std::collections::HashMap;
+
+/// What MikkTSpace outputs for corners it couldn't group.
+const DEFAULT_TANGENT: [f32; 4] = [1.0, 0.0, 0.0, -1.0];
+
+fn not_zero(x: f32) -> bool {
+  x.abs() > f32::MIN_POSITIVE
+}
+
+fn not_zero_vector(v: Vector3<f32>) -> bool {
+  not_zero(v.x) || not_zero(v.y) || not_zero(v.z)
+}
+
+fn project(v: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+  let v = v - normal * normal.dot(v);
+  if not_zero_vector(v) {
+    v.normalize()
+  } else {
+    v
+  }
+}
+
+/// Any unit vector perpendicular to `normal`.
+fn fallback_tangent(normal: Vector3<f32>) -> Vector3<f32> {
+  let helper = if normal.x.abs() < 0.9 {
+    Vector3::unit_x()
+  } else {
+    Vector3::unit_y()
+  };
+  let tangent = project(helper, normal);
+  if not_zero(tangent.magnitude2()) {
+    tangent
+  } else {
+    Vector3::unit_x()
+  }
+}
+
+struct Triangle {
+  /// Index of the triangle in the mesh.
+  index: usize,
+  /// Normalized tangent and bitangent, each zero when it can't be derived.
+  tangent: Vector3<f32>,
+  bitangent: Vector3<f32>,
+  /// Whether the texture mapping keeps the winding of the triangle.
+  orientation_preserving: bool,
+  /// Triangles with degenerate texture coordinates take the orientation of the first group that
+  /// reaches them, and don't contribute to the tangent of any group.
+  group_with_any: bool,
+  /// The triangle across the edge from each corner to the next.
+  neighbours: [Option<usize>; 3],
+  /// The group each corner ended up in.
+  groups: [Option<usize>; 3],
+}
+
+struct Group {
+  /// The welded vertex the group is around.
+  vertex: usize,
+  orientation_preserving: bool,
+  triangles: Vec<usize>,
+}
+
+/// Generates a tangent for every corner listed in `indices` (three per triangle).
+///
+/// The returned vector has one entry per index, since a vertex shared by triangles that end up in
+/// different groups gets a different tangent for each.
+pub fn generate(
+  positions: &[[f32; 3]],
+  normals: &[[f32; 3]],
+  tex_coords: &[[f32; 2]],
+  indices: &[u32],
+) -> Vec<[f32; 4]> {
+  let position = |corner: usize| Vector3::from(positions[indices[corner] as usize]);
+  let normal = |corner: usize| Vector3::from(normals[indices[corner] as usize]);
+  let tex_coord = |corner: usize| Vector2::from(tex_coords[indices[corner] as usize]);
+
+  // Weld vertices with identical attributes
+  let mut ids = HashMap::new();
+  let welded = indices
+    .iter()
+    .map(|&index| {
+      let index = index as usize;
+      let mut key = [0u32; 8];
+      for i in 0..3 {
+        key[i] = positions[index][i].to_bits();
+        key[i + 3] = normals[index][i].to_bits();
+      }
+      key[6] = tex_coords[index][0].to_bits();
+      key[7] = tex_coords[index][1].to_bits();
+      let next = ids.len();
+      *ids.entry(key).or_insert(next)
+    })
+    .collect::<Vec<_>>();
+
+  // Triangles with two corners in the same place are left out, and copy their tangents from the
+  // others at the end
+  let degenerate = |t: usize| {
+    let c = t * 3;
+    position(c) == position(c + 1)
+      || position(c + 1) == position(c + 2)
+      || position(c) == position(c + 2)
+  };
+  let mut triangles = (0..indices.len() / 3)
+    .filter(|&t| !degenerate(t))
+    .map(|t| {
+      let c = t * 3;
+      let d1 = position(c + 1) - position(c);
+      let d2 = position(c + 2) - position(c);
+      let t21 = tex_coord(c + 1) - tex_coord(c);
+      let t31 = tex_coord(c + 2) - tex_coord(c);
+      let signed_area = t21.x * t31.y - t21.y * t31.x;
+      let orientation_preserving = signed_area > 0.0;
+
+      let os = d1 * t31.y - d2 * t21.y;
+      let ot = d2 * t21.x - d1 * t31.x;
+      let mut tangent = Vector3::new(0.0, 0.0, 0.0);
+      let mut bitangent = Vector3::new(0.0, 0.0, 0.0);
+      let mut group_with_any = true;
+      if not_zero(signed_area) {
+        let sign = if orientation_preserving { 1.0 } else { -1.0 };
+        if not_zero(os.magnitude()) {
+          tangent = os * (sign / os.magnitude());
+        }
+        if not_zero(ot.magnitude()) {
+          bitangent = ot * (sign / ot.magnitude());
+        }
+        let area = signed_area.abs();
+        group_with_any = !(not_zero(os.magnitude() / area) && not_zero(ot.magnitude() / area));
+      }
+
+      Triangle {
+        index: t,
+        tangent,
+        bitangent,
+        orientation_preserving,
+        group_with_any,
+        neighbours: [None; 3],
+        groups: [None; 3],
+      }
+    })
+    .collect::<Vec<_>>();
+  let vertex = |triangle: &Triangle, i: usize| welded[triangle.index * 3 + i];
+
+  // Pair up edges that two triangles run along in opposite directions
+  let mut open_edges = HashMap::<(usize, usize), Vec<(usize, usize)>>::new();
+  for t in 0..triangles.len() {
+    for i in 0..3 {
+      let edge = (vertex(&triangles[t], i), vertex(&triangles[t], (i + 1) % 3));
+      match open_edges
+        .get_mut(&(edge.1, edge.0))
+        .filter(|open| !open.is_empty())
+      {
+        Some(open) => {
+          let (other, j) = open.remove(0);
+          triangles[t].neighbours[i] = Some(other);
+          triangles[other].neighbours[j] = Some(t);
+        }
+        None => open_edges.entry(edge).or_default().push((t, i)),
+      }
+    }
+  }
+
+  // Grow a group from every corner that isn't in one yet, across the edges on either side of
+  // the vertex, depth first
+  let mut groups = Vec::<Group>::new();
+  for t in 0..triangles.len() {
+    for i in 0..3 {
+      if triangles[t].group_with_any || triangles[t].groups[i].is_some() {
+        continue;
+      }
+      let group = groups.len();
+      let group_vertex = vertex(&triangles[t], i);
+      let orientation_preserving = triangles[t].orientation_preserving;
+      groups.push(Group {
+        vertex: group_vertex,
+        orientation_preserving,
+        triangles: vec![t],
+      });
+      triangles[t].groups[i] = Some(group);
+
+      let sides = |triangle: &Triangle, i: usize| {
+        let [left, right] = [triangle.neighbours[i], triangle.neighbours[(i + 2) % 3]];
+        // Pushed in reverse, so the left side is walked first
+        right.into_iter().chain(left)
+      };
+      let mut stack = sides(&triangles[t], i).collect::<Vec<_>>();
+      while let Some(next) = stack.pop() {
+        // Neighbours share the edge, so they have the vertex too
+        let corner = (0..3)
+          .find(|&j| vertex(&triangles[next], j) == group_vertex)
+          .unwrap();
+        let triangle = &mut triangles[next];
+        if triangle.groups[corner].is_some() {
+          continue;
+        }
+        if triangle.group_with_any && triangle.groups.iter().all(Option::is_none) {
+          triangle.orientation_preserving = orientation_preserving;
+        }
+        if triangle.orientation_preserving != orientation_preserving {
+          continue;
+        }
+        triangle.groups[corner] = Some(group);
+        groups[group].triangles.push(next);
+        stack.extend(sides(triangle, corner));
+      }
+    }
+  }
+
+  // Within a group, triangles whose tangents point in exactly opposite directions are averaged
+  // separately, since `genTangSpaceDefault` uses an angular threshold of 180 degrees
+  let mut tangents = vec![None; indices.len()];
+  for group in &groups {
+    let corner = |t: usize| {
+      let triangle = &triangles[t];
+      let i = (0..3)
+        .find(|&i| vertex(triangle, i) == group.vertex)
+        .unwrap();
+      triangle.index * 3 + i
+    };
+    let average = |members: &[usize]| {
+      let mut sum = Vector3::new(0.0, 0.0, 0.0);
+      for &t in members {
+        if triangles[t].group_with_any {
+          continue;
+        }
+        let c = corner(t);
+        let n = normal(c);
+        let base = c - c % 3;
+        let prev = base + (c + 2) % 3;
+        let next = base + (c + 1) % 3;
+        let v1 = project(position(prev) - position(c), n);
+        let v2 = project(position(next) - position(c), n);
+        let angle = v1.dot(v2).clamp(-1.0, 1.0).acos();
+        sum += project(triangles[t].tangent, n) * angle;
+      }
+      let tangent = if not_zero_vector(sum) {
+        sum.normalize()
+      } else {
+        fallback_tangent(normal(corner(members[0])))
+      };
+      let w = if group.orientation_preserving {
+        1.0
+      } else {
+        -1.0
+      };
+      [tangent.x, tangent.y, tangent.z, w]
+    };
+
+    let mut subgroups = Vec::<(Vec<usize>, [f32; 4])>::new();
+    for &t in &group.triangles {
+      let c = corner(t);
+      let n = normal(c);
+      let tangent = project(triangles[t].tangent, n);
+      let bitangent = project(triangles[t].bitangent, n);
+      let mut members = group
+        .triangles
+        .iter()
+        .copied()
+        .filter(|&other| {
+          let any = triangles[t].group_with_any || triangles[other].group_with_any;
+          any
+            || other == t
+            || (project(triangles[other].tangent, n).dot(tangent) > -1.0
+              && project(triangles[other].bitangent, n).dot(bitangent) > -1.0)
+        })
+        .collect::<Vec<_>>();
+      members.sort_unstable();
+      let result = match subgroups.iter().find(|(existing, _)| *existing == members) {
+        Some(&(_, result)) => result,
+        None => {
+          let result = average(&members);
+          subgroups.push((members, result));
+          result
+        }
+      };
+      tangents[c] = Some(result);
+    }
+  }
+
+  // Corners of degenerate triangles copy the first corner at the same vertex that isn't
+  let mut first_corners = HashMap::new();
+  for triangle in &triangles {
+    for i in 0..3 {
+      let corner = triangle.index * 3 + i;
+      first_corners.entry(welded[corner]).or_insert(corner);
+    }
+  }
+  for corner in 0..indices.len() {
+    if degenerate(corner / 3) {
+      tangents[corner] = first_corners
+        .get(&welded[corner])
+        .and_then(|&first| tangents[first]);
+    }
+  }
+
+  tangents
+    .into_iter()
+    .map(|tangent| tangent.unwrap_or(DEFAULT_TANGENT))
+    .collect()
+}
+
+/// Generates tangents for an indexed mesh, duplicating vertices whose corners ended up with
+/// different tangents (i.e. vertices on a mirroring seam).
+pub(super) fn generate_for_vertices(vertices: &mut Vec<ModelVertex>, indices: &mut [u32]) {
+  let positions = vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+  let normals = vertices.iter().map(|v| v.normal).collect::<Vec<_>>();
+  let tex_coords = vertices.iter().map(|v| v.tex_coords).collect::<Vec<_>>();
+  let tangents = generate(&positions, &normals, &tex_coords, indices);
+
+  let mut assigned = vec![None; vertices.len()];
+  let mut splits = HashMap::new();
+  for (index, tangent) in indices.iter_mut().zip(tangents) {
+    let vertex = *index as usize;
+    let bits = tangent.map(f32::to_bits);
+    match assigned[vertex] {
+      None => {
+        assigned[vertex] = Some(bits);
+        vertices[vertex].tangent = tangent;
+      }
+      Some(existing) if existing == bits => {}
+      Some(_) => {
+        *index = *splits.entry((vertex, bits)).or_insert_with(|| {
+          let mut split = vertices[vertex];
+          split.tangent = tangent;
+          vertices.push(split);
+          vertices.len() as u32 - 1
+        });
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_tangent(actual: [f32; 4], expected: [f32; 4]) {
+    for i in 0..4 {
+      assert!(
+        (actual[i] - expected[i]).abs() < 1e-5,
+        "expected {:?}, got {:?}",
+        expected,
+        actual
+      );
+    }
+  }
+
+  const QUAD_POSITIONS: [[f32; 3]; 4] = [
+    [0.0, 0.0, 0.0],
+    [1.0, 0.0, 0.0],
+    [1.0, 1.0, 0.0],
+    [0.0, 1.0, 0.0],
+  ];
+  const QUAD_INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+  #[test]
+  fn quad_tangent_follows_u() {
+    let normals = [[0.0, 0.0, 1.0]; 4];
+    let tex_coords = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+    let tangents = generate(&QUAD_POSITIONS, &normals, &tex_coords, &QUAD_INDICES);
+    assert_eq!(tangents.len(), QUAD_INDICES.len());
+    for tangent in tangents {
+      assert_tangent(tangent, [1.0, 0.0, 0.0, 1.0]);
+    }
+  }
+
+  #[test]
+  fn mirrored_tex_coords_flip_handedness() {
+    let normals = [[0.0, 0.0, 1.0]; 4];
+    let tex_coords = [[1.0, 0.0], [0.0, 0.0], [0.0, 1.0], [1.0, 1.0]];
+    for tangent in generate(&QUAD_POSITIONS, &normals, &tex_coords, &QUAD_INDICES) {
+      assert_tangent(tangent, [-1.0, 0.0, 0.0, -1.0]);
+    }
+  }
+
+  #[test]
+  fn tangent_is_orthogonalised_against_normal() {
+    let normals = [[0.6, 0.0, 0.8]; 4];
+    let tex_coords = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+    for tangent in generate(&QUAD_POSITIONS, &normals, &tex_coords, &QUAD_INDICES) {
+      assert_tangent(tangent, [0.8, 0.0, -0.6, 1.0]);
+    }
+  }
+
+  #[test]
+  fn corners_are_weighted_by_angle() {
+    // The triangles share the edge from the origin to (0, 1). The first has a 90 degree corner
+    // at the origin and a 45 degree one at (0, 1), with a tangent of +x, and the second the
+    // other way round, with its tangent pointing diagonally down.
+    let positions = [
+      [0.0, 0.0, 0.0],
+      [1.0, 0.0, 0.0],
+      [0.0, 1.0, 0.0],
+      [-1.0, 1.0, 0.0],
+    ];
+    let normals = [[0.0, 0.0, 1.0]; 4];
+    let tex_coords = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [-1.0, 0.0]];
+    let indices = [0, 1, 2, 0, 2, 3];
+    let tangents = generate(&positions, &normals, &tex_coords, &indices);
+
+    let x = Vector3::unit_x();
+    let diagonal = Vector3::new(1.0, -1.0, 0.0).normalize();
+    let at_origin = (x * 2.0 + diagonal).normalize();
+    let at_top = (x + diagonal * 2.0).normalize();
+    assert_tangent(tangents[0], [at_origin.x, at_origin.y, 0.0, 1.0]);
+    assert_tangent(tangents[3], [at_origin.x, at_origin.y, 0.0, 1.0]);
+    assert_tangent(tangents[2], [at_top.x, at_top.y, 0.0, 1.0]);
+    assert_tangent(tangents[4], [at_top.x, at_top.y, 0.0, 1.0]);
+    assert_tangent(tangents[1], [1.0, 0.0, 0.0, 1.0]);
+    assert_tangent(tangents[5], [diagonal.x, diagonal.y, 0.0, 1.0]);
+  }
+
+  #[test]
+  fn corners_only_join_across_shared_edges() {
+    // Both triangles have a corner at the origin, but no edge in common, so they keep their
+    // own tangents there.
+    let positions = [
+      [0.0, 0.0, 0.0],
+      [1.0, 0.0, 0.0],
+      [0.0, 1.0, 0.0],
+      [0.0, 1.0, 0.0],
+      [-1.0, 1.0, 0.0],
+    ];
+    let normals = [[0.0, 0.0, 1.0]; 5];
+    let tex_coords = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 0.0], [1.0, 1.0]];
+    let indices = [0, 1, 2, 0, 3, 4];
+    let tangents = generate(&positions, &normals, &tex_coords, &indices);
+    assert_tangent(tangents[0], [1.0, 0.0, 0.0, 1.0]);
+    assert_tangent(tangents[3], [0.0, 1.0, 0.0, 1.0]);
+  }
+
+  #[test]
+  fn degenerate_tex_coords_produce_a_valid_tangent() {
+    let normals = [[0.0, 0.0, 1.0]; 4];
+    let tex_coords = [[0.5, 0.5]; 4];
+    for tangent in generate(&QUAD_POSITIONS, &normals, &tex_coords, &QUAD_INDICES) {
+      let t = Vector3::new(tangent[0], tangent[1], tangent[2]);
+      assert!(tangent.iter().all(|c| c.is_finite()));
+      assert!((t.magnitude() - 1.0).abs() < 1e-5);
+      assert!(t.z.abs() < 1e-5);
+    }
+  }
+
+  #[test]
+  fn degenerate_uv_triangle_joins_its_neighbour() {
+    // The second triangle has no texture-space area, so it takes the tangent of the first at
+    // the vertices they share instead of producing NaNs.
+    let normals = [[0.0, 0.0, 1.0]; 4];
+    let tex_coords = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [1.0, 1.0]];
+    let tangents = generate(&QUAD_POSITIONS, &normals, &tex_coords, &QUAD_INDICES);
+    assert_tangent(tangents[3], tangents[0]);
+    assert_tangent(tangents[4], tangents[2]);
+    assert!(tangents.iter().flatten().all(|c| c.is_finite()));
+  }
+
+  #[test]
+  fn mirror_seam_splits_vertices() {
+    // Both triangles share the edge 0-2 with identical attributes, but the second one mirrors
+    // the texture, so the shared vertices need a tangent for each side.
+    let vertex = |position: [f32; 3], tex_coords: [f32; 2]| ModelVertex {
+      position,
+      tex_coords,
+      normal: [0.0, 0.0, 1.0],
+      tangent: [0.0; 4],
+    };
+    let mut vertices = vec![
+      vertex([0.0, 0.0, 0.0], [0.0, 0.0]),
+      vertex([1.0, 0.0, 0.0], [1.0, 0.0]),
+      vertex([0.0, 1.0, 0.0], [0.0, 1.0]),
+      vertex([-1.0, 0.0, 0.0], [1.0, 0.0]),
+    ];
+    let mut indices = [0, 1, 2, 0, 2, 3];
+    generate_for_vertices(&mut vertices, &mut indices);
+
+    assert_eq!(vertices.len(), 6);
+    assert_eq!(indices, [0, 1, 2, 4, 5, 3]);
+    assert_tangent(vertices[0].tangent, [1.0, 0.0, 0.0, 1.0]);
+    assert_tangent(vertices[4].tangent, [-1.0, 0.0, 0.0, -1.0]);
+  }
+
+  #[test]
+  fn mirrored_quad_matches_reference() {
+    // A strip of two quads whose texture mirrors about x = 1, with the right half sheared and
+    // its outer edge bent away. The expected tangents are what `genTangSpaceDefault` outputs.
+    let positions = [
+      [0.0, 0.0, 0.0],
+      [1.0, 0.0, 0.0],
+      [2.0, 0.5, 0.0],
+      [0.0, 1.0, 0.0],
+      [1.0, 1.0, 0.0],
+      [2.0, 1.5, 0.0],
+    ];
+    let up = [0.0, 0.0, 1.0];
+    let bent = [-0.6, 0.0, 0.8];
+    let normals = [up, up, bent, up, up, bent];
+    let tex_coords = [
+      [0.0, 0.0],
+      [1.0, 0.0],
+      [0.0, 0.0],
+      [0.0, 1.0],
+      [1.0, 1.0],
+      [0.0, 1.0],
+    ];
+    let indices = [0, 1, 4, 0, 4, 3, 1, 2, 5, 1, 5, 4];
+    let tangents = generate(&positions, &normals, &tex_coords, &indices);
+
+    let left = [1.0, 0.0, 0.0, 1.0];
+    let right = [-0.8944272, -0.4472136, 0.0, -1.0];
+    let right_bent = [-0.6783986, -0.5299989, -0.508799, -1.0];
+    let expected = [
+      left, left, left, left, left, left, right, right_bent, right_bent, right, right_bent, right,
+    ];
+    for (tangent, expected) in tangents.into_iter().zip(expected) {
+      assert_tangent(tangent, expected);
+    }
+
+    // The vertices on the mirror line need one tangent for each side
+    let mut vertices = positions
+      .iter()
+      .zip(normals)
+      .zip(tex_coords)
+      .map(|((&position, normal), tex_coords)| ModelVertex {
+        position,
+        tex_coords,
+        normal,
+        tangent: [0.0; 4],
+      })
+      .collect::<Vec<_>>();
+    let mut indices = indices;
+    generate_for_vertices(&mut vertices, &mut indices);
+    assert_eq!(vertices.len(), 8);
+    assert_eq!(indices, [0, 1, 4, 0, 4, 3, 6, 2, 5, 6, 5, 7]);
+    assert_eq!(vertices[6].position, positions[1]);
+    assert_eq!(vertices[7].position, positions[4]);
+  }
+}
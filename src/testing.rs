@@ -0,0 +1,19 @@
+//! Helpers shared by tests.
+
+/// A device on the default adapter. Tests that need one are marked `#[ignore]`, so they only run
+/// with `cargo test -- --ignored`, and then fail on machines without a GPU.
+pub fn device() -> (wgpu::Device, wgpu::Queue) {
+  let instance = wgpu::Instance::new(wgpu::Backends::all());
+  let adapter =
+    pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+      .expect("No GPU adapter available");
+  let device = pollster::block_on(adapter.request_device(
+    &wgpu::DeviceDescriptor {
+      label: Some("Test Device"),
+      features: wgpu::Features::empty(),
+      limits: adapter.limits(),
+    },
+    None,
+  ));
+  device.expect("Failed to create a device on the available adapter")
+}
@@ -1,109 +1,587 @@
-use anyhow::Result;
+pub mod atlas;
+pub mod compressed;
+mod decompress;
+mod exr;
+pub mod hdr;
+
+pub use atlas::{AtlasLayout, AtlasOptions};
+pub use compressed::{BlockFormat, CompressedImage};
+pub use hdr::HdrImage;
+
+use crate::{
+  cubemap::{self, EquirectangularPipelines},
+  mipmap::{self, MipmapMode, MipmapPipelines},
+};
+use anyhow::{bail, Context, Result};
 use image::GenericImageView;
-use std::path::Path;
+use std::{
+  collections::HashMap,
+  num::NonZeroU8,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex, Weak},
+};
+
+/// How a texture is sampled.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SamplerOptions {
+  pub address_mode_u: wgpu::AddressMode,
+  pub address_mode_v: wgpu::AddressMode,
+  pub address_mode_w: wgpu::AddressMode,
+  pub mag_filter: wgpu::FilterMode,
+  pub min_filter: wgpu::FilterMode,
+  pub mipmap_filter: wgpu::FilterMode,
+  /// Maximum anisotropy, between 1 and 16. Requires `DownlevelFlags::ANISOTROPIC_FILTERING`.
+  pub anisotropy_clamp: Option<NonZeroU8>,
+  /// Added to the mip level picked by the hardware. wgpu samplers have no LOD bias, so this is
+  /// passed to the shader through the material uniform instead.
+  pub lod_bias: f32,
+}
+
+impl Default for SamplerOptions {
+  /// Trilinear filtering with repeating texture coordinates, which suits tiling materials.
+  fn default() -> Self {
+    Self {
+      address_mode_u: wgpu::AddressMode::Repeat,
+      address_mode_v: wgpu::AddressMode::Repeat,
+      address_mode_w: wgpu::AddressMode::Repeat,
+      mag_filter: wgpu::FilterMode::Linear,
+      min_filter: wgpu::FilterMode::Linear,
+      mipmap_filter: wgpu::FilterMode::Linear,
+      anisotropy_clamp: None,
+      lod_bias: 0.0,
+    }
+  }
+}
+
+impl SamplerOptions {
+  /// Sets all address modes, e.g. to `ClampToEdge` for textures that shouldn't tile.
+  pub fn with_address_mode(self, address_mode: wgpu::AddressMode) -> Self {
+    Self {
+      address_mode_u: address_mode,
+      address_mode_v: address_mode,
+      address_mode_w: address_mode,
+      ..self
+    }
+  }
+}
+
+/// Hands out one shared `wgpu::Sampler` per distinct sampler descriptor, since samplers are
+/// a limited resource on some backends. Also keeps the pipelines for generating mip chains and
+/// converting panoramas to cube textures, as everything creating textures already has the cache
+/// at hand.
+#[derive(Default)]
+pub struct SamplerCache {
+  // The LOD bias isn't part of the sampler, so it isn't part of the key either
+  samplers: Mutex<HashMap<SamplerKey, Arc<wgpu::Sampler>>>,
+  mipmaps: MipmapPipelines,
+  equirectangular: EquirectangularPipelines,
+}
+
+type SamplerKey = (
+  [wgpu::AddressMode; 3],
+  [wgpu::FilterMode; 3],
+  Option<NonZeroU8>,
+);
+
+impl SamplerCache {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn get(&self, device: &wgpu::Device, options: &SamplerOptions) -> Arc<wgpu::Sampler> {
+    let key = (
+      [
+        options.address_mode_u,
+        options.address_mode_v,
+        options.address_mode_w,
+      ],
+      [
+        options.mag_filter,
+        options.min_filter,
+        options.mipmap_filter,
+      ],
+      options.anisotropy_clamp,
+    );
+    let mut samplers = self.samplers.lock().unwrap();
+    samplers
+      .entry(key)
+      .or_insert_with(|| {
+        Arc::new(device.create_sampler(&wgpu::SamplerDescriptor {
+          label: Some("Texture Sampler"),
+          address_mode_u: options.address_mode_u,
+          address_mode_v: options.address_mode_v,
+          address_mode_w: options.address_mode_w,
+          mag_filter: options.mag_filter,
+          min_filter: options.min_filter,
+          mipmap_filter: options.mipmap_filter,
+          anisotropy_clamp: options.anisotropy_clamp,
+          ..Default::default()
+        }))
+      })
+      .clone()
+  }
+
+  pub fn mipmaps(&self) -> &MipmapPipelines {
+    &self.mipmaps
+  }
+
+  pub fn equirectangular(&self) -> &EquirectangularPipelines {
+    &self.equirectangular
+  }
+}
+
+/// What a texture holds, which decides its format, colour space, which channels are kept and
+/// how its mip chain is filtered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TextureKind {
+  /// sRGB colour, e.g. diffuse or emissive maps.
+  Color,
+  /// Linear data in up to four channels, e.g. packed occlusion, roughness and metallic maps.
+  Linear,
+  /// Tangent space normals in RGB, renormalised after filtering.
+  Normal,
+  /// Tangent space normals with only X and Y stored in R and G. `triangle.wgsl` reconstructs Z
+  /// for normal maps with two channels.
+  NormalXy,
+  /// A single linear channel taken from red, e.g. opacity or height masks.
+  Mask,
+}
+
+impl TextureKind {
+  /// The format textures of this kind are uploaded as when they aren't compressed.
+  pub fn format(self) -> wgpu::TextureFormat {
+    match self {
+      TextureKind::Color => wgpu::TextureFormat::Rgba8UnormSrgb,
+      TextureKind::Linear | TextureKind::Normal => wgpu::TextureFormat::Rgba8Unorm,
+      TextureKind::NormalXy => wgpu::TextureFormat::Rg8Unorm,
+      TextureKind::Mask => wgpu::TextureFormat::R8Unorm,
+    }
+  }
+
+  pub fn is_srgb(self) -> bool {
+    self == TextureKind::Color
+  }
+
+  /// Whether texels are unit vectors, which filtering shortens.
+  pub fn has_unit_normals(self) -> bool {
+    self == TextureKind::Normal
+  }
+
+  /// The number of channels kept from RGBA pixels.
+  pub fn channel_count(self) -> usize {
+    match self {
+      TextureKind::Color | TextureKind::Linear | TextureKind::Normal => 4,
+      TextureKind::NormalXy => 2,
+      TextureKind::Mask => 1,
+    }
+  }
+
+  /// Keeps the first `channel_count` channels of each pixel of tightly packed RGBA data.
+  pub fn swizzle(self, rgba: &[u8]) -> Vec<u8> {
+    let channel_count = self.channel_count();
+    if channel_count == 4 {
+      return rgba.to_vec();
+    }
+    rgba
+      .chunks_exact(4)
+      .flat_map(|pixel| &pixel[..channel_count])
+      .copied()
+      .collect()
+  }
+}
+
+/// How depth values are laid out, which the projection, the depth clear value and the depth
+/// test all have to agree on.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DepthMode {
+  /// The near plane at 0 and the far plane at 1.
+  #[default]
+  Standard,
+  /// The near plane at 1 and an infinitely far plane at 0. Floats are most precise close to 0,
+  /// which cancels out the precision perspective loses with distance and avoids z-fighting far
+  /// from the camera.
+  ReverseZ,
+}
+
+impl DepthMode {
+  /// The depth of nothing having been drawn yet.
+  pub fn clear_value(self) -> f32 {
+    match self {
+      DepthMode::Standard => 1.0,
+      DepthMode::ReverseZ => 0.0,
+    }
+  }
+
+  /// The depth test letting closer fragments through.
+  pub fn compare(self) -> wgpu::CompareFunction {
+    match self {
+      DepthMode::Standard => wgpu::CompareFunction::Less,
+      DepthMode::ReverseZ => wgpu::CompareFunction::Greater,
+    }
+  }
+
+  /// The comparison for samplers reading the depth buffer, e.g. for shadow maps.
+  pub fn sampler_compare(self) -> wgpu::CompareFunction {
+    match self {
+      DepthMode::Standard => wgpu::CompareFunction::LessEqual,
+      DepthMode::ReverseZ => wgpu::CompareFunction::GreaterEqual,
+    }
+  }
+}
 
 pub struct Texture {
   pub texture: wgpu::Texture,
   pub view: wgpu::TextureView,
-  pub sampler: wgpu::Sampler,
+  pub sampler: Arc<wgpu::Sampler>,
+  pub sampler_options: SamplerOptions,
+  pub size: wgpu::Extent3d,
+  pub format: wgpu::TextureFormat,
+  pub mip_level_count: u32,
+  /// `D2` for plain textures, `Cube` for cube maps and `D2Array` for texture arrays, which is
+  /// what bind group layouts using `view` have to declare.
+  pub view_dimension: wgpu::TextureViewDimension,
 }
 
 impl Texture {
-  pub fn load<P: AsRef<Path>>(
-    path: P,
+  /// Creates a texture from the colour `pixel` returns for every x and y, for procedural
+  /// textures such as checkerboards or noise.
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_fn(
+    label: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
-    is_normal_map: bool,
+    dimensions: (u32, u32),
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+    mut pixel: impl FnMut(u32, u32) -> [u8; 4],
   ) -> Result<Self> {
-    let path = path.as_ref();
-    let label = path.to_string_lossy();
-    Self::from_image(
-      label.as_ref(),
+    let rgba =
+      image::RgbaImage::from_fn(dimensions.0, dimensions.1, |x, y| image::Rgba(pixel(x, y)));
+    Self::from_raw_rgba(
+      label,
       device,
       queue,
-      &image::open(path)?,
-      is_normal_map,
+      &rgba,
+      dimensions,
+      kind,
+      samplers,
+      sampler_options,
     )
   }
 
-  /* pub fn from_bytes(
+  /// Creates a texture from tightly packed, 8 bit per channel RGBA pixels, with a full mip
+  /// chain generated on the GPU.
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_raw_rgba(
     label: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
-    bytes: &[u8],
+    rgba: &[u8],
+    dimensions: (u32, u32),
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
   ) -> Result<Self> {
-    Self::from_image(label, device, queue, &image::load_from_memory(bytes)?)
-  } */
+    Self::from_raw_rgba_with_mipmaps(
+      label,
+      device,
+      queue,
+      rgba,
+      dimensions,
+      kind,
+      samplers,
+      sampler_options,
+      MipmapMode::Gpu,
+    )
+  }
 
-  pub fn from_image(
+  /// Like `from_raw_rgba`, but picks how the mip chain is generated.
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_raw_rgba_with_mipmaps(
     label: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
-    img: &image::DynamicImage,
-    is_normal_map: bool,
+    rgba: &[u8],
+    dimensions: (u32, u32),
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+    mipmaps: MipmapMode,
   ) -> Result<Self> {
-    let rgba = img.to_rgba8();
-    let dimensions = img.dimensions();
+    Self::from_rgba_layers(
+      label,
+      device,
+      queue,
+      &[rgba],
+      dimensions,
+      wgpu::TextureViewDimension::D2,
+      kind,
+      samplers,
+      sampler_options,
+      mipmaps,
+    )
+  }
+
+  /// Creates a texture with one layer of tightly packed RGBA pixels per entry of `layers`, and a
+  /// full mip chain for each.
+  #[allow(clippy::too_many_arguments)]
+  fn from_rgba_layers(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layers: &[&[u8]],
+    dimensions: (u32, u32),
+    view_dimension: wgpu::TextureViewDimension,
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+    mipmaps: MipmapMode,
+  ) -> Result<Self> {
+    let expected_len = 4 * dimensions.0 as usize * dimensions.1 as usize;
+    if let Some(rgba) = layers.iter().find(|rgba| rgba.len() != expected_len) {
+      bail!(
+        "{}: expected {} bytes of RGBA data for {}x{} pixels, got {}",
+        label,
+        expected_len,
+        dimensions.0,
+        dimensions.1,
+        rgba.len()
+      );
+    }
 
+    let format = kind.format();
+    let mip_level_count = mipmap::mip_level_count(dimensions.0, dimensions.1);
+    let array_layer_count = layers.len() as u32;
     let size = wgpu::Extent3d {
       width: dimensions.0,
       height: dimensions.1,
-      depth_or_array_layers: 1,
+      depth_or_array_layers: array_layer_count,
     };
     let texture = device.create_texture(&wgpu::TextureDescriptor {
       label: Some(label),
       size,
-      mip_level_count: 1,
+      mip_level_count,
       sample_count: 1,
       dimension: wgpu::TextureDimension::D2,
-      format: if is_normal_map {
-        wgpu::TextureFormat::Rgba8Unorm
-      } else {
-        wgpu::TextureFormat::Rgba8UnormSrgb
-      },
-      usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+      format,
+      usage: wgpu::TextureUsages::TEXTURE_BINDING
+        | wgpu::TextureUsages::COPY_DST
+        | wgpu::TextureUsages::COPY_SRC
+        | wgpu::TextureUsages::RENDER_ATTACHMENT,
     });
 
-    queue.write_texture(
-      wgpu::ImageCopyTexture {
-        aspect: wgpu::TextureAspect::All,
-        texture: &texture,
-        mip_level: 0,
-        origin: wgpu::Origin3d::ZERO,
-      },
-      &rgba,
-      wgpu::ImageDataLayout {
-        offset: 0,
-        bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.0),
-        rows_per_image: std::num::NonZeroU32::new(dimensions.1),
-      },
+    let write_level = |level: u32, data: &[u8]| {
+      write_level(
+        queue,
+        &texture,
+        format,
+        dimensions,
+        array_layer_count,
+        level,
+        data,
+      )
+    };
+    write_level(0, &kind.swizzle(&layers.concat()));
+    match mipmaps {
+      MipmapMode::Gpu => mipmap::generate_gpu(
+        samplers.mipmaps(),
+        device,
+        queue,
+        &texture,
+        format,
+        mip_level_count,
+        array_layer_count,
+        kind.has_unit_normals(),
+      ),
+      MipmapMode::Cpu => {
+        let chains = layers
+          .iter()
+          .map(|rgba| {
+            mipmap::generate_cpu(rgba, dimensions, kind.is_srgb(), kind.has_unit_normals())
+          })
+          .collect::<Vec<_>>();
+        for level in 1..mip_level_count {
+          let data = chains
+            .iter()
+            .map(|chain| chain[level as usize - 1].as_slice())
+            .collect::<Vec<_>>();
+          write_level(level, &kind.swizzle(&data.concat()));
+        }
+      }
+    }
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+      label: Some(label),
+      dimension: Some(view_dimension),
+      ..Default::default()
+    });
+
+    Ok(Self {
+      texture,
+      view,
+      sampler: samplers.get(device, sampler_options),
+      sampler_options: *sampler_options,
       size,
+      format,
+      mip_level_count,
+      view_dimension,
+    })
+  }
+
+  /// Uploads a pre-compressed mip chain as it is if the device supports its format, and
+  /// decompresses it on the CPU to the format `kind` asks for otherwise. Only `Color` textures
+  /// are sampled as sRGB. BC6H and ASTC can't be decompressed, so they fail to load on devices
+  /// that can't sample them.
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_compressed(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    image: &CompressedImage,
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let srgb = image.srgb && kind.is_srgb();
+    let dimensions = (image.width, image.height);
+    let upload_format = image
+      .upload_format(srgb, device.features())
+      .with_context(|| format!("{}: can't upload it compressed", label))?;
+    if let Some(format) = upload_format {
+      let levels = image.levels().collect::<Vec<_>>();
+      return Self::from_levels(
+        label,
+        device,
+        queue,
+        format,
+        dimensions,
+        &levels,
+        samplers,
+        sampler_options,
+      );
+    }
+
+    if !image.format.can_decompress() {
+      bail!(
+        "{}: the device can't sample {:?} without {:?}, and it can't be decompressed on the CPU",
+        label,
+        image.format,
+        image.format.required_features()
+      );
+    }
+    log::info!(
+      "{}: the device can't sample {:?}, decompressing it on the CPU",
+      label,
+      image.format
     );
+    let mut levels = image.decompress()?;
+    if levels.len() == 1 {
+      let mipmaps = mipmap::generate_cpu(&levels[0], dimensions, srgb, kind.has_unit_normals());
+      levels.extend(mipmaps);
+    }
+    let format = match kind.format() {
+      // Colour stored as linear data stays linear
+      wgpu::TextureFormat::Rgba8UnormSrgb if !srgb => wgpu::TextureFormat::Rgba8Unorm,
+      format => format,
+    };
+    let levels = levels
+      .iter()
+      .map(|level| kind.swizzle(level))
+      .collect::<Vec<_>>();
+    let levels = levels.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    Self::from_levels(
+      label,
+      device,
+      queue,
+      format,
+      dimensions,
+      &levels,
+      samplers,
+      sampler_options,
+    )
+  }
 
-    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-      address_mode_u: wgpu::AddressMode::ClampToEdge,
-      address_mode_v: wgpu::AddressMode::ClampToEdge,
-      address_mode_w: wgpu::AddressMode::ClampToEdge,
-      mag_filter: wgpu::FilterMode::Linear,
-      min_filter: wgpu::FilterMode::Nearest,
-      mipmap_filter: wgpu::FilterMode::Nearest,
-      ..Default::default()
+  /// Creates a texture with one mip level per entry of `levels`, each tightly packed.
+  #[allow(clippy::too_many_arguments)]
+  fn from_levels(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    format: wgpu::TextureFormat,
+    dimensions: (u32, u32),
+    levels: &[&[u8]],
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let size = wgpu::Extent3d {
+      width: dimensions.0,
+      height: dimensions.1,
+      depth_or_array_layers: 1,
+    };
+    let mip_level_count = levels.len() as u32;
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+      label: Some(label),
+      size,
+      mip_level_count,
+      sample_count: 1,
+      dimension: wgpu::TextureDimension::D2,
+      format,
+      usage: wgpu::TextureUsages::TEXTURE_BINDING
+        | wgpu::TextureUsages::COPY_DST
+        | wgpu::TextureUsages::COPY_SRC,
     });
+    for (level, data) in levels.iter().enumerate() {
+      write_level(queue, &texture, format, dimensions, 1, level as u32, data);
+    }
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let view_dimension = wgpu::TextureViewDimension::D2;
 
     Ok(Self {
       texture,
       view,
-      sampler,
+      sampler: samplers.get(device, sampler_options),
+      sampler_options: *sampler_options,
+      size,
+      format,
+      mip_level_count,
+      view_dimension,
     })
   }
 
+  /// Whether only red and green are stored, like in `NormalXy` textures or BC5 normal maps.
+  pub fn has_two_channels(&self) -> bool {
+    use wgpu::TextureFormat::*;
+    matches!(
+      self.format,
+      Rg8Unorm | Rg8Snorm | Bc5RgUnorm | Bc5RgSnorm | EacRgUnorm | EacRgSnorm
+    )
+  }
+
+  /// Bytes of GPU memory taken up by all mip levels, not counting any driver overhead.
+  pub fn memory_usage(&self) -> u64 {
+    let info = self.format.describe();
+    let (block_width, block_height) = (
+      info.block_dimensions.0 as u32,
+      info.block_dimensions.1 as u32,
+    );
+    (0..self.mip_level_count)
+      .map(|level| {
+        let (width, height) = mipmap::mip_size((self.size.width, self.size.height), level);
+        let blocks = width.div_ceil(block_width) as u64 * height.div_ceil(block_height) as u64;
+        blocks * info.block_size as u64 * self.size.depth_or_array_layers as u64
+      })
+      .sum()
+  }
+
   pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
   pub fn create_depth_texture(
     label: &str,
     device: &wgpu::Device,
     config: &wgpu::SurfaceConfiguration,
+    depth_mode: DepthMode,
   ) -> Self {
     let size = wgpu::Extent3d {
       width: config.width,
@@ -122,6 +600,7 @@ impl Texture {
     let texture = device.create_texture(&desc);
 
     let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    // Comparison samplers aren't shared through the sampler cache
     let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
       address_mode_u: wgpu::AddressMode::ClampToEdge,
       address_mode_v: wgpu::AddressMode::ClampToEdge,
@@ -129,7 +608,7 @@ impl Texture {
       mag_filter: wgpu::FilterMode::Linear,
       min_filter: wgpu::FilterMode::Linear,
       mipmap_filter: wgpu::FilterMode::Nearest,
-      compare: Some(wgpu::CompareFunction::LessEqual),
+      compare: Some(depth_mode.sampler_compare()),
       lod_min_clamp: -100.0,
       lod_max_clamp: 100.0,
       ..Default::default()
@@ -138,7 +617,957 @@ impl Texture {
     Self {
       texture,
       view,
-      sampler,
+      sampler: Arc::new(sampler),
+      sampler_options: SamplerOptions {
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..SamplerOptions::default().with_address_mode(wgpu::AddressMode::ClampToEdge)
+      },
+      size,
+      format: Self::DEPTH_FORMAT,
+      mip_level_count: 1,
+      view_dimension: wgpu::TextureViewDimension::D2,
+    }
+  }
+}
+
+/// Texture sources the demo scene doesn't use: image files, HDR images, cube maps, texture
+/// arrays and readback. They're exercised by the tests instead.
+#[cfg_attr(not(test), allow(dead_code))]
+impl Texture {
+  /// Loads an image file, or a KTX2 or DDS container with its mip chain as stored. Radiance and
+  /// OpenEXR files are loaded as `Rgba16Float`.
+  pub fn load<P: AsRef<Path>>(
+    path: P,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let path = path.as_ref();
+    let label = path.to_string_lossy();
+    if CompressedImage::is_container(path) {
+      return Self::from_compressed(
+        label.as_ref(),
+        device,
+        queue,
+        &CompressedImage::open(path)?,
+        kind,
+        samplers,
+        sampler_options,
+      );
+    }
+    if HdrImage::is_hdr(path) {
+      return Self::from_hdr(
+        label.as_ref(),
+        device,
+        queue,
+        &HdrImage::open(path)?,
+        wgpu::TextureFormat::Rgba16Float,
+        samplers,
+        sampler_options,
+      );
+    }
+    let img = image::open(path).with_context(|| format!("Failed to load texture {:?}", path))?;
+    Self::from_image(
+      label.as_ref(),
+      device,
+      queue,
+      &img,
+      kind,
+      samplers,
+      sampler_options,
+    )
+  }
+
+  /// Loads a Radiance `.hdr` or OpenEXR file into a floating point texture, either
+  /// `Rgba16Float` or `Rgba32Float`. The latter isn't filterable, so it has to be bound with a
+  /// non-filtering sampler.
+  pub fn load_hdr<P: AsRef<Path>>(
+    path: P,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    format: wgpu::TextureFormat,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let path = path.as_ref();
+    Self::from_hdr(
+      path.to_string_lossy().as_ref(),
+      device,
+      queue,
+      &HdrImage::open(path)?,
+      format,
+      samplers,
+      sampler_options,
+    )
+  }
+
+  /// Creates a texture from an encoded file in memory, e.g. one embedded in an archive or a
+  /// glTF buffer. KTX2, DDS, Radiance and OpenEXR files are recognised by their magic number,
+  /// and everything else is left to the `image` crate to guess.
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_bytes(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    bytes: &[u8],
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let context = || format!("Failed to load texture {}", label);
+    if CompressedImage::is_container_data(bytes) {
+      return Self::from_compressed(
+        label,
+        device,
+        queue,
+        &CompressedImage::from_bytes(bytes).with_context(context)?,
+        kind,
+        samplers,
+        sampler_options,
+      );
+    }
+    if HdrImage::is_hdr_data(bytes) {
+      return Self::from_hdr(
+        label,
+        device,
+        queue,
+        &HdrImage::from_bytes(bytes).with_context(context)?,
+        wgpu::TextureFormat::Rgba16Float,
+        samplers,
+        sampler_options,
+      );
+    }
+    let img = image::load_from_memory(bytes).with_context(context)?;
+    Self::from_image(label, device, queue, &img, kind, samplers, sampler_options)
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_image(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    img: &image::DynamicImage,
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let rgba = img.to_rgba8();
+    Self::from_raw_rgba(
+      label,
+      device,
+      queue,
+      &rgba,
+      img.dimensions(),
+      kind,
+      samplers,
+      sampler_options,
+    )
+  }
+
+  /// Creates a cube texture from six square faces of the same size, in the order +X, -X, +Y,
+  /// -Y, +Z, -Z, with a full mip chain generated on the GPU.
+  pub fn from_cube_faces(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    faces: &[image::DynamicImage],
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    check_cube_faces(label, faces)?;
+    let faces = faces.iter().map(|face| face.to_rgba8()).collect::<Vec<_>>();
+    Self::from_image_layers(
+      label,
+      device,
+      queue,
+      &faces,
+      wgpu::TextureViewDimension::Cube,
+      TextureKind::Color,
+      samplers,
+      sampler_options,
+    )
+  }
+
+  /// Loads the six faces of a cube texture, in the order +X, -X, +Y, -Y, +Z, -Z.
+  pub fn load_cube_faces<P: AsRef<Path>>(
+    paths: &[P],
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    if paths.len() != 6 {
+      bail!("A cube texture needs 6 faces, got {}", paths.len());
+    }
+    let faces = open_images(paths)?;
+    Self::from_cube_faces(
+      paths[0].as_ref().to_string_lossy().as_ref(),
+      device,
+      queue,
+      &faces,
+      samplers,
+      sampler_options,
+    )
+  }
+
+  /// Renders a cube texture with `face_size` pixel faces from an equirectangular `panorama`,
+  /// such as an HDR environment map, keeping its format.
+  pub fn from_equirectangular(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    panorama: &Texture,
+    face_size: u32,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let features = panorama.format.describe().guaranteed_format_features;
+    if !features.filterable
+      || !features
+        .allowed_usages
+        .contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+    {
+      bail!(
+        "{}: panoramas have to be filterable and renderable, which {:?} isn't",
+        label,
+        panorama.format
+      );
+    }
+    if panorama.view_dimension != wgpu::TextureViewDimension::D2 {
+      bail!("{}: panoramas have to be 2D textures", label);
+    }
+
+    let mip_level_count = mipmap::mip_level_count(face_size, face_size);
+    let texture = cubemap::from_equirectangular(
+      samplers.equirectangular(),
+      samplers.mipmaps(),
+      device,
+      queue,
+      &panorama.view,
+      panorama.format,
+      face_size,
+      mip_level_count,
+    );
+    let view_dimension = wgpu::TextureViewDimension::Cube;
+    let view = texture.create_view(&wgpu::TextureViewDescriptor {
+      label: Some(label),
+      dimension: Some(view_dimension),
+      ..Default::default()
+    });
+
+    Ok(Self {
+      texture,
+      view,
+      sampler: samplers.get(device, sampler_options),
+      sampler_options: *sampler_options,
+      size: wgpu::Extent3d {
+        width: face_size,
+        height: face_size,
+        depth_or_array_layers: 6,
+      },
+      format: panorama.format,
+      mip_level_count,
+      view_dimension,
+    })
+  }
+
+  /// Creates a 2D texture array with one layer per image, which all have to be the same size,
+  /// e.g. for blending terrain materials in a single binding.
+  pub fn from_layers(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layers: &[image::DynamicImage],
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let layers = layers
+      .iter()
+      .map(|layer| layer.to_rgba8())
+      .collect::<Vec<_>>();
+    Self::from_image_layers(
+      label,
+      device,
+      queue,
+      &layers,
+      wgpu::TextureViewDimension::D2Array,
+      kind,
+      samplers,
+      sampler_options,
+    )
+  }
+
+  /// Loads a 2D texture array with one layer per image file.
+  pub fn load_array<P: AsRef<Path>>(
+    paths: &[P],
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let label = match paths.first() {
+      Some(path) => path.as_ref().to_string_lossy().into_owned(),
+      None => bail!("Texture arrays need at least one layer"),
+    };
+    Self::from_layers(
+      &label,
+      device,
+      queue,
+      &open_images(paths)?,
+      kind,
+      samplers,
+      sampler_options,
+    )
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  fn from_image_layers(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layers: &[image::RgbaImage],
+    view_dimension: wgpu::TextureViewDimension,
+    kind: TextureKind,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let dimensions = match layers.first() {
+      Some(layer) => layer.dimensions(),
+      None => bail!("{}: texture arrays need at least one layer", label),
+    };
+    if let Some(layer) = layers.iter().position(|l| l.dimensions() != dimensions) {
+      bail!(
+        "{}: layer {} is {:?}, but layer 0 is {:?}",
+        label,
+        layer,
+        layers[layer].dimensions(),
+        dimensions
+      );
+    }
+    let layers = layers
+      .iter()
+      .map(|layer| layer.as_raw().as_slice())
+      .collect::<Vec<_>>();
+    Self::from_rgba_layers(
+      label,
+      device,
+      queue,
+      &layers,
+      dimensions,
+      view_dimension,
+      kind,
+      samplers,
+      sampler_options,
+      MipmapMode::Gpu,
+    )
+  }
+
+  /// Creates an `Rgba16Float` or `Rgba32Float` texture with a full mip chain, box filtered on the
+  /// CPU in linear space.
+  #[allow(clippy::too_many_arguments)]
+  pub fn from_hdr(
+    label: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    image: &HdrImage,
+    format: wgpu::TextureFormat,
+    samplers: &SamplerCache,
+    sampler_options: &SamplerOptions,
+  ) -> Result<Self> {
+    let encode = match format {
+      wgpu::TextureFormat::Rgba16Float => |pixels: &[[f32; 4]]| {
+        pixels
+          .iter()
+          .flatten()
+          .flat_map(|&c| half::f16::from_f32(c).to_le_bytes())
+          .collect::<Vec<_>>()
+      },
+      wgpu::TextureFormat::Rgba32Float => {
+        |pixels: &[[f32; 4]]| bytemuck::cast_slice::<_, u8>(pixels).to_vec()
+      }
+      _ => bail!(
+        "{}: HDR textures have to be Rgba16Float or Rgba32Float, not {:?}",
+        label,
+        format
+      ),
+    };
+    let dimensions = (image.width, image.height);
+    if image.pixels.len() != dimensions.0 as usize * dimensions.1 as usize {
+      bail!(
+        "{}: expected {} pixels for {}x{}, got {}",
+        label,
+        dimensions.0 as usize * dimensions.1 as usize,
+        dimensions.0,
+        dimensions.1,
+        image.pixels.len()
+      );
+    }
+
+    let mipmaps = mipmap::generate_cpu_f32(&image.pixels, dimensions);
+    let levels = std::iter::once(image.pixels.as_slice())
+      .chain(mipmaps.iter().map(Vec::as_slice))
+      .map(encode)
+      .collect::<Vec<_>>();
+    let levels = levels.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    Self::from_levels(
+      label,
+      device,
+      queue,
+      format,
+      dimensions,
+      &levels,
+      samplers,
+      sampler_options,
+    )
+  }
+
+  /// Copies the top mip level of the first layer back from the GPU, e.g. for screenshots or
+  /// comparing against golden images.
+  pub async fn read_to_image(
+    &self,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+  ) -> Result<ReadbackImage> {
+    self.read_level_to_image(device, queue, 0, 0).await
+  }
+
+  /// Copies one mip level of one layer back from the GPU. 8 bit formats come back as RGBA8,
+  /// with BGRA swizzled, missing channels set to 0 and alpha to 255, and sRGB left encoded.
+  /// Float formats come back as linear RGBA32F.
+  ///
+  /// The texture needs `COPY_SRC` usage, which all textures created by this module have.
+  pub async fn read_level_to_image(
+    &self,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mip_level: u32,
+    array_layer: u32,
+  ) -> Result<ReadbackImage> {
+    use wgpu::TextureFormat::*;
+    let bytes_per_pixel = match self.format {
+      R8Unorm => 1,
+      Rg8Unorm => 2,
+      Rgba8Unorm | Rgba8UnormSrgb | Bgra8Unorm | Bgra8UnormSrgb => 4,
+      Rgba16Float => 8,
+      Rgba32Float => 16,
+      format => bail!("Reading back {:?} textures isn't supported", format),
+    };
+    if mip_level >= self.mip_level_count || array_layer >= self.size.depth_or_array_layers {
+      bail!(
+        "Texture has {} mip levels and {} layers, can't read level {} of layer {}",
+        self.mip_level_count,
+        self.size.depth_or_array_layers,
+        mip_level,
+        array_layer
+      );
+    }
+
+    let (width, height) = mipmap::mip_size((self.size.width, self.size.height), mip_level);
+    // Rows in the buffer have to start at multiples of 256 bytes
+    let row_len = width * bytes_per_pixel;
+    let padded_row_len =
+      row_len.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+      label: Some("Texture Readback Buffer"),
+      size: padded_row_len as u64 * height as u64,
+      usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+      mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+      label: Some("Texture Readback Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+      wgpu::ImageCopyTexture {
+        aspect: wgpu::TextureAspect::All,
+        texture: &self.texture,
+        mip_level,
+        origin: wgpu::Origin3d {
+          x: 0,
+          y: 0,
+          z: array_layer,
+        },
+      },
+      wgpu::ImageCopyBuffer {
+        buffer: &buffer,
+        layout: wgpu::ImageDataLayout {
+          offset: 0,
+          bytes_per_row: std::num::NonZeroU32::new(padded_row_len),
+          rows_per_image: std::num::NonZeroU32::new(height),
+        },
+      },
+      wgpu::Extent3d {
+        width,
+        height,
+        depth_or_array_layers: 1,
+      },
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let mapping = slice.map_async(wgpu::MapMode::Read);
+    // Native backends only make progress on the mapping while the device is polled
+    device.poll(wgpu::Maintain::Wait);
+    mapping.await?;
+    let data = unpad_rows(&slice.get_mapped_range(), row_len, padded_row_len);
+    buffer.unmap();
+
+    let image = match self.format {
+      Rgba8Unorm | Rgba8UnormSrgb => {
+        ReadbackImage::Rgba8(image::RgbaImage::from_raw(width, height, data).unwrap())
+      }
+      R8Unorm | Rg8Unorm => {
+        let pixels = data
+          .chunks_exact(bytes_per_pixel as usize)
+          .flat_map(|pixel| [pixel[0], *pixel.get(1).unwrap_or(&0), 0, 255])
+          .collect();
+        ReadbackImage::Rgba8(image::RgbaImage::from_raw(width, height, pixels).unwrap())
+      }
+      Bgra8Unorm | Bgra8UnormSrgb => {
+        let mut data = data;
+        for pixel in data.chunks_exact_mut(4) {
+          pixel.swap(0, 2);
+        }
+        ReadbackImage::Rgba8(image::RgbaImage::from_raw(width, height, data).unwrap())
+      }
+      Rgba16Float => {
+        let pixels = data
+          .chunks_exact(2)
+          .map(|half| half::f16::from_le_bytes([half[0], half[1]]).to_f32())
+          .collect();
+        ReadbackImage::Rgba32F(Rgba32FImage::from_raw(width, height, pixels).unwrap())
+      }
+      _ => {
+        let pixels = data
+          .chunks_exact(4)
+          .map(|float| f32::from_le_bytes(float.try_into().unwrap()))
+          .collect();
+        ReadbackImage::Rgba32F(Rgba32FImage::from_raw(width, height, pixels).unwrap())
+      }
+    };
+    Ok(image)
+  }
+}
+
+pub type Rgba32FImage = image::ImageBuffer<image::Rgba<f32>, Vec<f32>>;
+
+/// Pixels copied back from a texture by `Texture::read_to_image`. Like readback itself, only the
+/// tests look at them.
+#[cfg_attr(not(test), allow(dead_code))]
+pub enum ReadbackImage {
+  Rgba8(image::RgbaImage),
+  /// Linear float pixels, from either `Rgba16Float` or `Rgba32Float` textures.
+  Rgba32F(Rgba32FImage),
+}
+
+/// Writes mip `level` of every layer of a texture with base size `dimensions`, from tightly
+/// packed rows of pixels or blocks, one layer after the other.
+fn write_level(
+  queue: &wgpu::Queue,
+  texture: &wgpu::Texture,
+  format: wgpu::TextureFormat,
+  dimensions: (u32, u32),
+  array_layer_count: u32,
+  level: u32,
+  data: &[u8],
+) {
+  let info = format.describe();
+  let (block_width, block_height) = (
+    info.block_dimensions.0 as u32,
+    info.block_dimensions.1 as u32,
+  );
+  let (width, height) = mipmap::mip_size(dimensions, level);
+  let (blocks_x, blocks_y) = (width.div_ceil(block_width), height.div_ceil(block_height));
+  queue.write_texture(
+    wgpu::ImageCopyTexture {
+      aspect: wgpu::TextureAspect::All,
+      texture,
+      mip_level: level,
+      origin: wgpu::Origin3d::ZERO,
+    },
+    data,
+    wgpu::ImageDataLayout {
+      offset: 0,
+      bytes_per_row: std::num::NonZeroU32::new(blocks_x * info.block_size as u32),
+      rows_per_image: std::num::NonZeroU32::new(blocks_y),
+    },
+    // Copies of compressed formats cover whole blocks, even past the edge of small mips
+    wgpu::Extent3d {
+      width: blocks_x * block_width,
+      height: blocks_y * block_height,
+      depth_or_array_layers: array_layer_count,
+    },
+  );
+}
+
+/// Drops the padding at the end of each row of a texture copied to a buffer.
+fn unpad_rows(data: &[u8], row_len: u32, padded_row_len: u32) -> Vec<u8> {
+  data
+    .chunks(padded_row_len as usize)
+    .flat_map(|row| &row[..row_len as usize])
+    .copied()
+    .collect()
+}
+
+/// Checks that there are six square faces of the same size, before anything is uploaded.
+fn check_cube_faces(label: &str, faces: &[image::DynamicImage]) -> Result<()> {
+  if faces.len() != 6 {
+    bail!(
+      "{}: a cube texture needs 6 faces, got {}",
+      label,
+      faces.len()
+    );
+  }
+  let dimensions = faces[0].dimensions();
+  if dimensions.0 != dimensions.1 {
+    bail!(
+      "{}: cube faces have to be square, not {}x{}",
+      label,
+      dimensions.0,
+      dimensions.1
+    );
+  }
+  if let Some(face) = faces.iter().find(|face| face.dimensions() != dimensions) {
+    bail!(
+      "{}: cube faces have to be the same size, got {:?} and {:?}",
+      label,
+      dimensions,
+      face.dimensions()
+    );
+  }
+  Ok(())
+}
+
+/// Opens every image in `paths`, for the layers of a cube map or texture array.
+fn open_images<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<image::DynamicImage>> {
+  paths
+    .iter()
+    .map(|path| {
+      let path = path.as_ref();
+      image::open(path).with_context(|| format!("Failed to load texture {:?}", path))
+    })
+    .collect()
+}
+
+/// 1x1 textures used in place of material maps that an asset doesn't provide.
+///
+/// These are meant to be created once per device and shared between all loaded models.
+pub struct DefaultTextures {
+  /// Opaque white, so the material's diffuse colour is used as-is.
+  pub diffuse: Arc<Texture>,
+  /// A flat tangent-space normal, (0.5, 0.5, 1.0).
+  pub normal: Arc<Texture>,
+}
+
+impl DefaultTextures {
+  pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, samplers: &SamplerCache) -> Result<Self> {
+    let pixel = |label: &str, rgba: [u8; 4], kind: TextureKind| {
+      Texture::from_fn(
+        label,
+        device,
+        queue,
+        (1, 1),
+        kind,
+        samplers,
+        &SamplerOptions::default(),
+        |_, _| rgba,
+      )
+      .map(Arc::new)
+    };
+    Ok(Self {
+      diffuse: pixel(
+        "Default Diffuse Texture",
+        [255, 255, 255, 255],
+        TextureKind::Color,
+      )?,
+      normal: pixel(
+        "Default Normal Texture",
+        [128, 128, 255, 255],
+        TextureKind::Normal,
+      )?,
+    })
+  }
+}
+
+/// Shares textures between materials and models, so every image file is uploaded once.
+///
+/// Textures are keyed on their canonical path and `TextureKind`, and are freed once the last
+/// `Arc` handed out for them is dropped. Only the texture is shared, not how it's sampled, so
+/// materials that want other sampler options get their own sampler from `samplers`.
+pub struct TextureRegistry {
+  pub defaults: DefaultTextures,
+  pub samplers: SamplerCache,
+  textures: Mutex<HashMap<(PathBuf, TextureKind), Weak<Texture>>>,
+}
+
+impl TextureRegistry {
+  pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Self> {
+    let samplers = SamplerCache::new();
+    Ok(Self {
+      defaults: DefaultTextures::new(device, queue, &samplers)?,
+      samplers,
+      textures: Mutex::new(HashMap::new()),
+    })
+  }
+
+  /// Returns the texture registered for the image file at `path`, or registers the one that
+  /// `create` makes, for textures whose pixels were already decoded elsewhere.
+  pub fn get_or_insert_with(
+    &self,
+    path: &Path,
+    kind: TextureKind,
+    create: impl FnOnce() -> Result<Texture>,
+  ) -> Result<Arc<Texture>> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let key = (path, kind);
+    if let Some(texture) = self
+      .textures
+      .lock()
+      .unwrap()
+      .get(&key)
+      .and_then(Weak::upgrade)
+    {
+      return Ok(texture);
+    }
+
+    // Don't hold the lock while loading, other threads may want other textures meanwhile
+    let texture = Arc::new(create()?);
+    let mut textures = self.textures.lock().unwrap();
+    match textures.get(&key).and_then(Weak::upgrade) {
+      // Another thread loaded the same texture first
+      Some(existing) => Ok(existing),
+      None => {
+        textures.insert(key, Arc::downgrade(&texture));
+        Ok(texture)
+      }
+    }
+  }
+
+  /// The number of registered textures that are still in use.
+  pub fn len(&self) -> usize {
+    self.live_textures().len()
+  }
+
+  /// Bytes of GPU memory taken up by registered textures that are still in use.
+  pub fn memory_usage(&self) -> u64 {
+    self
+      .live_textures()
+      .iter()
+      .map(|texture| texture.memory_usage())
+      .sum()
+  }
+
+  fn live_textures(&self) -> Vec<Arc<Texture>> {
+    let mut textures = self.textures.lock().unwrap();
+    textures.retain(|_, texture| texture.strong_count() > 0);
+    textures.values().filter_map(Weak::upgrade).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rows_are_unpadded() {
+    let (row_len, padded_row_len) = (3, 8);
+    let data = [1, 2, 3, 0, 0, 0, 0, 0, 4, 5, 6, 0, 0, 0, 0, 0];
+    assert_eq!(
+      unpad_rows(&data, row_len, padded_row_len),
+      [1, 2, 3, 4, 5, 6]
+    );
+    // The last row doesn't have to be padded
+    assert_eq!(
+      unpad_rows(&data[..11], row_len, padded_row_len),
+      [1, 2, 3, 4, 5, 6]
+    );
+  }
+
+  #[test]
+  fn cube_faces_have_to_be_six_squares_of_one_size() {
+    let face = |width, height| image::DynamicImage::new_rgba8(width, height);
+    assert!(check_cube_faces("cube", &vec![face(4, 4); 6]).is_ok());
+    assert!(check_cube_faces("cube", &vec![face(4, 4); 5]).is_err());
+    assert!(check_cube_faces("cube", &vec![face(4, 2); 6]).is_err());
+    let mut faces = vec![face(4, 4); 6];
+    faces[5] = face(2, 2);
+    assert!(check_cube_faces("cube", &faces).is_err());
+  }
+
+  #[test]
+  #[ignore = "needs a GPU"]
+  fn raw_rgba_round_trips() {
+    let (device, queue) = crate::testing::device();
+    let samplers = SamplerCache::new();
+    // 37 pixels make 148 byte rows, which have to be padded to 256 for the copy
+    for dimensions in [(37, 5), (64, 3)] {
+      let rgba = (0..dimensions.0 * dimensions.1 * 4)
+        .map(|i| (i * 7 % 251) as u8)
+        .collect::<Vec<_>>();
+      for kind in [TextureKind::Color, TextureKind::Linear] {
+        let texture = Texture::from_raw_rgba(
+          "readback test",
+          &device,
+          &queue,
+          &rgba,
+          dimensions,
+          kind,
+          &samplers,
+          &SamplerOptions::default(),
+        )
+        .unwrap();
+        match pollster::block_on(texture.read_to_image(&device, &queue)) {
+          Ok(ReadbackImage::Rgba8(image)) => {
+            assert_eq!(image.dimensions(), dimensions);
+            assert_eq!(image.as_raw(), &rgba, "{:?} {:?}", kind, dimensions);
+          }
+          _ => panic!("expected an RGBA8 readback"),
+        }
+      }
+    }
+  }
+
+  /// Writes 2x2 `image` files in a fresh directory, one per colour, returning their paths.
+  fn write_images(name: &str, colours: &[[u8; 4]]) -> Vec<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("texture-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    colours
+      .iter()
+      .enumerate()
+      .map(|(i, colour)| {
+        let path = dir.join(format!("{}.png", i));
+        image::RgbaImage::from_pixel(2, 2, image::Rgba(*colour))
+          .save(&path)
+          .unwrap();
+        path
+      })
+      .collect()
+  }
+
+  fn first_pixel(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &Texture,
+    layer: u32,
+  ) -> [u8; 4] {
+    match pollster::block_on(texture.read_level_to_image(device, queue, 0, layer)) {
+      Ok(ReadbackImage::Rgba8(image)) => image.get_pixel(0, 0).0,
+      _ => panic!("expected an RGBA8 readback"),
+    }
+  }
+
+  #[test]
+  #[ignore = "needs a GPU"]
+  fn files_are_loaded_by_format() {
+    let (device, queue) = crate::testing::device();
+    let samplers = SamplerCache::new();
+    let options = SamplerOptions::default();
+    let png = write_images("files", &[[10, 20, 30, 255]]).remove(0);
+    let radiance = png.with_extension("hdr");
+    let pixels = vec![image::Rgb([0.5f32, 1.0, 2.0]); 4];
+    let mut bytes = Vec::new();
+    image::codecs::hdr::HdrEncoder::new(&mut bytes)
+      .encode(&pixels, 2, 2)
+      .unwrap();
+    std::fs::write(&radiance, &bytes).unwrap();
+
+    let texture = Texture::load(
+      &png,
+      &device,
+      &queue,
+      TextureKind::Linear,
+      &samplers,
+      &options,
+    )
+    .unwrap();
+    assert_eq!(texture.format, wgpu::TextureFormat::Rgba8Unorm);
+    assert_eq!(first_pixel(&device, &queue, &texture, 0), [10, 20, 30, 255]);
+    let texture = Texture::from_bytes(
+      "png",
+      &device,
+      &queue,
+      &std::fs::read(&png).unwrap(),
+      TextureKind::Linear,
+      &samplers,
+      &options,
+    )
+    .unwrap();
+    assert_eq!(first_pixel(&device, &queue, &texture, 0), [10, 20, 30, 255]);
+
+    let from_file = Texture::load(
+      &radiance,
+      &device,
+      &queue,
+      TextureKind::Color,
+      &samplers,
+      &options,
+    )
+    .unwrap();
+    let from_bytes = Texture::from_bytes(
+      "radiance",
+      &device,
+      &queue,
+      &bytes,
+      TextureKind::Color,
+      &samplers,
+      &options,
+    )
+    .unwrap();
+    let full = Texture::load_hdr(
+      &radiance,
+      &device,
+      &queue,
+      wgpu::TextureFormat::Rgba32Float,
+      &samplers,
+      &options,
+    )
+    .unwrap();
+    assert_eq!(from_file.format, wgpu::TextureFormat::Rgba16Float);
+    assert_eq!(from_bytes.format, wgpu::TextureFormat::Rgba16Float);
+    assert_eq!(full.format, wgpu::TextureFormat::Rgba32Float);
+    for texture in [from_file, from_bytes, full] {
+      match pollster::block_on(texture.read_to_image(&device, &queue)) {
+        Ok(ReadbackImage::Rgba32F(image)) => {
+          assert_eq!(image.get_pixel(1, 1).0, [0.5, 1.0, 2.0, 1.0])
+        }
+        _ => panic!("expected a float readback"),
+      }
+    }
+    std::fs::remove_dir_all(png.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  #[ignore = "needs a GPU"]
+  fn layers_are_loaded_in_order() {
+    let (device, queue) = crate::testing::device();
+    let samplers = SamplerCache::new();
+    let options = SamplerOptions::default();
+    let colours = (0..6).map(|i| [i * 40, 0, 0, 255]).collect::<Vec<_>>();
+    let paths = write_images("layers", &colours);
+
+    let cube = Texture::load_cube_faces(&paths, &device, &queue, &samplers, &options).unwrap();
+    assert_eq!(cube.view_dimension, wgpu::TextureViewDimension::Cube);
+    assert!(Texture::load_cube_faces(&paths[..5], &device, &queue, &samplers, &options).is_err());
+    let array = Texture::load_array(
+      &paths[..3],
+      &device,
+      &queue,
+      TextureKind::Linear,
+      &samplers,
+      &options,
+    )
+    .unwrap();
+    assert_eq!(array.size.depth_or_array_layers, 3);
+    assert_eq!(array.view_dimension, wgpu::TextureViewDimension::D2Array);
+    for (layer, colour) in colours.iter().enumerate() {
+      assert_eq!(first_pixel(&device, &queue, &cube, layer as u32), *colour);
+      if layer < 3 {
+        assert_eq!(first_pixel(&device, &queue, &array, layer as u32), *colour);
+      }
     }
+    std::fs::remove_dir_all(paths[0].parent().unwrap()).unwrap();
   }
 }
@@ -0,0 +1,293 @@
+//! Packing many small images into one texture, so meshes using them can share a bind group.
+
+use anyhow::{bail, Result};
+
+/// How images are packed into an atlas.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AtlasOptions {
+  /// Pixels around each image filled by repeating its edges, so filtering doesn't pick up its
+  /// neighbours. Smaller mip levels still bleed once the padding shrinks below a pixel.
+  pub padding: u32,
+  /// The largest width or height the atlas may grow to.
+  pub max_size: u32,
+}
+
+impl Default for AtlasOptions {
+  fn default() -> Self {
+    Self {
+      padding: 4,
+      max_size: 4096,
+    }
+  }
+}
+
+/// Where an image ended up in an atlas.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AtlasRegion {
+  /// Top left corner of the image in pixels, inside the padding.
+  pub x: u32,
+  pub y: u32,
+  pub width: u32,
+  pub height: u32,
+  /// Maps the image's texture coordinates to the atlas's, as `uv * uv_scale + uv_offset`.
+  pub uv_offset: [f32; 2],
+  pub uv_scale: [f32; 2],
+}
+
+impl AtlasRegion {
+  /// Maps texture coordinates between 0 and 1 on the image to the atlas. Coordinates outside
+  /// that range land on neighbouring images, so tiling textures can't be atlased.
+  pub fn transform(&self, [u, v]: [f32; 2]) -> [f32; 2] {
+    [
+      u * self.uv_scale[0] + self.uv_offset[0],
+      v * self.uv_scale[1] + self.uv_offset[1],
+    ]
+  }
+}
+
+/// The size of an atlas and where each image goes, which can be reused for several atlases
+/// with the same layout, e.g. for the diffuse and normal maps of a set of materials.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AtlasLayout {
+  pub width: u32,
+  pub height: u32,
+  pub padding: u32,
+  /// One region per packed size, in the order they were given.
+  pub regions: Vec<AtlasRegion>,
+}
+
+impl AtlasLayout {
+  /// Packs rectangles of the given sizes onto shelves, tallest first, in the narrowest power of
+  /// two wide atlas that's about square.
+  pub fn pack(sizes: &[(u32, u32)], options: &AtlasOptions) -> Result<Self> {
+    let padded = sizes
+      .iter()
+      .map(|&(width, height)| (width + 2 * options.padding, height + 2 * options.padding))
+      .collect::<Vec<_>>();
+    let widest = padded.iter().map(|size| size.0).max().unwrap_or(1);
+    if let Some(&(width, height)) = padded
+      .iter()
+      .find(|&&(width, height)| width > options.max_size || height > options.max_size)
+    {
+      bail!(
+        "A {}x{} image with padding doesn't fit in a {}x{} atlas",
+        width,
+        height,
+        options.max_size,
+        options.max_size
+      );
+    }
+
+    let mut order = (0..sizes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| std::cmp::Reverse(padded[i]));
+    let area = padded
+      .iter()
+      .map(|&(w, h)| w as u64 * h as u64)
+      .sum::<u64>();
+    let mut width = ((area as f64).sqrt() as u32)
+      .max(widest)
+      .next_power_of_two()
+      .min(options.max_size);
+    loop {
+      // Shelves are filled left to right, and a new one is started when an image doesn't fit
+      let mut corners = vec![(0, 0); sizes.len()];
+      let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+      for &i in &order {
+        let (w, h) = padded[i];
+        if x + w > width {
+          y += shelf_height;
+          x = 0;
+          shelf_height = 0;
+        }
+        corners[i] = (x, y);
+        x += w;
+        shelf_height = shelf_height.max(h);
+      }
+      let height = (y + shelf_height).max(1);
+
+      if height <= width || width >= options.max_size {
+        if height > options.max_size {
+          bail!(
+            "{} images don't fit in a {}x{} atlas",
+            sizes.len(),
+            options.max_size,
+            options.max_size
+          );
+        }
+        let regions = corners
+          .iter()
+          .zip(sizes)
+          .map(|(&(x, y), &(w, h))| {
+            let (x, y) = (x + options.padding, y + options.padding);
+            AtlasRegion {
+              x,
+              y,
+              width: w,
+              height: h,
+              uv_offset: [x as f32 / width as f32, y as f32 / height as f32],
+              uv_scale: [w as f32 / width as f32, h as f32 / height as f32],
+            }
+          })
+          .collect();
+        return Ok(Self {
+          width,
+          height,
+          padding: options.padding,
+          regions,
+        });
+      }
+      width = (width * 2).min(options.max_size);
+    }
+  }
+
+  /// Copies one image per region into a new atlas image, repeating their edges into the
+  /// padding. Each image has to have the size of its region.
+  pub fn compose(&self, images: &[&image::RgbaImage]) -> Result<image::RgbaImage> {
+    if images.len() != self.regions.len() {
+      bail!(
+        "Expected {} images for the atlas, got {}",
+        self.regions.len(),
+        images.len()
+      );
+    }
+    let mut atlas = image::RgbaImage::new(self.width, self.height);
+    for (i, (region, image)) in self.regions.iter().zip(images).enumerate() {
+      if image.dimensions() != (region.width, region.height) {
+        bail!(
+          "Image {} is {:?}, but its atlas region is {}x{}",
+          i,
+          image.dimensions(),
+          region.width,
+          region.height
+        );
+      }
+      if region.width == 0 || region.height == 0 {
+        continue;
+      }
+      let padding = self.padding as i64;
+      for y in -padding..region.height as i64 + padding {
+        for x in -padding..region.width as i64 + padding {
+          let source = image.get_pixel(
+            x.clamp(0, region.width as i64 - 1) as u32,
+            y.clamp(0, region.height as i64 - 1) as u32,
+          );
+          atlas.put_pixel(
+            (region.x as i64 + x) as u32,
+            (region.y as i64 + y) as u32,
+            *source,
+          );
+        }
+      }
+    }
+    Ok(atlas)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn regions_stay_inside_the_atlas_without_overlapping() {
+    let sizes = [
+      (30, 10),
+      (16, 16),
+      (1, 40),
+      (25, 7),
+      (8, 8),
+      (64, 3),
+      (5, 5),
+      (0, 0),
+    ];
+    let options = AtlasOptions {
+      padding: 2,
+      max_size: 256,
+    };
+    let layout = AtlasLayout::pack(&sizes, &options).unwrap();
+    assert!(layout.width.is_power_of_two());
+
+    // Compare the regions with their padding, which mustn't overlap either
+    let padded = layout
+      .regions
+      .iter()
+      .map(|r| (r.x - 2, r.y - 2, r.x + r.width + 2, r.y + r.height + 2))
+      .collect::<Vec<_>>();
+    for (i, (region, &size)) in layout.regions.iter().zip(&sizes).enumerate() {
+      assert_eq!((region.width, region.height), size);
+      let (_, _, right, bottom) = padded[i];
+      assert!(
+        right <= layout.width && bottom <= layout.height,
+        "{:?}",
+        region
+      );
+      for (j, other) in padded.iter().enumerate().skip(i + 1) {
+        let overlaps = padded[i].0 < other.2
+          && other.0 < padded[i].2
+          && padded[i].1 < other.3
+          && other.1 < padded[i].3;
+        assert!(!overlaps, "regions {} and {} overlap", i, j);
+      }
+    }
+  }
+
+  #[test]
+  fn padding_repeats_the_edges() {
+    let image = image::RgbaImage::from_fn(2, 2, |x, y| image::Rgba([x as u8, y as u8, 7, 255]));
+    let layout = AtlasLayout::pack(
+      &[(2, 2)],
+      &AtlasOptions {
+        padding: 2,
+        max_size: 16,
+      },
+    )
+    .unwrap();
+    let atlas = layout.compose(&[&image]).unwrap();
+    let region = layout.regions[0];
+    assert_eq!((region.x, region.y), (2, 2));
+
+    for y in 0..6 {
+      for x in 0..6 {
+        let expected = image.get_pixel(x.clamp(2, 3) - 2, y.clamp(2, 3) - 2);
+        assert_eq!(atlas.get_pixel(x, y), expected, "({}, {})", x, y);
+      }
+    }
+  }
+
+  #[test]
+  fn images_that_dont_fit_are_errors() {
+    let options = AtlasOptions {
+      padding: 2,
+      max_size: 16,
+    };
+    assert!(AtlasLayout::pack(&[(12, 12)], &options).is_ok());
+    assert!(AtlasLayout::pack(&[(13, 4)], &options).is_err());
+    assert!(AtlasLayout::pack(&[(4, 4); 4], &options).is_ok());
+    assert!(AtlasLayout::pack(&[(4, 4); 5], &options).is_err());
+  }
+
+  #[test]
+  fn transform_maps_the_unit_square_onto_the_region() {
+    let layout = AtlasLayout::pack(&[(32, 16), (8, 8)], &AtlasOptions::default()).unwrap();
+    let (width, height) = (layout.width as f32, layout.height as f32);
+    for region in &layout.regions {
+      let [u, v] = region.transform([0.0, 0.0]);
+      assert_eq!([u * width, v * height], [region.x as f32, region.y as f32]);
+      let [u, v] = region.transform([1.0, 1.0]);
+      assert_eq!(
+        [u * width, v * height],
+        [
+          (region.x + region.width) as f32,
+          (region.y + region.height) as f32
+        ]
+      );
+    }
+  }
+
+  #[test]
+  fn compose_checks_the_images() {
+    let layout = AtlasLayout::pack(&[(2, 2)], &AtlasOptions::default()).unwrap();
+    let wrong_size = image::RgbaImage::new(3, 2);
+    assert!(layout.compose(&[&wrong_size]).is_err());
+    assert!(layout.compose(&[]).is_err());
+  }
+}
@@ -0,0 +1,459 @@
+//! Pre-compressed mip chains loaded from KTX2 and DDS containers.
+
+use super::decompress;
+use anyhow::{bail, Context, Result};
+use std::{fs, path::Path};
+
+const KTX2_MAGIC: [u8; 12] = [
+  0xab, b'K', b'T', b'X', b' ', b'2', b'0', 0xbb, b'\r', b'\n', 0x1a, b'\n',
+];
+const DDS_MAGIC: [u8; 4] = *b"DDS ";
+
+/// Block sizes of the ASTC formats, in the order both KTX2 and wgpu list them.
+pub const ASTC_BLOCK_SIZES: [(u8, u8); 14] = [
+  (4, 4),
+  (5, 4),
+  (5, 5),
+  (6, 5),
+  (6, 6),
+  (8, 5),
+  (8, 6),
+  (8, 8),
+  (10, 5),
+  (10, 6),
+  (10, 8),
+  (10, 10),
+  (12, 10),
+  (12, 12),
+];
+
+/// The layout of the pixels in a container, independent of whether they're sRGB encoded.
+///
+/// This is a superset of what wgpu can sample, since ETC2 RGBA8 has no `wgpu::TextureFormat`
+/// yet and has to be decompressed either way. Every format except BC6H and ASTC can also be
+/// decompressed on the CPU; those two only load on devices that can sample them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockFormat {
+  Bc1,
+  Bc2,
+  Bc3,
+  Bc4 {
+    signed: bool,
+  },
+  Bc5 {
+    signed: bool,
+  },
+  Bc6h {
+    signed: bool,
+  },
+  Bc7,
+  Etc2Rgb,
+  Etc2RgbA1,
+  Etc2Rgba8,
+  EacR11 {
+    signed: bool,
+  },
+  EacRg11 {
+    signed: bool,
+  },
+  Astc {
+    block: (u8, u8),
+  },
+  /// Uncompressed, 8 bits per channel.
+  Rgba8,
+  /// Uncompressed, 8 bits per channel with red and blue swapped.
+  Bgra8,
+}
+
+impl BlockFormat {
+  pub fn block_dimensions(self) -> (u32, u32) {
+    match self {
+      BlockFormat::Astc { block } => (block.0 as u32, block.1 as u32),
+      BlockFormat::Rgba8 | BlockFormat::Bgra8 => (1, 1),
+      _ => (4, 4),
+    }
+  }
+
+  /// Bytes per block.
+  pub fn block_size(self) -> u32 {
+    match self {
+      BlockFormat::Bc1
+      | BlockFormat::Bc4 { .. }
+      | BlockFormat::Etc2Rgb
+      | BlockFormat::Etc2RgbA1
+      | BlockFormat::EacR11 { .. } => 8,
+      BlockFormat::Rgba8 | BlockFormat::Bgra8 => 4,
+      _ => 16,
+    }
+  }
+
+  /// Features the device needs to sample this format directly.
+  pub fn required_features(self) -> wgpu::Features {
+    match self {
+      BlockFormat::Bc1
+      | BlockFormat::Bc2
+      | BlockFormat::Bc3
+      | BlockFormat::Bc4 { .. }
+      | BlockFormat::Bc5 { .. }
+      | BlockFormat::Bc6h { .. }
+      | BlockFormat::Bc7 => wgpu::Features::TEXTURE_COMPRESSION_BC,
+      BlockFormat::Etc2Rgb
+      | BlockFormat::Etc2RgbA1
+      | BlockFormat::Etc2Rgba8
+      | BlockFormat::EacR11 { .. }
+      | BlockFormat::EacRg11 { .. } => wgpu::Features::TEXTURE_COMPRESSION_ETC2,
+      BlockFormat::Astc { .. } => wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR,
+      BlockFormat::Rgba8 | BlockFormat::Bgra8 => wgpu::Features::empty(),
+    }
+  }
+
+  /// Whether the CPU decoders in `decompress` handle this format. BC6H and ASTC have no CPU
+  /// decoder, so they need a device with `required_features`.
+  pub fn can_decompress(self) -> bool {
+    !matches!(self, BlockFormat::Bc6h { .. } | BlockFormat::Astc { .. })
+  }
+
+  /// The matching wgpu format, if there is one. `srgb` is ignored by formats that only come in
+  /// a linear variant.
+  pub fn wgpu_format(self, srgb: bool) -> Option<wgpu::TextureFormat> {
+    use wgpu::TextureFormat as F;
+    let pick = |linear, srgb_format| if srgb { srgb_format } else { linear };
+    Some(match self {
+      BlockFormat::Bc1 => pick(F::Bc1RgbaUnorm, F::Bc1RgbaUnormSrgb),
+      BlockFormat::Bc2 => pick(F::Bc2RgbaUnorm, F::Bc2RgbaUnormSrgb),
+      BlockFormat::Bc3 => pick(F::Bc3RgbaUnorm, F::Bc3RgbaUnormSrgb),
+      BlockFormat::Bc4 { signed: false } => F::Bc4RUnorm,
+      BlockFormat::Bc4 { signed: true } => F::Bc4RSnorm,
+      BlockFormat::Bc5 { signed: false } => F::Bc5RgUnorm,
+      BlockFormat::Bc5 { signed: true } => F::Bc5RgSnorm,
+      BlockFormat::Bc6h { signed: false } => F::Bc6hRgbUfloat,
+      BlockFormat::Bc6h { signed: true } => F::Bc6hRgbSfloat,
+      BlockFormat::Bc7 => pick(F::Bc7RgbaUnorm, F::Bc7RgbaUnormSrgb),
+      BlockFormat::Etc2Rgb => pick(F::Etc2RgbUnorm, F::Etc2RgbUnormSrgb),
+      BlockFormat::Etc2RgbA1 => pick(F::Etc2RgbA1Unorm, F::Etc2RgbA1UnormSrgb),
+      BlockFormat::Etc2Rgba8 => return None,
+      BlockFormat::EacR11 { signed: false } => F::EacRUnorm,
+      BlockFormat::EacR11 { signed: true } => F::EacRSnorm,
+      BlockFormat::EacRg11 { signed: false } => F::EacRgUnorm,
+      BlockFormat::EacRg11 { signed: true } => F::EacRgSnorm,
+      BlockFormat::Astc { block } => {
+        let formats = [
+          (F::Astc4x4RgbaUnorm, F::Astc4x4RgbaUnormSrgb),
+          (F::Astc5x4RgbaUnorm, F::Astc5x4RgbaUnormSrgb),
+          (F::Astc5x5RgbaUnorm, F::Astc5x5RgbaUnormSrgb),
+          (F::Astc6x5RgbaUnorm, F::Astc6x5RgbaUnormSrgb),
+          (F::Astc6x6RgbaUnorm, F::Astc6x6RgbaUnormSrgb),
+          (F::Astc8x5RgbaUnorm, F::Astc8x5RgbaUnormSrgb),
+          (F::Astc8x6RgbaUnorm, F::Astc8x6RgbaUnormSrgb),
+          (F::Astc8x8RgbaUnorm, F::Astc8x8RgbaUnormSrgb),
+          (F::Astc10x5RgbaUnorm, F::Astc10x5RgbaUnormSrgb),
+          (F::Astc10x6RgbaUnorm, F::Astc10x6RgbaUnormSrgb),
+          (F::Astc10x8RgbaUnorm, F::Astc10x8RgbaUnormSrgb),
+          (F::Astc10x10RgbaUnorm, F::Astc10x10RgbaUnormSrgb),
+          (F::Astc12x10RgbaUnorm, F::Astc12x10RgbaUnormSrgb),
+          (F::Astc12x12RgbaUnorm, F::Astc12x12RgbaUnormSrgb),
+        ];
+        let index = ASTC_BLOCK_SIZES.iter().position(|&size| size == block)?;
+        pick(formats[index].0, formats[index].1)
+      }
+      BlockFormat::Rgba8 => pick(F::Rgba8Unorm, F::Rgba8UnormSrgb),
+      BlockFormat::Bgra8 => pick(F::Bgra8Unorm, F::Bgra8UnormSrgb),
+    })
+  }
+}
+
+/// A 2D texture whose mip levels are stored the way the GPU samples them.
+pub struct CompressedImage {
+  pub format: BlockFormat,
+  /// Whether the colour channels are sRGB encoded.
+  pub srgb: bool,
+  pub width: u32,
+  pub height: u32,
+  pub mip_level_count: u32,
+  /// Every mip level, largest first, each tightly packed in rows of blocks.
+  pub data: Vec<u8>,
+}
+
+impl CompressedImage {
+  pub fn new(
+    format: BlockFormat,
+    srgb: bool,
+    (width, height): (u32, u32),
+    mip_level_count: u32,
+    data: Vec<u8>,
+  ) -> Result<Self> {
+    let image = Self {
+      format,
+      srgb,
+      width,
+      height,
+      mip_level_count,
+      data,
+    };
+    let expected_len = (0..mip_level_count)
+      .map(|level| image.level_len(level))
+      .sum::<usize>();
+    if image.data.len() != expected_len {
+      bail!(
+        "Expected {} bytes for {} mip levels of {}x{} {:?}, got {}",
+        expected_len,
+        mip_level_count,
+        width,
+        height,
+        format,
+        image.data.len()
+      );
+    }
+    Ok(image)
+  }
+
+  /// The format to upload the image in as it is on a device with `features`, or `None` if it
+  /// has to be decompressed first. The GPU only samples block compressed textures that are a
+  /// whole number of blocks in size, so anything else is an error rather than a fallback.
+  pub fn upload_format(
+    &self,
+    srgb: bool,
+    features: wgpu::Features,
+  ) -> Result<Option<wgpu::TextureFormat>> {
+    let format = match self.format.wgpu_format(srgb) {
+      Some(format) if features.contains(self.format.required_features()) => format,
+      _ => return Ok(None),
+    };
+    let (block_width, block_height) = self.format.block_dimensions();
+    if !self.width.is_multiple_of(block_width) || !self.height.is_multiple_of(block_height) {
+      bail!(
+        "{}x{} {:?} isn't a whole number of {}x{} blocks, which the GPU needs",
+        self.width,
+        self.height,
+        self.format,
+        block_width,
+        block_height
+      );
+    }
+    Ok(Some(format))
+  }
+
+  /// Whether `path` has the extension of a container this module can read.
+  pub fn is_container(path: &Path) -> bool {
+    let extension = path
+      .extension()
+      .and_then(|ext| ext.to_str())
+      .map(|ext| ext.to_ascii_lowercase());
+    matches!(extension.as_deref(), Some("ktx2") | Some("dds"))
+  }
+
+  /// Whether `bytes` start with the magic number of a container this module can read.
+  pub fn is_container_data(bytes: &[u8]) -> bool {
+    bytes.starts_with(&KTX2_MAGIC) || bytes.starts_with(&DDS_MAGIC)
+  }
+
+  pub fn open(path: &Path) -> Result<Self> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    Self::from_bytes(&bytes).with_context(|| format!("Failed to load texture {:?}", path))
+  }
+
+  /// Parses a KTX2 or DDS file, telling them apart by their magic number.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    if bytes.starts_with(&KTX2_MAGIC) {
+      Self::from_ktx2(bytes)
+    } else if bytes.starts_with(&DDS_MAGIC) {
+      Self::from_dds(bytes)
+    } else {
+      bail!("Not a KTX2 or DDS file")
+    }
+  }
+
+  pub fn from_ktx2(bytes: &[u8]) -> Result<Self> {
+    let reader = ktx2::Reader::new(bytes).context("Invalid KTX2 file")?;
+    let header = reader.header();
+    if let Some(scheme) = header.supercompression_scheme {
+      bail!("Supercompressed KTX2 files ({:?}) aren't supported", scheme);
+    }
+    if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count != 1 {
+      bail!("Only 2D KTX2 textures are supported");
+    }
+    let format = header
+      .format
+      .context("KTX2 file has no Vulkan format, Basis Universal isn't supported")?;
+    let (format, srgb) = ktx2_format(format)?;
+
+    let dimensions = (header.pixel_width, header.pixel_height.max(1));
+    let mut data = Vec::new();
+    let mut mip_level_count = 0;
+    for level in reader.levels() {
+      let len = level_len(format, dimensions, mip_level_count);
+      data.extend_from_slice(level.get(..len).context("KTX2 mip level is truncated")?);
+      mip_level_count += 1;
+    }
+    Self::new(format, srgb, dimensions, mip_level_count, data)
+  }
+
+  pub fn from_dds(bytes: &[u8]) -> Result<Self> {
+    let dds = ddsfile::Dds::read(bytes).context("Invalid DDS file")?;
+    if dds.get_depth() > 1 || dds.get_num_array_layers() > 1 {
+      bail!("Only 2D DDS textures are supported");
+    }
+    let (format, srgb) = dds_format(&dds)?;
+
+    let dimensions = (dds.get_width(), dds.get_height());
+    let mip_level_count = dds.get_num_mipmap_levels().max(1);
+    let len = (0..mip_level_count)
+      .map(|level| level_len(format, dimensions, level))
+      .sum::<usize>();
+    let data = dds
+      .data
+      .get(..len)
+      .context("DDS mip chain is truncated")?
+      .to_vec();
+    Self::new(format, srgb, dimensions, mip_level_count, data)
+  }
+
+  /// Bytes taken up by mip `level`.
+  pub fn level_len(&self, level: u32) -> usize {
+    level_len(self.format, (self.width, self.height), level)
+  }
+
+  /// The data of each mip level, largest first.
+  pub fn levels(&self) -> impl Iterator<Item = &[u8]> {
+    let mut offset = 0;
+    (0..self.mip_level_count).map(move |level| {
+      let len = self.level_len(level);
+      offset += len;
+      &self.data[offset - len..offset]
+    })
+  }
+
+  /// Decodes every mip level to tightly packed, 8 bit per channel RGBA.
+  pub fn decompress(&self) -> Result<Vec<Vec<u8>>> {
+    self
+      .levels()
+      .enumerate()
+      .map(|(level, data)| {
+        let size = crate::mipmap::mip_size((self.width, self.height), level as u32);
+        decompress::decompress(self.format, data, size)
+      })
+      .collect()
+  }
+}
+
+fn level_len(format: BlockFormat, dimensions: (u32, u32), level: u32) -> usize {
+  let (width, height) = crate::mipmap::mip_size(dimensions, level);
+  let (block_width, block_height) = format.block_dimensions();
+  width.div_ceil(block_width) as usize
+    * height.div_ceil(block_height) as usize
+    * format.block_size() as usize
+}
+
+fn ktx2_format(format: ktx2::Format) -> Result<(BlockFormat, bool)> {
+  use ktx2::Format as F;
+  Ok(match format {
+    F::BC1_RGB_UNORM_BLOCK | F::BC1_RGBA_UNORM_BLOCK => (BlockFormat::Bc1, false),
+    F::BC1_RGB_SRGB_BLOCK | F::BC1_RGBA_SRGB_BLOCK => (BlockFormat::Bc1, true),
+    F::BC2_UNORM_BLOCK => (BlockFormat::Bc2, false),
+    F::BC2_SRGB_BLOCK => (BlockFormat::Bc2, true),
+    F::BC3_UNORM_BLOCK => (BlockFormat::Bc3, false),
+    F::BC3_SRGB_BLOCK => (BlockFormat::Bc3, true),
+    F::BC4_UNORM_BLOCK => (BlockFormat::Bc4 { signed: false }, false),
+    F::BC4_SNORM_BLOCK => (BlockFormat::Bc4 { signed: true }, false),
+    F::BC5_UNORM_BLOCK => (BlockFormat::Bc5 { signed: false }, false),
+    F::BC5_SNORM_BLOCK => (BlockFormat::Bc5 { signed: true }, false),
+    F::BC6H_UFLOAT_BLOCK => (BlockFormat::Bc6h { signed: false }, false),
+    F::BC6H_SFLOAT_BLOCK => (BlockFormat::Bc6h { signed: true }, false),
+    F::BC7_UNORM_BLOCK => (BlockFormat::Bc7, false),
+    F::BC7_SRGB_BLOCK => (BlockFormat::Bc7, true),
+    F::ETC2_R8G8B8_UNORM_BLOCK => (BlockFormat::Etc2Rgb, false),
+    F::ETC2_R8G8B8_SRGB_BLOCK => (BlockFormat::Etc2Rgb, true),
+    F::ETC2_R8G8B8A1_UNORM_BLOCK => (BlockFormat::Etc2RgbA1, false),
+    F::ETC2_R8G8B8A1_SRGB_BLOCK => (BlockFormat::Etc2RgbA1, true),
+    F::ETC2_R8G8B8A8_UNORM_BLOCK => (BlockFormat::Etc2Rgba8, false),
+    F::ETC2_R8G8B8A8_SRGB_BLOCK => (BlockFormat::Etc2Rgba8, true),
+    F::EAC_R11_UNORM_BLOCK => (BlockFormat::EacR11 { signed: false }, false),
+    F::EAC_R11_SNORM_BLOCK => (BlockFormat::EacR11 { signed: true }, false),
+    F::EAC_R11G11_UNORM_BLOCK => (BlockFormat::EacRg11 { signed: false }, false),
+    F::EAC_R11G11_SNORM_BLOCK => (BlockFormat::EacRg11 { signed: true }, false),
+    F::R8G8B8A8_UNORM => (BlockFormat::Rgba8, false),
+    F::R8G8B8A8_SRGB => (BlockFormat::Rgba8, true),
+    F::B8G8R8A8_UNORM => (BlockFormat::Bgra8, false),
+    F::B8G8R8A8_SRGB => (BlockFormat::Bgra8, true),
+    _ => {
+      // The ASTC formats alternate between UNORM and SRGB, starting at 4x4 UNORM
+      let astc = format
+        .0
+        .get()
+        .checked_sub(F::ASTC_4x4_UNORM_BLOCK.0.get())
+        .filter(|&i| (i as usize) < 2 * ASTC_BLOCK_SIZES.len());
+      match astc {
+        Some(i) => (
+          BlockFormat::Astc {
+            block: ASTC_BLOCK_SIZES[i as usize / 2],
+          },
+          i % 2 == 1,
+        ),
+        None => bail!("Unsupported KTX2 format {:?}", format),
+      }
+    }
+  })
+}
+
+fn dds_format(dds: &ddsfile::Dds) -> Result<(BlockFormat, bool)> {
+  use ddsfile::{D3DFormat, DxgiFormat as F};
+  if let Some(format) = dds.get_dxgi_format() {
+    return Ok(match format {
+      F::BC1_Typeless | F::BC1_UNorm => (BlockFormat::Bc1, false),
+      F::BC1_UNorm_sRGB => (BlockFormat::Bc1, true),
+      F::BC2_Typeless | F::BC2_UNorm => (BlockFormat::Bc2, false),
+      F::BC2_UNorm_sRGB => (BlockFormat::Bc2, true),
+      F::BC3_Typeless | F::BC3_UNorm => (BlockFormat::Bc3, false),
+      F::BC3_UNorm_sRGB => (BlockFormat::Bc3, true),
+      F::BC4_Typeless | F::BC4_UNorm => (BlockFormat::Bc4 { signed: false }, false),
+      F::BC4_SNorm => (BlockFormat::Bc4 { signed: true }, false),
+      F::BC5_Typeless | F::BC5_UNorm => (BlockFormat::Bc5 { signed: false }, false),
+      F::BC5_SNorm => (BlockFormat::Bc5 { signed: true }, false),
+      F::BC6H_Typeless | F::BC6H_UF16 => (BlockFormat::Bc6h { signed: false }, false),
+      F::BC6H_SF16 => (BlockFormat::Bc6h { signed: true }, false),
+      F::BC7_Typeless | F::BC7_UNorm => (BlockFormat::Bc7, false),
+      F::BC7_UNorm_sRGB => (BlockFormat::Bc7, true),
+      F::R8G8B8A8_Typeless | F::R8G8B8A8_UNorm => (BlockFormat::Rgba8, false),
+      F::R8G8B8A8_UNorm_sRGB => (BlockFormat::Rgba8, true),
+      F::B8G8R8A8_Typeless | F::B8G8R8A8_UNorm => (BlockFormat::Bgra8, false),
+      F::B8G8R8A8_UNorm_sRGB => (BlockFormat::Bgra8, true),
+      _ => bail!("Unsupported DXGI format {:?}", format),
+    });
+  }
+  Ok(match dds.get_d3d_format() {
+    Some(D3DFormat::DXT1) => (BlockFormat::Bc1, false),
+    // DXT2 and DXT4 only differ from DXT3 and DXT5 in having premultiplied alpha
+    Some(D3DFormat::DXT2) | Some(D3DFormat::DXT3) => (BlockFormat::Bc2, false),
+    Some(D3DFormat::DXT4) | Some(D3DFormat::DXT5) => (BlockFormat::Bc3, false),
+    Some(D3DFormat::A8B8G8R8) => (BlockFormat::Rgba8, false),
+    Some(D3DFormat::A8R8G8B8) => (BlockFormat::Bgra8, false),
+    format => bail!("Unsupported DDS format {:?}", format),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn partial_blocks_cant_be_uploaded() {
+    // 6x4 BC1 is two blocks of 8 bytes, the second only half covered
+    let image = CompressedImage::new(BlockFormat::Bc1, true, (6, 4), 1, vec![0; 16]).unwrap();
+    assert!(image
+      .upload_format(true, wgpu::Features::TEXTURE_COMPRESSION_BC)
+      .is_err());
+    // Decompressing on the CPU handles partial blocks
+    assert_eq!(
+      image.upload_format(true, wgpu::Features::empty()).unwrap(),
+      None
+    );
+
+    let image = CompressedImage::new(BlockFormat::Bc1, true, (8, 4), 1, vec![0; 16]).unwrap();
+    assert_eq!(
+      image
+        .upload_format(true, wgpu::Features::TEXTURE_COMPRESSION_BC)
+        .unwrap(),
+      Some(wgpu::TextureFormat::Bc1RgbaUnormSrgb)
+    );
+  }
+}
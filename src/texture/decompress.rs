@@ -0,0 +1,1042 @@
+//! CPU decoders for block-compressed formats, for adapters that can't sample them directly.
+
+use super::compressed::BlockFormat;
+use anyhow::{bail, Result};
+
+/// The pixels of one 4x4 block, row by row.
+type Block = [[u8; 4]; 16];
+
+/// Decodes one mip level of `format` to tightly packed, 8 bit per channel RGBA. Fails for the
+/// formats `BlockFormat::can_decompress` rules out.
+///
+/// Missing channels are filled in like the GPU would, with 0 for green and blue and 255 for
+/// alpha. Signed formats are mapped to unsigned the way normal maps are usually stored, with -1
+/// at 0 and 1 at 255.
+pub fn decompress(
+  format: BlockFormat,
+  data: &[u8],
+  (width, height): (u32, u32),
+) -> Result<Vec<u8>> {
+  let decode_block: fn(&[u8], &mut Block) = match format {
+    BlockFormat::Rgba8 => return Ok(data.to_vec()),
+    BlockFormat::Bgra8 => {
+      return Ok(
+        data
+          .chunks_exact(4)
+          .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]])
+          .collect(),
+      )
+    }
+    BlockFormat::Bc1 => |block, out| bc1(block, out, true),
+    BlockFormat::Bc2 => bc2,
+    BlockFormat::Bc3 => bc3,
+    BlockFormat::Bc4 { signed } => {
+      if signed {
+        |block, out| channels(out, &[bc4(block, true)])
+      } else {
+        |block, out| channels(out, &[bc4(block, false)])
+      }
+    }
+    BlockFormat::Bc5 { signed } => {
+      if signed {
+        |block, out| channels(out, &[bc4(block, true), bc4(&block[8..], true)])
+      } else {
+        |block, out| channels(out, &[bc4(block, false), bc4(&block[8..], false)])
+      }
+    }
+    BlockFormat::Bc7 => bc7,
+    BlockFormat::Etc2Rgb => |block, out| etc2_rgb(block, out, false),
+    BlockFormat::Etc2RgbA1 => |block, out| etc2_rgb(block, out, true),
+    BlockFormat::Etc2Rgba8 => |block, out| {
+      etc2_rgb(&block[8..], out, false);
+      for (pixel, alpha) in out.iter_mut().zip(eac_alpha(block)) {
+        pixel[3] = alpha;
+      }
+    },
+    BlockFormat::EacR11 { signed } => {
+      if signed {
+        |block, out| channels(out, &[eac_r11(block, true)])
+      } else {
+        |block, out| channels(out, &[eac_r11(block, false)])
+      }
+    }
+    BlockFormat::EacRg11 { signed } => {
+      if signed {
+        |block, out| channels(out, &[eac_r11(block, true), eac_r11(&block[8..], true)])
+      } else {
+        |block, out| channels(out, &[eac_r11(block, false), eac_r11(&block[8..], false)])
+      }
+    }
+    BlockFormat::Bc6h { .. } | BlockFormat::Astc { .. } => {
+      bail!("There's no CPU decoder for {:?}", format)
+    }
+  };
+
+  let block_size = format.block_size() as usize;
+  let (blocks_x, blocks_y) = (width.div_ceil(4) as usize, height.div_ceil(4) as usize);
+  let expected_len = blocks_x * blocks_y * block_size;
+  if data.len() < expected_len {
+    bail!(
+      "Expected {} bytes of {:?} data for {}x{} pixels, got {}",
+      expected_len,
+      format,
+      width,
+      height,
+      data.len()
+    );
+  }
+
+  let (width, height) = (width as usize, height as usize);
+  let mut rgba = vec![0; 4 * width * height];
+  let mut block = [[0; 4]; 16];
+  for (i, data) in data[..expected_len].chunks_exact(block_size).enumerate() {
+    decode_block(data, &mut block);
+    let (block_x, block_y) = (4 * (i % blocks_x), 4 * (i / blocks_x));
+    for y in 0..4.min(height - block_y) {
+      for x in 0..4.min(width - block_x) {
+        let offset = 4 * ((block_y + y) * width + block_x + x);
+        rgba[offset..offset + 4].copy_from_slice(&block[4 * y + x]);
+      }
+    }
+  }
+  Ok(rgba)
+}
+
+/// Fills a block from one or two decoded channels.
+fn channels(out: &mut Block, channels: &[[u8; 16]]) {
+  for (i, pixel) in out.iter_mut().enumerate() {
+    *pixel = [0, 0, 0, 255];
+    for (c, channel) in channels.iter().enumerate() {
+      pixel[c] = channel[i];
+    }
+  }
+}
+
+fn mix(a: [u8; 4], b: [u8; 4], weight_a: u32, weight_b: u32) -> [u8; 4] {
+  [0, 1, 2, 3].map(|c| {
+    let sum = weight_a + weight_b;
+    ((a[c] as u32 * weight_a + b[c] as u32 * weight_b + sum / 2) / sum) as u8
+  })
+}
+
+fn rgb565(color: u16) -> [u8; 4] {
+  let (r, g, b) = (
+    (color >> 11) as u8,
+    (color >> 5) as u8 & 0x3f,
+    color as u8 & 0x1f,
+  );
+  [
+    (r << 3) | (r >> 2),
+    (g << 2) | (g >> 4),
+    (b << 3) | (b >> 2),
+    255,
+  ]
+}
+
+/// The colour half of a BC1, BC2 or BC3 block. Only BC1 has the 3 colour mode with a
+/// transparent index.
+fn bc1(block: &[u8], out: &mut Block, allow_transparent: bool) {
+  let c0 = u16::from_le_bytes([block[0], block[1]]);
+  let c1 = u16::from_le_bytes([block[2], block[3]]);
+  let (e0, e1) = (rgb565(c0), rgb565(c1));
+  let palette = if c0 > c1 || !allow_transparent {
+    [e0, e1, mix(e0, e1, 2, 1), mix(e0, e1, 1, 2)]
+  } else {
+    [e0, e1, mix(e0, e1, 1, 1), [0; 4]]
+  };
+  let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+  for (i, pixel) in out.iter_mut().enumerate() {
+    *pixel = palette[(indices >> (2 * i)) as usize & 3];
+  }
+}
+
+fn bc2(block: &[u8], out: &mut Block) {
+  bc1(&block[8..], out, false);
+  let alpha = u64::from_le_bytes(block[..8].try_into().unwrap());
+  for (i, pixel) in out.iter_mut().enumerate() {
+    pixel[3] = ((alpha >> (4 * i)) & 0xf) as u8 * 17;
+  }
+}
+
+fn bc3(block: &[u8], out: &mut Block) {
+  bc1(&block[8..], out, false);
+  for (pixel, alpha) in out.iter_mut().zip(bc4(block, false)) {
+    pixel[3] = alpha;
+  }
+}
+
+/// A BC4 block, which is also the alpha half of a BC3 block and each channel of BC5.
+fn bc4(block: &[u8], signed: bool) -> [u8; 16] {
+  let (e0, e1, min, max) = if signed {
+    let endpoint = |byte: u8| (byte as i8).max(-127) as f32;
+    (endpoint(block[0]), endpoint(block[1]), -127.0, 127.0)
+  } else {
+    (block[0] as f32, block[1] as f32, 0.0, 255.0)
+  };
+  let lerp = |a: f32, b: f32, d: f32| ((a * e0 + b * e1) / d).round();
+  let palette = if e0 > e1 {
+    [
+      e0,
+      e1,
+      lerp(6.0, 1.0, 7.0),
+      lerp(5.0, 2.0, 7.0),
+      lerp(4.0, 3.0, 7.0),
+      lerp(3.0, 4.0, 7.0),
+      lerp(2.0, 5.0, 7.0),
+      lerp(1.0, 6.0, 7.0),
+    ]
+  } else {
+    [
+      e0,
+      e1,
+      lerp(4.0, 1.0, 5.0),
+      lerp(3.0, 2.0, 5.0),
+      lerp(2.0, 3.0, 5.0),
+      lerp(1.0, 4.0, 5.0),
+      min,
+      max,
+    ]
+  };
+  let palette = palette.map(|v| ((v - min) / (max - min) * 255.0).round() as u8);
+  let mut indices = [0; 8];
+  indices[..6].copy_from_slice(&block[2..8]);
+  let indices = u64::from_le_bytes(indices);
+  let mut values = [0; 16];
+  for (i, value) in values.iter_mut().enumerate() {
+    *value = palette[(indices >> (3 * i)) as usize & 7];
+  }
+  values
+}
+
+struct Bc7Mode {
+  subsets: usize,
+  partition_bits: usize,
+  rotation_bits: usize,
+  index_selection_bits: usize,
+  color_bits: usize,
+  alpha_bits: usize,
+  endpoint_pbits: bool,
+  shared_pbits: bool,
+  index_bits: usize,
+  index_bits2: usize,
+}
+
+#[rustfmt::skip]
+const BC7_MODES: [Bc7Mode; 8] = [
+  Bc7Mode { subsets: 3, partition_bits: 4, rotation_bits: 0, index_selection_bits: 0, color_bits: 4, alpha_bits: 0, endpoint_pbits: true, shared_pbits: false, index_bits: 3, index_bits2: 0 },
+  Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 6, alpha_bits: 0, endpoint_pbits: false, shared_pbits: true, index_bits: 3, index_bits2: 0 },
+  Bc7Mode { subsets: 3, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 0, endpoint_pbits: false, shared_pbits: false, index_bits: 2, index_bits2: 0 },
+  Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 0, endpoint_pbits: true, shared_pbits: false, index_bits: 2, index_bits2: 0 },
+  Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 1, color_bits: 5, alpha_bits: 6, endpoint_pbits: false, shared_pbits: false, index_bits: 2, index_bits2: 3 },
+  Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 0, color_bits: 7, alpha_bits: 8, endpoint_pbits: false, shared_pbits: false, index_bits: 2, index_bits2: 2 },
+  Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 7, endpoint_pbits: true, shared_pbits: false, index_bits: 4, index_bits2: 0 },
+  Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 5, endpoint_pbits: true, shared_pbits: false, index_bits: 2, index_bits2: 0 },
+];
+
+/// Two subset partitions, with bit `i` set if pixel `i` is in the second subset.
+#[rustfmt::skip]
+const BC7_PARTITIONS2: [u16; 64] = [
+  0xcccc, 0x8888, 0xeeee, 0xecc8, 0xc880, 0xfeec, 0xfec8, 0xec80,
+  0xc800, 0xffec, 0xfe80, 0xe800, 0xffe8, 0xff00, 0xfff0, 0xf000,
+  0xf710, 0x008e, 0x7100, 0x08ce, 0x008c, 0x7310, 0x3100, 0x8cce,
+  0x088c, 0x3110, 0x6666, 0x366c, 0x17e8, 0x0ff0, 0x718e, 0x399c,
+  0xaaaa, 0xf0f0, 0x5a5a, 0x33cc, 0x3c3c, 0x55aa, 0x9696, 0xa55a,
+  0x73ce, 0x13c8, 0x324c, 0x3bdc, 0x6996, 0xc33c, 0x9966, 0x0660,
+  0x0272, 0x04e4, 0x4e40, 0x2720, 0xc936, 0x936c, 0x39c6, 0x639c,
+  0x9336, 0x9cc6, 0x817e, 0xe718, 0xccf0, 0x0fcc, 0x7744, 0xee22,
+];
+
+/// Three subset partitions, with the subset of each pixel.
+#[rustfmt::skip]
+const BC7_PARTITIONS3: [[u8; 16]; 64] = [
+  [0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 1, 2, 2, 2, 2],
+  [0, 0, 0, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 2, 1],
+  [0, 0, 0, 0, 2, 0, 0, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+  [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 1, 0, 1, 1, 1],
+  [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2],
+  [0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 2, 2],
+  [0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1],
+  [0, 0, 1, 1, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1],
+  [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2],
+  [0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2],
+  [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+  [0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2],
+  [0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2, 0, 1, 1, 2],
+  [0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2, 0, 1, 2, 2],
+  [0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2, 1, 2, 2, 2],
+  [0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0, 2, 2, 2, 0],
+  [0, 0, 0, 1, 0, 0, 1, 1, 0, 1, 1, 2, 1, 1, 2, 2],
+  [0, 1, 1, 1, 0, 0, 1, 1, 2, 0, 0, 1, 2, 2, 0, 0],
+  [0, 0, 0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2],
+  [0, 0, 2, 2, 0, 0, 2, 2, 0, 0, 2, 2, 1, 1, 1, 1],
+  [0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2, 0, 2, 2, 2],
+  [0, 0, 0, 1, 0, 0, 0, 1, 2, 2, 2, 1, 2, 2, 2, 1],
+  [0, 0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2],
+  [0, 0, 0, 0, 1, 1, 0, 0, 2, 2, 1, 0, 2, 2, 1, 0],
+  [0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1, 0, 0, 0, 0],
+  [0, 0, 1, 2, 0, 0, 1, 2, 1, 1, 2, 2, 2, 2, 2, 2],
+  [0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1, 0, 1, 1, 0],
+  [0, 0, 0, 0, 0, 1, 1, 0, 1, 2, 2, 1, 1, 2, 2, 1],
+  [0, 0, 2, 2, 1, 1, 0, 2, 1, 1, 0, 2, 0, 0, 2, 2],
+  [0, 1, 1, 0, 0, 1, 1, 0, 2, 0, 0, 2, 2, 2, 2, 2],
+  [0, 0, 1, 1, 0, 1, 2, 2, 0, 1, 2, 2, 0, 0, 1, 1],
+  [0, 0, 0, 0, 2, 0, 0, 0, 2, 2, 1, 1, 2, 2, 2, 1],
+  [0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 2, 2, 2],
+  [0, 2, 2, 2, 0, 0, 2, 2, 0, 0, 1, 2, 0, 0, 1, 1],
+  [0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 2, 2, 0, 2, 2, 2],
+  [0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0, 0, 1, 2, 0],
+  [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0],
+  [0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0],
+  [0, 1, 2, 0, 2, 0, 1, 2, 1, 2, 0, 1, 0, 1, 2, 0],
+  [0, 0, 1, 1, 2, 2, 0, 0, 1, 1, 2, 2, 0, 0, 1, 1],
+  [0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0, 1, 1],
+  [0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+  [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1],
+  [0, 0, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2, 1, 1, 2, 2],
+  [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 2, 2, 0, 0, 1, 1],
+  [0, 2, 2, 0, 1, 2, 2, 1, 0, 2, 2, 0, 1, 2, 2, 1],
+  [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 0, 1, 0, 1],
+  [0, 0, 0, 0, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1, 2, 1],
+  [0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 2, 2, 2, 2],
+  [0, 2, 2, 2, 0, 1, 1, 1, 0, 2, 2, 2, 0, 1, 1, 1],
+  [0, 0, 0, 2, 1, 1, 1, 2, 0, 0, 0, 2, 1, 1, 1, 2],
+  [0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 2],
+  [0, 2, 2, 2, 0, 1, 1, 1, 0, 1, 1, 1, 0, 2, 2, 2],
+  [0, 0, 0, 2, 1, 1, 1, 2, 1, 1, 1, 2, 0, 0, 0, 2],
+  [0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2],
+  [0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2, 2, 1, 1, 2],
+  [0, 1, 1, 0, 0, 1, 1, 0, 2, 2, 2, 2, 2, 2, 2, 2],
+  [0, 0, 2, 2, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 2, 2],
+  [0, 0, 2, 2, 1, 1, 2, 2, 1, 1, 2, 2, 0, 0, 2, 2],
+  [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 1, 1, 2],
+  [0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1],
+  [0, 2, 2, 2, 1, 2, 2, 2, 0, 2, 2, 2, 1, 2, 2, 2],
+  [0, 1, 0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2],
+  [0, 1, 1, 1, 2, 0, 1, 1, 2, 2, 0, 1, 2, 2, 2, 0],
+];
+
+/// The pixel of the second subset whose index is stored with one bit less, per partition.
+#[rustfmt::skip]
+const BC7_ANCHORS2: [u8; 64] = [
+  15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+  15,  2,  8,  2,  2,  8,  8, 15,  2,  8,  2,  2,  8,  8,  2,  2,
+  15, 15,  6,  8,  2,  8, 15, 15,  2,  8,  2,  2,  2, 15, 15,  6,
+   6,  2,  6,  8, 15, 15,  2,  2, 15, 15, 15, 15, 15,  2,  2, 15,
+];
+
+/// The anchor pixels of the second and third subsets of three subset partitions.
+#[rustfmt::skip]
+const BC7_ANCHORS3: [[u8; 64]; 2] = [
+  [
+     3,  3, 15, 15,  8,  3, 15, 15,  8,  8,  6,  6,  6,  5,  3,  3,
+     3,  3,  8, 15,  3,  3,  6, 10,  5,  8,  8,  6,  8,  5, 15, 15,
+     8, 15,  3,  5,  6, 10,  8, 15, 15,  3, 15,  5, 15, 15, 15, 15,
+     3, 15,  5,  5,  5,  8,  5, 10,  5, 10,  8, 13, 15, 12,  3,  3,
+  ],
+  [
+    15,  8,  8,  3, 15, 15,  3,  8, 15, 15, 15, 15, 15, 15, 15,  8,
+    15,  8, 15,  3, 15,  8, 15,  8,  3, 15,  6, 10, 15, 15, 10,  8,
+    15,  3, 15, 10, 10,  8,  9, 10,  6, 15,  8, 15,  3,  6,  6,  8,
+    15,  3, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,  3, 15, 15,  8,
+  ],
+];
+
+const BC7_WEIGHTS2: [u32; 4] = [0, 21, 43, 64];
+const BC7_WEIGHTS3: [u32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const BC7_WEIGHTS4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// Reads little endian bit fields, least significant bit first.
+struct Bits<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl Bits<'_> {
+  fn read(&mut self, count: usize) -> u32 {
+    let mut value = 0;
+    for i in 0..count {
+      let bit = self.position + i;
+      value |= ((self.bytes[bit / 8] >> (bit % 8)) as u32 & 1) << i;
+    }
+    self.position += count;
+    value
+  }
+}
+
+fn bc7(block: &[u8], out: &mut Block) {
+  let mode_index = block[0].trailing_zeros() as usize;
+  let mode = match BC7_MODES.get(mode_index) {
+    Some(mode) => mode,
+    // Reserved mode, which decodes to transparent black
+    None => {
+      *out = [[0; 4]; 16];
+      return;
+    }
+  };
+  let mut bits = Bits {
+    bytes: block,
+    position: mode_index + 1,
+  };
+  let partition = bits.read(mode.partition_bits) as usize;
+  let rotation = bits.read(mode.rotation_bits);
+  let index_selection = bits.read(mode.index_selection_bits);
+
+  // Endpoints are stored channel by channel, then subset by subset
+  let mut endpoints = [[[0u32; 4]; 2]; 3];
+  for c in 0..4 {
+    let channel_bits = if c < 3 {
+      mode.color_bits
+    } else {
+      mode.alpha_bits
+    };
+    for subset in endpoints.iter_mut().take(mode.subsets) {
+      for endpoint in subset.iter_mut() {
+        endpoint[c] = bits.read(channel_bits);
+      }
+    }
+  }
+  let mut pbits = [[0; 2]; 3];
+  for subset in pbits.iter_mut().take(mode.subsets) {
+    if mode.endpoint_pbits {
+      *subset = [bits.read(1), bits.read(1)];
+    }
+  }
+  for subset in pbits.iter_mut().take(mode.subsets) {
+    if mode.shared_pbits {
+      let pbit = bits.read(1);
+      *subset = [pbit, pbit];
+    }
+  }
+  let has_pbits = mode.endpoint_pbits || mode.shared_pbits;
+  let mut colors = [[[255u8; 4]; 2]; 3];
+  for s in 0..mode.subsets {
+    for e in 0..2 {
+      for c in 0..4 {
+        let mut channel_bits = if c < 3 {
+          mode.color_bits
+        } else {
+          mode.alpha_bits
+        };
+        if channel_bits == 0 {
+          continue;
+        }
+        let mut value = endpoints[s][e][c];
+        if has_pbits {
+          value = (value << 1) | pbits[s][e];
+          channel_bits += 1;
+        }
+        // Expand to 8 bits by repeating the high bits in the low ones
+        value <<= 8 - channel_bits;
+        colors[s][e][c] = (value | (value >> channel_bits)) as u8;
+      }
+    }
+  }
+
+  let subset = |i: usize| match mode.subsets {
+    1 => 0,
+    2 => (BC7_PARTITIONS2[partition] >> i) as usize & 1,
+    _ => BC7_PARTITIONS3[partition][i] as usize,
+  };
+  let is_anchor = |i: usize| {
+    i == 0
+      || match mode.subsets {
+        2 => i == BC7_ANCHORS2[partition] as usize,
+        3 => BC7_ANCHORS3
+          .iter()
+          .any(|anchors| i == anchors[partition] as usize),
+        _ => false,
+      }
+  };
+  let mut indices = [0; 16];
+  for (i, index) in indices.iter_mut().enumerate() {
+    *index = bits.read(mode.index_bits - is_anchor(i) as usize) as usize;
+  }
+  let mut indices2 = [0; 16];
+  if mode.index_bits2 > 0 {
+    for (i, index) in indices2.iter_mut().enumerate() {
+      *index = bits.read(mode.index_bits2 - (i == 0) as usize) as usize;
+    }
+  }
+
+  let weights = |index_bits: usize| match index_bits {
+    2 => &BC7_WEIGHTS2[..],
+    3 => &BC7_WEIGHTS3[..],
+    _ => &BC7_WEIGHTS4[..],
+  };
+  for (i, pixel) in out.iter_mut().enumerate() {
+    let [e0, e1] = colors[subset(i)];
+    let ((color_index, color_bits), (alpha_index, alpha_bits)) = if mode.index_bits2 == 0 {
+      ((indices[i], mode.index_bits), (indices[i], mode.index_bits))
+    } else if index_selection == 0 {
+      (
+        (indices[i], mode.index_bits),
+        (indices2[i], mode.index_bits2),
+      )
+    } else {
+      (
+        (indices2[i], mode.index_bits2),
+        (indices[i], mode.index_bits),
+      )
+    };
+    let interpolate = |c: usize, index: usize, index_bits: usize| {
+      let weight = weights(index_bits)[index];
+      (((64 - weight) * e0[c] as u32 + weight * e1[c] as u32 + 32) >> 6) as u8
+    };
+    *pixel = [
+      interpolate(0, color_index, color_bits),
+      interpolate(1, color_index, color_bits),
+      interpolate(2, color_index, color_bits),
+      interpolate(3, alpha_index, alpha_bits),
+    ];
+    match rotation {
+      1 => pixel.swap(0, 3),
+      2 => pixel.swap(1, 3),
+      3 => pixel.swap(2, 3),
+      _ => {}
+    }
+  }
+}
+
+const ETC_MODIFIERS: [[i32; 2]; 8] = [
+  [2, 8],
+  [5, 17],
+  [9, 29],
+  [13, 42],
+  [18, 60],
+  [24, 80],
+  [33, 106],
+  [47, 183],
+];
+const ETC_DISTANCES: [i32; 8] = [3, 6, 11, 16, 23, 32, 41, 64];
+
+#[rustfmt::skip]
+const EAC_MODIFIERS: [[i32; 8]; 16] = [
+  [-3, -6, -9, -15, 2, 5, 8, 14],
+  [-3, -7, -10, -13, 2, 6, 9, 12],
+  [-2, -5, -8, -13, 1, 4, 7, 12],
+  [-2, -4, -6, -13, 1, 3, 5, 12],
+  [-3, -6, -8, -12, 2, 5, 7, 11],
+  [-3, -7, -9, -11, 2, 6, 8, 10],
+  [-4, -7, -8, -11, 3, 6, 7, 10],
+  [-3, -5, -8, -11, 2, 4, 7, 10],
+  [-2, -6, -8, -10, 1, 5, 7, 9],
+  [-2, -5, -8, -10, 1, 4, 7, 9],
+  [-2, -4, -8, -10, 1, 3, 7, 9],
+  [-2, -5, -7, -10, 1, 4, 6, 9],
+  [-3, -4, -7, -10, 2, 3, 6, 9],
+  [-1, -2, -3, -10, 0, 1, 2, 9],
+  [-4, -6, -8, -9, 3, 5, 7, 8],
+  [-3, -5, -7, -9, 2, 4, 6, 8],
+];
+
+fn extend4(value: u8) -> i32 {
+  (value as i32 & 0xf) * 17
+}
+
+fn extend5(value: i32) -> i32 {
+  (value << 3) | (value >> 2)
+}
+
+fn clamp_rgb([r, g, b]: [i32; 3]) -> [u8; 4] {
+  [
+    r.clamp(0, 255) as u8,
+    g.clamp(0, 255) as u8,
+    b.clamp(0, 255) as u8,
+    255,
+  ]
+}
+
+/// An ETC2 RGB block, or an ETC2 RGB A1 block with `punchthrough`, where the differential bit
+/// instead marks whether the block is fully opaque.
+fn etc2_rgb(block: &[u8], out: &mut Block, punchthrough: bool) {
+  let b = block;
+  let bits = u64::from_be_bytes(block[..8].try_into().unwrap());
+  let differential = punchthrough || b[3] & 2 != 0;
+  let opaque = !punchthrough || b[3] & 2 != 0;
+  // Pixels are numbered column by column, with the high bits of all indices first
+  let index = |x: usize, y: usize| {
+    let k = 4 * x + y;
+    ((((bits >> (16 + k)) & 1) << 1) | ((bits >> k) & 1)) as usize
+  };
+  let transparent = |index: usize| !opaque && index == 2;
+
+  let subblock_colors = if !differential {
+    [
+      [extend4(b[0] >> 4), extend4(b[1] >> 4), extend4(b[2] >> 4)],
+      [extend4(b[0]), extend4(b[1]), extend4(b[2])],
+    ]
+  } else {
+    let base = [b[0] >> 3, b[1] >> 3, b[2] >> 3].map(|c| c as i32);
+    // 3 bit two's complement offsets
+    let delta = [b[0], b[1], b[2]].map(|c| ((c as i32 & 7) << 29) >> 29);
+    let second = [0, 1, 2].map(|c| base[c] + delta[c]);
+    let overflows = |c: usize| !(0..32).contains(&second[c]);
+    if overflows(0) {
+      // T mode
+      let c0 = [
+        extend4(((b[0] >> 1) & 0xc) | (b[0] & 3)),
+        extend4(b[1] >> 4),
+        extend4(b[1]),
+      ];
+      let c1 = [extend4(b[2] >> 4), extend4(b[2]), extend4(b[3] >> 4)];
+      let distance = ETC_DISTANCES[(((b[3] >> 1) & 6) | (b[3] & 1)) as usize];
+      let palette = [c0, c1.map(|c| c + distance), c1, c1.map(|c| c - distance)];
+      return paint(out, index, transparent, palette);
+    }
+    if overflows(1) {
+      // H mode
+      let c0 = [
+        (b[0] >> 3) & 0xf,
+        ((b[0] & 7) << 1) | ((b[1] >> 4) & 1),
+        (b[1] & 8) | ((b[1] & 3) << 1) | (b[2] >> 7),
+      ];
+      let c1 = [
+        (b[2] >> 3) & 0xf,
+        ((b[2] & 7) << 1) | (b[3] >> 7),
+        (b[3] >> 3) & 0xf,
+      ];
+      let packed = |c: [u8; 3]| ((c[0] as u32) << 8) | ((c[1] as u32) << 4) | c[2] as u32;
+      let distance_index = (b[3] & 4) | ((b[3] & 1) << 1) | (packed(c0) >= packed(c1)) as u8;
+      let distance = ETC_DISTANCES[distance_index as usize];
+      let (c0, c1) = (c0.map(extend4), c1.map(extend4));
+      let palette = [
+        c0.map(|c| c + distance),
+        c0.map(|c| c - distance),
+        c1.map(|c| c + distance),
+        c1.map(|c| c - distance),
+      ];
+      return paint(out, index, transparent, palette);
+    }
+    if overflows(2) {
+      return planar(block, out);
+    }
+    [base.map(extend5), second.map(extend5)]
+  };
+
+  let tables = [(b[3] >> 5) as usize, ((b[3] >> 2) & 7) as usize];
+  let flip = b[3] & 1 != 0;
+  for y in 0..4 {
+    for x in 0..4 {
+      let subblock = if flip { y >= 2 } else { x >= 2 } as usize;
+      let index = index(x, y);
+      let [small, large] = ETC_MODIFIERS[tables[subblock]];
+      let modifier = match index {
+        // Without the opaque bit, the small modifiers are replaced by 0 and transparency
+        0 if !opaque => 0,
+        0 => small,
+        1 => large,
+        2 => -small,
+        _ => -large,
+      };
+      out[4 * y + x] = if transparent(index) {
+        [0; 4]
+      } else {
+        clamp_rgb(subblock_colors[subblock].map(|c| c + modifier))
+      };
+    }
+  }
+}
+
+/// Fills a block from a palette of 4 colours, for the T and H modes of ETC2.
+fn paint(
+  out: &mut Block,
+  index: impl Fn(usize, usize) -> usize,
+  transparent: impl Fn(usize) -> bool,
+  palette: [[i32; 3]; 4],
+) {
+  for y in 0..4 {
+    for x in 0..4 {
+      let index = index(x, y);
+      out[4 * y + x] = if transparent(index) {
+        [0; 4]
+      } else {
+        clamp_rgb(palette[index])
+      };
+    }
+  }
+}
+
+/// The planar mode of ETC2, which interpolates between three colours over the block.
+fn planar(b: &[u8], out: &mut Block) {
+  let extend6 = |c: u8| ((c as i32) << 2) | (c as i32 >> 4);
+  let extend7 = |c: u8| ((c as i32) << 1) | (c as i32 >> 6);
+  let origin = [
+    extend6((b[0] >> 1) & 0x3f),
+    extend7(((b[0] & 1) << 6) | ((b[1] >> 1) & 0x3f)),
+    extend6(((b[1] & 1) << 5) | (b[2] & 0x18) | ((b[2] & 3) << 1) | (b[3] >> 7)),
+  ];
+  let horizontal = [
+    extend6((((b[3] >> 2) & 0x1f) << 1) | (b[3] & 1)),
+    extend7(b[4] >> 1),
+    extend6(((b[4] & 1) << 5) | (b[5] >> 3)),
+  ];
+  let vertical = [
+    extend6(((b[5] & 7) << 3) | (b[6] >> 5)),
+    extend7(((b[6] & 0x1f) << 2) | (b[7] >> 6)),
+    extend6(b[7] & 0x3f),
+  ];
+  for y in 0..4 {
+    for x in 0..4 {
+      let (xi, yi) = (x as i32, y as i32);
+      out[4 * y + x] = clamp_rgb([0, 1, 2].map(|c| {
+        (xi * (horizontal[c] - origin[c]) + yi * (vertical[c] - origin[c]) + 4 * origin[c] + 2) >> 2
+      }));
+    }
+  }
+}
+
+/// Reads the 3 bit indices of an EAC block, converting them from column to row order.
+fn eac_indices(block: &[u8]) -> [usize; 16] {
+  let bits = u64::from_be_bytes(block[..8].try_into().unwrap());
+  let mut indices = [0; 16];
+  for y in 0..4 {
+    for x in 0..4 {
+      indices[4 * y + x] = ((bits >> (45 - 3 * (4 * x + y))) & 7) as usize;
+    }
+  }
+  indices
+}
+
+/// The 8 bit alpha block of ETC2 RGBA8.
+fn eac_alpha(block: &[u8]) -> [u8; 16] {
+  let base = block[0] as i32;
+  let multiplier = (block[1] >> 4) as i32;
+  let modifiers = EAC_MODIFIERS[(block[1] & 0xf) as usize];
+  eac_indices(block).map(|index| (base + modifiers[index] * multiplier).clamp(0, 255) as u8)
+}
+
+/// An 11 bit EAC channel, rounded to 8 bits.
+fn eac_r11(block: &[u8], signed: bool) -> [u8; 16] {
+  let multiplier = match (block[1] >> 4) as i32 {
+    0 => 1,
+    multiplier => 8 * multiplier,
+  };
+  let modifiers = EAC_MODIFIERS[(block[1] & 0xf) as usize];
+  eac_indices(block).map(|index| {
+    if signed {
+      let base = (block[0] as i8).max(-127) as i32 * 8;
+      let value = (base + modifiers[index] * multiplier).clamp(-1023, 1023);
+      (((value + 1023) * 255 + 1023) / 2046) as u8
+    } else {
+      let base = block[0] as i32 * 8 + 4;
+      let value = (base + modifiers[index] * multiplier).clamp(0, 2047);
+      ((value * 255 + 1023) / 2047) as u8
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Decodes a single 4x4 block.
+  fn decode(format: BlockFormat, block: &[u8]) -> Vec<[u8; 4]> {
+    decompress(format, block, (4, 4))
+      .unwrap()
+      .chunks_exact(4)
+      .map(|pixel| pixel.try_into().unwrap())
+      .collect()
+  }
+
+  /// Packs `(value, bits)` fields least significant bit first, the way BC7 blocks are laid out.
+  fn pack(fields: &[(u32, usize)]) -> [u8; 16] {
+    let mut block = [0; 16];
+    let mut position = 0;
+    for &(value, bits) in fields {
+      for i in 0..bits {
+        let bit = position + i;
+        block[bit / 8] |= (((value >> i) & 1) as u8) << (bit % 8);
+      }
+      position += bits;
+    }
+    assert_eq!(position, 128);
+    block
+  }
+
+  /// The 3 bit indices of a BC4 block, pixel `i` getting index `i % 8`.
+  fn bc4_indices() -> [u8; 6] {
+    let indices = (0..16).fold(0u64, |bits, i| bits | ((i as u64 % 8) << (3 * i)));
+    indices.to_le_bytes()[..6].try_into().unwrap()
+  }
+
+  #[test]
+  fn bc1_four_colour_block() {
+    // Red and blue endpoints, the first four pixels using each palette entry
+    let pixels = decode(BlockFormat::Bc1, &[0x00, 0xf8, 0x1f, 0x00, 0xe4, 0, 0, 0]);
+    assert_eq!(pixels[0], [255, 0, 0, 255]);
+    assert_eq!(pixels[1], [0, 0, 255, 255]);
+    assert_eq!(pixels[2], [170, 0, 85, 255]);
+    assert_eq!(pixels[3], [85, 0, 170, 255]);
+    assert!(pixels[4..].iter().all(|&pixel| pixel == [255, 0, 0, 255]));
+  }
+
+  #[test]
+  fn bc1_three_colour_block_has_a_transparent_index() {
+    // c0 <= c1 selects the midpoint and transparent black
+    let pixels = decode(BlockFormat::Bc1, &[0x00, 0x00, 0x02, 0x00, 0xe4, 0, 0, 0]);
+    assert_eq!(pixels[0], [0, 0, 0, 255]);
+    assert_eq!(pixels[1], [0, 0, 16, 255]);
+    assert_eq!(pixels[2], [0, 0, 8, 255]);
+    assert_eq!(pixels[3], [0, 0, 0, 0]);
+  }
+
+  #[test]
+  fn bc2_has_explicit_alpha_and_always_four_colours() {
+    let mut block = 0xfedc_ba98_7654_3210u64.to_le_bytes().to_vec();
+    // Black to white with c0 <= c1, every pixel using index 2
+    block.extend_from_slice(&[0x00, 0x00, 0xff, 0xff, 0xaa, 0xaa, 0xaa, 0xaa]);
+    let pixels = decode(BlockFormat::Bc2, &block);
+    for (i, pixel) in pixels.iter().enumerate() {
+      assert_eq!(*pixel, [85, 85, 85, i as u8 * 17]);
+    }
+  }
+
+  #[test]
+  fn bc3_interpolates_alpha() {
+    let mut block = vec![70, 0];
+    block.extend_from_slice(&bc4_indices());
+    block.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+    let alphas = decode(BlockFormat::Bc3, &block)
+      .iter()
+      .map(|pixel| pixel[3])
+      .collect::<Vec<_>>();
+    assert_eq!(alphas[..8], [70, 0, 60, 50, 40, 30, 20, 10]);
+    assert_eq!(alphas[..8], alphas[8..]);
+  }
+
+  #[test]
+  fn bc4_six_value_mode_has_the_extremes() {
+    let mut block = vec![0, 50];
+    block.extend_from_slice(&bc4_indices());
+    let pixels = decode(BlockFormat::Bc4 { signed: false }, &block);
+    let reds = pixels.iter().map(|pixel| pixel[0]).collect::<Vec<_>>();
+    assert_eq!(reds[..8], [0, 50, 10, 20, 30, 40, 0, 255]);
+    assert!(pixels.iter().all(|pixel| pixel[1..] == [0, 0, 255]));
+  }
+
+  #[test]
+  fn bc4_signed_maps_to_unsigned() {
+    let mut block = vec![70, -70i8 as u8];
+    block.extend_from_slice(&bc4_indices());
+    let reds = decode(BlockFormat::Bc4 { signed: true }, &block)
+      .iter()
+      .map(|pixel| pixel[0])
+      .collect::<Vec<_>>();
+    assert_eq!(reds[..8], [198, 57, 178, 158, 138, 117, 97, 77]);
+
+    // -128 is the same as -127, the six value mode adding -1 and 1
+    let mut block = vec![-128i8 as u8, 127];
+    block.extend_from_slice(&bc4_indices());
+    let reds = decode(BlockFormat::Bc4 { signed: true }, &block)
+      .iter()
+      .map(|pixel| pixel[0])
+      .collect::<Vec<_>>();
+    assert_eq!([reds[0], reds[1], reds[6], reds[7]], [0, 255, 0, 255]);
+  }
+
+  #[test]
+  fn bc5_decodes_red_and_green() {
+    let mut block = vec![70, 0];
+    block.extend_from_slice(&bc4_indices());
+    block.extend_from_slice(&[0, 50]);
+    block.extend_from_slice(&bc4_indices());
+    let pixels = decode(BlockFormat::Bc5 { signed: false }, &block);
+    assert_eq!(pixels[2], [60, 10, 0, 255]);
+    assert_eq!(pixels[7], [10, 255, 0, 255]);
+  }
+
+  #[test]
+  fn bc7_mode_6_interpolates_with_4_bit_indices() {
+    let mut fields = vec![(1 << 6, 7)];
+    // Red from 0 to 127, green and blue 0, alpha 127, then the p-bits 0 and 1
+    fields.extend([
+      (0, 7),
+      (127, 7),
+      (0, 7),
+      (0, 7),
+      (0, 7),
+      (0, 7),
+      (127, 7),
+      (127, 7),
+    ]);
+    fields.extend([(0, 1), (1, 1)]);
+    // The anchor pixel 0 drops the high bit of its index
+    fields.push((0, 3));
+    fields.extend((1..16).map(|i| (i, 4)));
+    let pixels = decode(BlockFormat::Bc7, &pack(&fields));
+    let reds = [
+      0, 16, 36, 52, 68, 84, 104, 120, 135, 151, 171, 187, 203, 219, 239, 255,
+    ];
+    for (i, pixel) in pixels.iter().enumerate() {
+      let high = (i >= 8) as u8;
+      assert_eq!(*pixel, [reds[i], high, high, 254 + high], "pixel {}", i);
+    }
+  }
+
+  #[test]
+  fn bc7_mode_1_uses_the_partition() {
+    // Partition 13 puts the bottom two rows in the second subset
+    let mut fields = vec![(0b10, 2), (13, 6)];
+    // Red, green and blue of both endpoints of both subsets
+    fields.extend([(63, 6), (63, 6), (0, 6), (0, 6)]);
+    fields.extend([(0, 6), (0, 6), (63, 6), (63, 6)]);
+    fields.extend([(0, 6), (0, 6), (0, 6), (0, 6)]);
+    // Shared p-bits, then indices with the anchors at pixels 0 and 15 a bit shorter
+    fields.extend([(1, 1), (1, 1), (0, 32), (0, 14)]);
+    let pixels = decode(BlockFormat::Bc7, &pack(&fields));
+    assert!(pixels[..8].iter().all(|&pixel| pixel == [255, 2, 2, 255]));
+    assert!(pixels[8..].iter().all(|&pixel| pixel == [2, 255, 2, 255]));
+  }
+
+  #[test]
+  fn bc7_reserved_mode_is_transparent_black() {
+    let pixels = decode(BlockFormat::Bc7, &[0; 16]);
+    assert!(pixels.iter().all(|&pixel| pixel == [0; 4]));
+  }
+
+  #[test]
+  fn etc2_individual_mode() {
+    // 136 and 68 with tables 0 and 7, split left and right. Pixel (3, 0) uses index 3 and
+    // pixel (0, 1) index 1.
+    let pixels = decode(
+      BlockFormat::Etc2Rgb,
+      &[0x84, 0x84, 0x84, 0x1c, 0x10, 0x00, 0x10, 0x02],
+    );
+    assert_eq!(pixels[0], [138, 138, 138, 255]);
+    assert_eq!(pixels[2], [115, 115, 115, 255]);
+    assert_eq!(pixels[3], [0, 0, 0, 255]);
+    assert_eq!(pixels[4], [144, 144, 144, 255]);
+  }
+
+  #[test]
+  fn etc2_differential_mode() {
+    // Red 16 + 3, green 8 - 1 and blue 31 + 0, flipped into top and bottom halves
+    let pixels = decode(BlockFormat::Etc2Rgb, &[0x83, 0x47, 0xf8, 0x03, 0, 0, 0, 0]);
+    assert!(pixels[..8]
+      .iter()
+      .all(|&pixel| pixel == [134, 68, 255, 255]));
+    assert!(pixels[8..]
+      .iter()
+      .all(|&pixel| pixel == [158, 59, 255, 255]));
+  }
+
+  #[test]
+  fn etc2_t_mode() {
+    // Red overflows. The first row uses each of the four paint colours.
+    let pixels = decode(
+      BlockFormat::Etc2Rgb,
+      &[0x07, 0x00, 0x88, 0x82, 0x11, 0x00, 0x10, 0x10],
+    );
+    assert_eq!(
+      pixels[..4],
+      [
+        [51, 0, 0, 255],
+        [139, 139, 139, 255],
+        [136, 136, 136, 255],
+        [133, 133, 133, 255]
+      ]
+    );
+    assert_eq!(pixels[4], [51, 0, 0, 255]);
+  }
+
+  #[test]
+  fn etc2_planar_mode() {
+    // Blue overflows. Red goes from 0 at the origin to 255 on the horizontal edge.
+    let pixels = decode(BlockFormat::Etc2Rgb, &[0, 0, 0x04, 0x7f, 0, 0, 0, 0]);
+    for (i, pixel) in pixels.iter().enumerate() {
+      assert_eq!(*pixel, [[0, 64, 128, 191][i % 4], 0, 0, 255], "pixel {}", i);
+    }
+  }
+
+  #[test]
+  fn etc2_punchthrough_alpha() {
+    // Without the opaque bit, index 2 is transparent and the small modifier is 0
+    let pixels = decode(
+      BlockFormat::Etc2RgbA1,
+      &[0x80, 0x80, 0x80, 0x00, 0x10, 0x10, 0x11, 0x00],
+    );
+    assert_eq!(
+      pixels[..4],
+      [
+        [132, 132, 132, 255],
+        [0, 0, 0, 0],
+        [140, 140, 140, 255],
+        [124, 124, 124, 255]
+      ]
+    );
+  }
+
+  #[test]
+  fn etc2_rgba8_alpha() {
+    // Base 128, multiplier 1 and table 0. Pixel (0, 0) uses index 7 and pixel (1, 0) index 4.
+    let block = [
+      128, 0x10, 0xe0, 0x08, 0, 0, 0, 0, 0x88, 0x88, 0x88, 0x00, 0, 0, 0, 0,
+    ];
+    let pixels = decode(BlockFormat::Etc2Rgba8, &block);
+    assert_eq!(pixels[0], [138, 138, 138, 142]);
+    assert_eq!(pixels[1], [138, 138, 138, 130]);
+    assert_eq!(pixels[2], [138, 138, 138, 125]);
+  }
+
+  #[test]
+  fn eac_r11() {
+    let block = [128, 0x10, 0xe0, 0, 0, 0, 0, 0];
+    let pixels = decode(BlockFormat::EacR11 { signed: false }, &block);
+    assert_eq!(pixels[0], [142, 0, 0, 255]);
+    assert_eq!(pixels[1], [125, 0, 0, 255]);
+
+    // A multiplier of 0 still steps by 1, and values clamp at 2047
+    let pixels = decode(
+      BlockFormat::EacR11 { signed: false },
+      &[255, 0, 0xe0, 0, 0, 0, 0, 0],
+    );
+    assert_eq!(pixels[0][0], 255);
+  }
+
+  #[test]
+  fn eac_signed_r11_and_rg11() {
+    // Base 0 plus 2 in red, base 127 plus 14 * 120 clamped to 1023 in green
+    let block = [0, 0x00, 0x80, 0, 0, 0, 0, 0, 127, 0xf0, 0xe0, 0, 0, 0, 0, 0];
+    let pixels = decode(BlockFormat::EacRg11 { signed: true }, &block);
+    assert_eq!(pixels[0], [128, 255, 0, 255]);
+  }
+
+  #[test]
+  fn uncompressed_formats_are_copied() {
+    let data = (0..64).collect::<Vec<u8>>();
+    assert_eq!(decompress(BlockFormat::Rgba8, &data, (4, 4)).unwrap(), data);
+    let bgra = decompress(BlockFormat::Bgra8, &data, (4, 4)).unwrap();
+    assert_eq!(bgra[..8], [2, 1, 0, 3, 6, 5, 4, 7]);
+  }
+
+  #[test]
+  fn partial_blocks_are_cropped() {
+    // A 5x5 image takes 2x2 blocks, each a solid colour
+    let solid = |color: u16| {
+      let [low, high] = color.to_le_bytes();
+      [low, high, low, high, 0, 0, 0, 0]
+    };
+    let data = [0xf800, 0x07e0, 0x001f, 0xffff].map(solid).concat();
+    let rgba = decompress(BlockFormat::Bc1, &data, (5, 5)).unwrap();
+    assert_eq!(rgba.len(), 4 * 5 * 5);
+    let pixel = |x: usize, y: usize| &rgba[4 * (5 * y + x)..4 * (5 * y + x) + 4];
+    assert_eq!(pixel(3, 3), [255, 0, 0, 255]);
+    assert_eq!(pixel(4, 0), [0, 255, 0, 255]);
+    assert_eq!(pixel(0, 4), [0, 0, 255, 255]);
+    assert_eq!(pixel(4, 4), [255, 255, 255, 255]);
+
+    assert!(decompress(BlockFormat::Bc1, &data[..24], (5, 5)).is_err());
+  }
+
+  #[test]
+  fn bc6h_and_astc_are_rejected() {
+    for format in [
+      BlockFormat::Bc6h { signed: false },
+      BlockFormat::Astc { block: (4, 4) },
+    ] {
+      assert!(!format.can_decompress());
+      assert!(decompress(format, &[0; 16], (4, 4)).is_err());
+    }
+    assert!(BlockFormat::Bc7.can_decompress());
+  }
+}
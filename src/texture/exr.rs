@@ -0,0 +1,156 @@
+//! OpenEXR files, read with the `exr` crate. Every compression method and both scanline and
+//! tiled files are supported, but only the first layer and its largest mip level are read.
+
+use super::hdr::HdrImage;
+use anyhow::{bail, Context, Result};
+use exr::prelude::{AnyChannel, FlatSamples, ReadChannels, ReadLayers};
+use std::io::Cursor;
+
+pub const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+/// Decodes an OpenEXR file. `R`, `G`, `B` and `A` channels are used as they are, a lone
+/// luminance or depth channel is copied to all three colour channels, and other channels are
+/// ignored.
+pub fn decode(bytes: &[u8]) -> Result<HdrImage> {
+  if !bytes.starts_with(&MAGIC) {
+    bail!("Not an OpenEXR file");
+  }
+  let image = exr::prelude::read()
+    .no_deep_data()
+    .largest_resolution_level()
+    .all_channels()
+    .first_valid_layer()
+    .all_attributes()
+    .non_parallel()
+    .from_buffered(Cursor::new(bytes))
+    .context("Invalid OpenEXR file")?;
+  let layer = image.layer_data;
+  let (width, height) = (layer.size.width(), layer.size.height());
+  let channels = &layer.channel_data.list;
+
+  // Channels may be prefixed with a layer name, like `diffuse.R`
+  let base_name = |channel: &AnyChannel<FlatSamples>| {
+    let name = channel.name.to_string();
+    name
+      .rsplit('.')
+      .next()
+      .unwrap_or_default()
+      .to_ascii_uppercase()
+  };
+  let find = |wanted: &str| channels.iter().position(|c| base_name(c) == wanted);
+  let mut targets = vec![Vec::new(); channels.len()];
+  for (component, name) in ["R", "G", "B", "A"].iter().enumerate() {
+    if let Some(channel) = find(name) {
+      targets[channel].push(component);
+    }
+  }
+  if targets.iter().all(|components| components.is_empty()) {
+    let channel = match (find("Y"), find("Z"), channels.len()) {
+      (Some(channel), _, _) | (None, Some(channel), _) => channel,
+      (None, None, 1) => 0,
+      _ => bail!("EXR file has no RGB, luminance or depth channels"),
+    };
+    targets[channel] = vec![0, 1, 2];
+  }
+
+  let mut pixels = vec![[0.0, 0.0, 0.0, 1.0]; width * height];
+  for (channel, components) in channels.iter().zip(&targets) {
+    if components.is_empty() {
+      continue;
+    }
+    for (pixel, value) in pixels.iter_mut().zip(channel.sample_data.values_as_f32()) {
+      for &component in components {
+        pixel[component] = value;
+      }
+    }
+  }
+
+  Ok(HdrImage {
+    width: width as u32,
+    height: height as u32,
+    pixels,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use exr::prelude::{
+    AnyChannels, Compression, Encoding, Image, Layer, LayerAttributes, Vec2, WritableImage,
+  };
+
+  /// Writes a 3x2 image with the given channels, each sample holding its index plus 10 times
+  /// the position of its channel in `names`.
+  fn encode(names: &[&str], compression: Compression) -> Vec<u8> {
+    let size = Vec2(3, 2);
+    let channels = names
+      .iter()
+      .enumerate()
+      .map(|(c, name)| {
+        let samples = (0..6).map(|i| half::f16::from_f32(i as f32 + 10.0 * c as f32));
+        AnyChannel::new(*name, FlatSamples::F16(samples.collect()))
+      })
+      .collect();
+    let layer = Layer::new(
+      size,
+      LayerAttributes::default(),
+      Encoding {
+        compression,
+        ..Encoding::default()
+      },
+      AnyChannels::sort(channels),
+    );
+    let mut bytes = Cursor::new(Vec::new());
+    Image::from_layer(layer)
+      .write()
+      .non_parallel()
+      .to_buffered(&mut bytes)
+      .unwrap();
+    bytes.into_inner()
+  }
+
+  #[test]
+  fn every_compression_method_decodes() {
+    for compression in [
+      Compression::Uncompressed,
+      Compression::RLE,
+      Compression::ZIP1,
+      Compression::ZIP16,
+      Compression::PIZ,
+    ] {
+      let image = decode(&encode(&["R", "G", "B", "A"], compression)).unwrap();
+      assert_eq!((image.width, image.height), (3, 2));
+      assert_eq!(
+        image.pixels[4],
+        [4.0, 14.0, 24.0, 34.0],
+        "{:?}",
+        compression
+      );
+    }
+  }
+
+  #[test]
+  fn missing_channels_are_filled_in() {
+    let image = decode(&encode(&["R", "G"], Compression::Uncompressed)).unwrap();
+    assert_eq!(image.pixels[5], [5.0, 15.0, 0.0, 1.0]);
+  }
+
+  #[test]
+  fn luminance_is_copied_to_every_colour_channel() {
+    let image = decode(&encode(&["Y"], Compression::PIZ)).unwrap();
+    assert_eq!(image.pixels[2], [2.0, 2.0, 2.0, 1.0]);
+    assert!(decode(&encode(&["U", "V"], Compression::Uncompressed)).is_err());
+  }
+
+  #[test]
+  fn layer_prefixes_are_ignored() {
+    let image = decode(&encode(&["diffuse.B"], Compression::ZIP1)).unwrap();
+    assert_eq!(image.pixels[1], [0.0, 0.0, 1.0, 1.0]);
+  }
+
+  #[test]
+  fn other_files_are_rejected() {
+    assert!(decode(b"#?RADIANCE").is_err());
+    assert!(decode(&MAGIC).is_err());
+  }
+}
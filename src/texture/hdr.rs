@@ -0,0 +1,128 @@
+//! Floating point images from Radiance `.hdr` and OpenEXR files.
+
+use super::exr;
+use anyhow::{bail, Context, Result};
+use std::{fs, io::BufReader, path::Path};
+
+/// Radiance files start with `#?RADIANCE` or `#?RGBE`, depending on the program that wrote them.
+const RADIANCE_MAGIC: &[u8] = b"#?";
+
+/// A linear, floating point RGBA image.
+pub struct HdrImage {
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<[f32; 4]>,
+}
+
+impl HdrImage {
+  /// Whether `path` has the extension of a format this module can read.
+  pub fn is_hdr(path: &Path) -> bool {
+    let extension = path
+      .extension()
+      .and_then(|ext| ext.to_str())
+      .map(|ext| ext.to_ascii_lowercase());
+    matches!(extension.as_deref(), Some("hdr") | Some("exr"))
+  }
+
+  /// Whether `bytes` look like a Radiance or OpenEXR file.
+  pub fn is_hdr_data(bytes: &[u8]) -> bool {
+    bytes.starts_with(RADIANCE_MAGIC) || bytes.starts_with(&exr::MAGIC)
+  }
+
+  /// Loads a Radiance or OpenEXR file, picking the decoder based on the file extension.
+  pub fn open(path: &Path) -> Result<Self> {
+    let extension = path
+      .extension()
+      .and_then(|ext| ext.to_str())
+      .map(|ext| ext.to_ascii_lowercase());
+    let image = match extension.as_deref() {
+      Some("hdr") => {
+        let file = fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        Self::from_radiance(BufReader::new(file))
+      }
+      Some("exr") => {
+        let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        exr::decode(&bytes)
+      }
+      _ => bail!("Unsupported HDR format"),
+    };
+    image.with_context(|| format!("Failed to load texture {:?}", path))
+  }
+
+  /// Parses a Radiance or OpenEXR file, telling them apart by their magic number.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    if bytes.starts_with(RADIANCE_MAGIC) {
+      Self::from_radiance(bytes)
+    } else if bytes.starts_with(&exr::MAGIC) {
+      exr::decode(bytes)
+    } else {
+      bail!("Not a Radiance or OpenEXR file")
+    }
+  }
+
+  pub fn from_radiance(reader: impl std::io::BufRead) -> Result<Self> {
+    let decoder = image::codecs::hdr::HdrDecoder::new(reader)?;
+    let metadata = decoder.metadata();
+    let pixels = decoder
+      .read_image_hdr()?
+      .into_iter()
+      .map(|pixel| [pixel[0], pixel[1], pixel[2], 1.0])
+      .collect();
+    Ok(Self {
+      width: metadata.width,
+      height: metadata.height,
+      pixels,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::{codecs::hdr::HdrEncoder, Rgb};
+
+  /// A 3x2 Radiance file whose pixels are all exactly representable as RGBE.
+  fn encode_radiance() -> Vec<u8> {
+    let pixels = (0..6)
+      .map(|i| Rgb([i as f32, 0.5, 2.0 * i as f32]))
+      .collect::<Vec<_>>();
+    let mut bytes = Vec::new();
+    HdrEncoder::new(&mut bytes).encode(&pixels, 3, 2).unwrap();
+    bytes
+  }
+
+  #[test]
+  fn radiance_files_decode_to_opaque_rgba() {
+    let bytes = encode_radiance();
+    assert!(HdrImage::is_hdr_data(&bytes));
+    let image = HdrImage::from_bytes(&bytes).unwrap();
+    assert_eq!((image.width, image.height), (3, 2));
+    for (i, pixel) in image.pixels.iter().enumerate() {
+      assert_eq!(*pixel, [i as f32, 0.5, 2.0 * i as f32, 1.0]);
+    }
+  }
+
+  #[test]
+  fn files_are_decoded_by_extension() {
+    let dir = std::env::temp_dir().join(format!("hdr-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sky.HDR");
+    fs::write(&path, encode_radiance()).unwrap();
+    assert!(HdrImage::is_hdr(&path));
+    assert_eq!(HdrImage::open(&path).unwrap().pixels.len(), 6);
+
+    // Radiance data behind an OpenEXR extension goes to the wrong decoder
+    let path = dir.join("sky.exr");
+    fs::write(&path, encode_radiance()).unwrap();
+    assert!(HdrImage::open(&path).is_err());
+    assert!(!HdrImage::is_hdr(&dir.join("sky.png")));
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn other_files_are_rejected() {
+    assert!(HdrImage::is_hdr_data(&exr::MAGIC));
+    assert!(!HdrImage::is_hdr_data(b"\x89PNG"));
+    assert!(HdrImage::from_bytes(b"\x89PNG").is_err());
+  }
+}
@@ -1,6 +1,8 @@
 
 [[block]]
 struct Camera {
+  // The camera's position with w = 1, or the direction towards it with w = 0 for orthographic
+  // projections
   view_pos: vec4<f32>;
   view_proj: mat4x4<f32>;
 };
@@ -18,8 +20,8 @@ struct VertexInput {
   [[location(0)]] position: vec3<f32>;
   [[location(1)]] uvs: vec2<f32>;
   [[location(2)]] normal: vec3<f32>;
-  [[location(3)]] tangent: vec3<f32>;
-  [[location(4)]] bitangent: vec3<f32>;
+  // xyz is the tangent, w the handedness of the tangent frame
+  [[location(3)]] tangent: vec4<f32>;
 };
 
 struct InstanceInput {
@@ -38,7 +40,7 @@ struct VertexOutput {
   [[location(0)]] uvs: vec2<f32>;
   [[location(1)]] tangent_position: vec3<f32>;
   [[location(2)]] tangent_light_position: vec3<f32>;
-  [[location(3)]] tangent_view_position: vec3<f32>;
+  [[location(3)]] tangent_view_vec: vec3<f32>;
 };
 
 [[stage(vertex)]]
@@ -56,11 +58,11 @@ fn vs_main(vertex: VertexInput, instance: InstanceInput) -> VertexOutput {
   );
 
   let world_normal = normalize(normal_matrix * vertex.normal);
-  let world_tangent = normalize(normal_matrix * vertex.tangent);
-  let world_bitangent = normalize(normal_matrix * vertex.bitangent);
+  let world_tangent = normalize(normal_matrix * vertex.tangent.xyz);
+  let world_bitangent = cross(world_normal, world_tangent) * vertex.tangent.w;
   let tangent_matrix = transpose(mat3x3<f32>(
-    world_bitangent,
     world_tangent,
+    world_bitangent,
     world_normal,
   ));
 
@@ -71,7 +73,7 @@ fn vs_main(vertex: VertexInput, instance: InstanceInput) -> VertexOutput {
   out.frag_pos = clip_pos;
   out.uvs = vertex.uvs;
   out.tangent_position = tangent_matrix * world_pos.xyz;
-  out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
+  out.tangent_view_vec = tangent_matrix * (camera.view_pos.xyz - world_pos.xyz * camera.view_pos.w);
   out.tangent_light_position = tangent_matrix * light.position;
   return out;
 }
@@ -81,22 +83,43 @@ fn vs_main(vertex: VertexInput, instance: InstanceInput) -> VertexOutput {
 [[group(0), binding(2)]] var t_normal: texture_2d<f32>;
 [[group(0), binding(3)]] var s_normal: sampler;
 
+[[block]]
+struct Material {
+  ambient: vec3<f32>;
+  shininess: f32;
+  diffuse: vec3<f32>;
+  opacity: f32;
+  specular: vec3<f32>;
+  diffuse_lod_bias: f32;
+  normal_lod_bias: f32;
+  // 1 if the normal map only stores x and y
+  normal_xy: u32;
+};
+
+[[group(0), binding(4)]] var<uniform> material: Material;
+
 [[stage(fragment)]]
 fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-  let object = textureSample(t_diffuse, s_diffuse, in.uvs);
-  let normal = textureSample(t_normal, s_normal, in.uvs);
-  
-  let tangent_normal = normal.xyz * 2.0 - 1.0;
+  let object = textureSampleBias(t_diffuse, s_diffuse, in.uvs, material.diffuse_lod_bias);
+  let normal = textureSampleBias(t_normal, s_normal, in.uvs, material.normal_lod_bias);
+
+  var tangent_normal = normal.xyz * 2.0 - 1.0;
+  // z follows from x and y, since the normal is a unit vector facing out of the surface
+  if (material.normal_xy != 0u) {
+    let xy = tangent_normal.xy;
+    tangent_normal = vec3<f32>(xy, sqrt(max(1.0 - dot(xy, xy), 0.0)));
+  }
   // vector from vertex to light
   let light_vec = normalize(in.tangent_light_position - in.tangent_position);
   // vector from vertex to camera
-  let view_vec = normalize(in.tangent_view_position - in.tangent_position);
+  let view_vec = normalize(in.tangent_view_vec);
   // vector "half-way" between the light and view vectors
   let half_vec = normalize(view_vec + light_vec);
 
   // ambient component
   // - how much the scene is lit as a whole
-  let ambient = light.color * 0.1;
+  // - the material's ambient colour (`Ka`) says how much of the light it reflects
+  let ambient = light.color * material.ambient;
 
   // diffuse component
   // - the light reflected by the object
@@ -106,8 +129,12 @@ fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
   // specular component
   // - highlights on shiny objects
   // - the closer `half_vec` gets to `normal`, the stronger the highlight gets
-  let specular = light.color * pow(max(dot(tangent_normal, half_vec), 0.0), 32.0);
-  
-  let result = (ambient + diffuse + specular) * object.rgb;
-  return vec4<f32>(result, object.a);
+  // - `shininess` (`Ns`) controls how tight the highlight is, `specular` (`Ks`) tints it
+  let specular_strength = pow(max(dot(tangent_normal, half_vec), 0.0), material.shininess);
+  let specular = light.color * material.specular * specular_strength;
+
+  // the diffuse colour (`Kd`) tints the texture, and the dissolve (`d`) scales its alpha
+  let albedo = object.rgb * material.diffuse;
+  let result = (ambient + diffuse) * albedo + specular;
+  return vec4<f32>(result, object.a * material.opacity);
 }
\ No newline at end of file